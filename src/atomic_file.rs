@@ -0,0 +1,84 @@
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use dberror::DBError;
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Name of the temporary file used while `target` is being replaced. It lives
+/// in the same directory so the final rename never crosses file systems. The
+/// counter keeps concurrent saves within one process apart.
+fn temp_sibling(target: &Path) -> PathBuf {
+    let name = target.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or("db".into());
+    let count = TEMP_COUNTER.fetch_add(1, Ordering::SeqCst);
+    return target.with_file_name(format!(".{}.tmp-{}-{}", name, process::id(), count));
+}
+
+/// Flushes the directory entry of `path` so that a preceding rename survives a crash.
+#[cfg(unix)]
+pub fn sync_parent_dir(path: &Path) -> Result<(), DBError> {
+    let dir = match path.parent() {
+        Some(p) if p.as_os_str().len() > 0 => p,
+        _ => Path::new("."),
+    };
+    try!(try!(File::open(dir)).sync_all());
+    return Ok(());
+}
+
+#[cfg(not(unix))]
+pub fn sync_parent_dir(_path: &Path) -> Result<(), DBError> {
+    return Ok(());
+}
+
+fn write_synced<F>(path: &Path, write: F) -> Result<(), DBError>
+    where F: FnOnce(&mut Write) -> Result<(), DBError> {
+    let mut w = BufWriter::new(try!(File::create(path)));
+    try!(write(&mut w));
+    let f = try!(w.into_inner().map_err(|e| e.into_error()));
+    try!(f.sync_all());
+    return Ok(());
+}
+
+/// Replaces the contents of `target` with whatever `write` produces. The data
+/// is written to a temporary sibling, fsynced and renamed over `target`, so a
+/// reader either sees the old or the new file, never a partially written one.
+pub fn write_atomically<F>(target: &Path, write: F) -> Result<(), DBError>
+    where F: FnOnce(&mut Write) -> Result<(), DBError> {
+    let tmp = temp_sibling(target);
+    if let Err(e) = write_synced(&tmp, write) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    if let Err(e) = fs::rename(&tmp, target) {
+        let _ = fs::remove_file(&tmp);
+        return Err(DBError::IO(e));
+    }
+    try!(sync_parent_dir(target));
+    return Ok(());
+}
+
+#[test]
+fn test_write_atomically() {
+    let target = ::std::env::temp_dir().join(format!("theban_db_atomic_{}", process::id()));
+    write_atomically(&target, |w| { try!(w.write_all(b"old")); Ok(()) }).unwrap();
+
+    let res = write_atomically(&target, |w| {
+        try!(w.write_all(b"torn"));
+        Err(DBError::Protocol("interrupted".into()))
+    });
+    assert!(res.is_err());
+    assert!(temp_sibling(&target) != temp_sibling(&target));
+
+    let mut content = vec![];
+    File::open(&target).unwrap().read_to_end(&mut content).unwrap();
+    assert_eq!(content, b"old".to_vec());
+    let leftover = fs::read_dir(target.parent().unwrap()).unwrap()
+                      .any(|e| e.unwrap().file_name().to_string_lossy().starts_with(&format!(".theban_db_atomic_{}.tmp-", process::id())));
+    assert!(!leftover);
+    fs::remove_file(&target).unwrap();
+}
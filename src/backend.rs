@@ -0,0 +1,340 @@
+extern crate memrange;
+
+use self::memrange::Range;
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+use content::Object;
+use content::Bitmap;
+use dberror::DBError;
+use persistent_tree::{PersistentTree, PersistentTreeIter};
+use lazy::LazyTables;
+
+#[cfg(test)] use db::DB;
+
+/// Storage for the tables of a `DB`. A backend only stores and looks up
+/// entries, journaling, bitmap merging and the table checks are done by the
+/// `DB` on top of it.
+///
+/// Queries yield entries borrowed from the backend where it keeps them in
+/// memory and owned ones where it has to load them. A failed load ends up as
+/// an error in the iterator.
+pub trait StorageBackend {
+    type ObjectIter<'a>: Iterator<Item=Result<(Range, Cow<'a, Object>), DBError>> where Self: 'a;
+    type BitmapIter<'a>: Iterator<Item=Result<(Range, Cow<'a, Bitmap>), DBError>> where Self: 'a;
+
+    fn has_table(&self, table: &String) -> bool;
+
+    /// Names of all tables in ascending order.
+    fn tables(&self) -> Vec<&String>;
+
+    /// Creates `table` if it does not exist yet.
+    fn add_table(&mut self, table: &String) -> Result<(), DBError>;
+
+    fn drop_table(&mut self, table: &String) -> Result<(), DBError>;
+
+    /// Moves all entries of `from` to the new table `to`.
+    fn rename_table(&mut self, from: &String, to: &String) -> Result<(), DBError>;
+
+    fn clear_table(&mut self, table: &String) -> Result<(), DBError>;
+
+    /// Objects intersecting `r` in ascending order. None if there is no such table.
+    fn query_objects<'a>(&'a self, table: &String, r: Range) -> Option<Self::ObjectIter<'a>>;
+
+    /// The object with exactly the range `r`, if there is one.
+    fn get_object(&self, table: &String, r: Range) -> Result<Option<Object>, DBError> {
+        for entry in self.query_objects(table, r).into_iter().flatten() {
+            let (rng, obj) = try!(entry);
+            if rng == r {
+                return Ok(Some(obj.into_owned()));
+            }
+        }
+        return Ok(None);
+    }
+
+    /// Stores `d` at `r`, replacing an object with exactly that range.
+    fn insert_object(&mut self, table: &String, r: Range, d: Object) -> Result<(), DBError>;
+
+    /// Removes the object with exactly the range `r`.
+    fn delete_object(&mut self, table: &String, r: Range) -> Result<(), DBError>;
+
+    fn query_bitmaps<'a>(&'a self, table: &String, r: Range) -> Option<Self::BitmapIter<'a>>;
+
+    /// Stores `d` at `r` as is, the `DB` merges bitmaps before.
+    fn insert_bitmap(&mut self, table: &String, r: Range, d: Bitmap) -> Result<(), DBError>;
+
+    /// Removes the bitmap with exactly the range `r`.
+    fn delete_bitmap(&mut self, table: &String, r: Range) -> Result<(), DBError>;
+}
+
+/// The default backend, interval trees in memory. The trees share their nodes
+/// with snapshots of the DB, a write only copies the nodes on the path to the
+/// changed entry.
+#[derive(Clone)]
+pub struct MemoryBackend {
+    pub(crate) obj_map: BTreeMap<String, PersistentTree<Object>>,
+    pub(crate) bit_map: BTreeMap<String, PersistentTree<Bitmap>>,
+    /// Tables of a DB opened with `open_lazy` that are not in the maps above.
+    pub(crate) lazy: Option<LazyTables>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        return MemoryBackend{obj_map: BTreeMap::new(), bit_map: BTreeMap::new(), lazy: None};
+    }
+
+    pub(crate) fn new_from_shared_data(obj_map: BTreeMap<String, PersistentTree<Object>>, bit_map: BTreeMap<String, PersistentTree<Bitmap>>) -> MemoryBackend {
+        return MemoryBackend{obj_map: obj_map, bit_map: bit_map, lazy: None};
+    }
+}
+
+/// Iterator over the entries of a `MemoryBackend` table. If a lazily opened
+/// table fails to load, the error is its only item.
+pub struct MemoryIter<'a, T: 'a> {
+    entries: Option<PersistentTreeIter<'a, T>>,
+    error: Option<DBError>,
+}
+
+impl<'a, T> MemoryIter<'a, T> {
+    fn new(tree: Result<Option<&'a PersistentTree<T>>, DBError>, r: Range) -> Option<MemoryIter<'a, T>> {
+        return match tree {
+            Ok(tree) => tree.map(|tree| MemoryIter{entries: Some(tree.range(r.min, r.max)), error: None}),
+            Err(e) => Some(MemoryIter{entries: None, error: Some(e)}),
+        };
+    }
+}
+
+impl<'a, T: Clone> Iterator for MemoryIter<'a, T> {
+    type Item = Result<(Range, Cow<'a, T>), DBError>;
+
+    fn next(&mut self) -> Option<Result<(Range, Cow<'a, T>), DBError>> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        return self.entries.as_mut().and_then(|entries| entries.next()).map(|(rng, data)| Ok((rng, Cow::Borrowed(data))));
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    type ObjectIter<'a> = MemoryIter<'a, Object>;
+    type BitmapIter<'a> = MemoryIter<'a, Bitmap>;
+
+    fn has_table(&self, table: &String) -> bool {
+        return self.obj_map.contains_key(table) || self.bit_map.contains_key(table) ||
+               self.lazy.as_ref().map(|lazy| lazy.contains(table)).unwrap_or(false);
+    }
+
+    fn tables(&self) -> Vec<&String> {
+        let mut names = self.obj_map.keys().chain(self.bit_map.keys()).collect::<BTreeSet<&String>>();
+        if let Some(ref lazy) = self.lazy {
+            names.extend(lazy.names());
+        }
+        return names.into_iter().collect();
+    }
+
+    fn add_table(&mut self, table: &String) -> Result<(), DBError> {
+        try!(self.materialize(table));
+        if !self.obj_map.contains_key(table) {
+            self.obj_map.insert(table.clone(), PersistentTree::new());
+        }
+        if !self.bit_map.contains_key(table) {
+            self.bit_map.insert(table.clone(), PersistentTree::new());
+        }
+        return Ok(());
+    }
+
+    fn drop_table(&mut self, table: &String) -> Result<(), DBError> {
+        self.obj_map.remove(table);
+        self.bit_map.remove(table);
+        if let Some(ref mut lazy) = self.lazy {
+            lazy.remove(table);
+        }
+        return Ok(());
+    }
+
+    fn rename_table(&mut self, from: &String, to: &String) -> Result<(), DBError> {
+        try!(self.materialize(from));
+        let objects = self.obj_map.remove(from).unwrap_or(PersistentTree::new());
+        let bitmaps = self.bit_map.remove(from).unwrap_or(PersistentTree::new());
+        self.obj_map.insert(to.clone(), objects);
+        self.bit_map.insert(to.clone(), bitmaps);
+        return Ok(());
+    }
+
+    fn clear_table(&mut self, table: &String) -> Result<(), DBError> {
+        if let Some(ref mut lazy) = self.lazy {
+            lazy.remove(table);
+        }
+        self.obj_map.insert(table.clone(), PersistentTree::new());
+        self.bit_map.insert(table.clone(), PersistentTree::new());
+        return Ok(());
+    }
+
+    fn query_objects<'a>(&'a self, table: &String, r: Range) -> Option<MemoryIter<'a, Object>> {
+        return MemoryIter::new(self.object_tree(table), r);
+    }
+
+    fn get_object(&self, table: &String, r: Range) -> Result<Option<Object>, DBError> {
+        let tree = try!(self.object_tree(table));
+        return Ok(tree.and_then(|tree| tree.get(r)).cloned());
+    }
+
+    fn insert_object(&mut self, table: &String, r: Range, d: Object) -> Result<(), DBError> {
+        try!(self.add_table(table));
+        self.obj_map.get_mut(table).unwrap().insert(r, d);
+        return Ok(());
+    }
+
+    fn delete_object(&mut self, table: &String, r: Range) -> Result<(), DBError> {
+        try!(self.materialize(table));
+        if let Some(tree) = self.obj_map.get_mut(table) {
+            tree.delete(r);
+        }
+        return Ok(());
+    }
+
+    fn query_bitmaps<'a>(&'a self, table: &String, r: Range) -> Option<MemoryIter<'a, Bitmap>> {
+        return MemoryIter::new(self.bitmap_tree(table), r);
+    }
+
+    fn insert_bitmap(&mut self, table: &String, r: Range, d: Bitmap) -> Result<(), DBError> {
+        try!(self.add_table(table));
+        self.bit_map.get_mut(table).unwrap().insert(r, d);
+        return Ok(());
+    }
+
+    fn delete_bitmap(&mut self, table: &String, r: Range) -> Result<(), DBError> {
+        try!(self.materialize(table));
+        if let Some(tree) = self.bit_map.get_mut(table) {
+            tree.delete(r);
+        }
+        return Ok(());
+    }
+}
+
+/// Backend storing every table as a sorted vector, used to check that `DB`
+/// only relies on the trait. Queries hand out copies, like a backend that
+/// does not keep its entries in memory would.
+#[cfg(test)]
+pub(crate) struct VecBackend {
+    tables: BTreeMap<String, (Vec<(Range, Object)>, Vec<(Range, Bitmap)>)>,
+}
+
+#[cfg(test)]
+impl VecBackend {
+    pub(crate) fn new() -> VecBackend {
+        return VecBackend{tables: BTreeMap::new()};
+    }
+}
+
+#[cfg(test)]
+fn vec_query<'a, T: Clone>(entries: &Vec<(Range, T)>, r: Range) -> ::std::vec::IntoIter<Result<(Range, Cow<'a, T>), DBError>> {
+    let mut res = entries.iter().filter(|&&(rng, _)| rng.intersect(&r)).map(|&(rng, ref d)| (rng, d.clone())).collect::<Vec<(Range, T)>>();
+    res.sort_by_key(|&(rng, _)| (rng.min, rng.max));
+    return res.into_iter().map(|(rng, d)| Ok((rng, Cow::Owned(d)))).collect::<Vec<_>>().into_iter();
+}
+
+#[cfg(test)]
+fn vec_insert<T>(entries: &mut Vec<(Range, T)>, r: Range, d: T) {
+    entries.retain(|&(rng, _)| rng != r);
+    entries.push((r, d));
+}
+
+#[cfg(test)]
+impl StorageBackend for VecBackend {
+    type ObjectIter<'a> = ::std::vec::IntoIter<Result<(Range, Cow<'a, Object>), DBError>>;
+    type BitmapIter<'a> = ::std::vec::IntoIter<Result<(Range, Cow<'a, Bitmap>), DBError>>;
+
+    fn has_table(&self, table: &String) -> bool {
+        return self.tables.contains_key(table);
+    }
+
+    fn tables(&self) -> Vec<&String> {
+        return self.tables.keys().collect();
+    }
+
+    fn add_table(&mut self, table: &String) -> Result<(), DBError> {
+        self.tables.entry(table.clone()).or_insert((vec![], vec![]));
+        return Ok(());
+    }
+
+    fn drop_table(&mut self, table: &String) -> Result<(), DBError> {
+        self.tables.remove(table);
+        return Ok(());
+    }
+
+    fn rename_table(&mut self, from: &String, to: &String) -> Result<(), DBError> {
+        let entries = self.tables.remove(from).unwrap_or((vec![], vec![]));
+        self.tables.insert(to.clone(), entries);
+        return Ok(());
+    }
+
+    fn clear_table(&mut self, table: &String) -> Result<(), DBError> {
+        self.tables.insert(table.clone(), (vec![], vec![]));
+        return Ok(());
+    }
+
+    fn query_objects<'a>(&'a self, table: &String, r: Range) -> Option<Self::ObjectIter<'a>> {
+        return self.tables.get(table).map(|&(ref objects, _)| vec_query(objects, r));
+    }
+
+    fn insert_object(&mut self, table: &String, r: Range, d: Object) -> Result<(), DBError> {
+        try!(self.add_table(table));
+        vec_insert(&mut self.tables.get_mut(table).unwrap().0, r, d);
+        return Ok(());
+    }
+
+    fn delete_object(&mut self, table: &String, r: Range) -> Result<(), DBError> {
+        if let Some(&mut (ref mut objects, _)) = self.tables.get_mut(table) {
+            objects.retain(|&(rng, _)| rng != r);
+        }
+        return Ok(());
+    }
+
+    fn query_bitmaps<'a>(&'a self, table: &String, r: Range) -> Option<Self::BitmapIter<'a>> {
+        return self.tables.get(table).map(|&(_, ref bitmaps)| vec_query(bitmaps, r));
+    }
+
+    fn insert_bitmap(&mut self, table: &String, r: Range, d: Bitmap) -> Result<(), DBError> {
+        try!(self.add_table(table));
+        vec_insert(&mut self.tables.get_mut(table).unwrap().1, r, d);
+        return Ok(());
+    }
+
+    fn delete_bitmap(&mut self, table: &String, r: Range) -> Result<(), DBError> {
+        if let Some(&mut (_, ref mut bitmaps)) = self.tables.get_mut(table) {
+            bitmaps.retain(|&(rng, _)| rng != r);
+        }
+        return Ok(());
+    }
+}
+
+#[test]
+fn test_custom_backend() {
+    let mut db = DB::with_backend(VecBackend::new());
+    let mut reference = DB::new();
+    let (foo, bar) = ("foo".to_string(), "bar".to_string());
+    for i in 0..20u64 {
+        let rng = Range::new(i * 3, i * 3 + 4);
+        db.insert_object(&foo, rng, Object::new(vec![i as u8])).unwrap();
+        reference.insert_object(&foo, rng, Object::new(vec![i as u8])).unwrap();
+        let rng = Range::new((i * 7) % 40, (i * 7) % 40 + 2);
+        db.insert_bitmap(&bar, rng, Bitmap::new(1, vec![i as u8; 3])).unwrap();
+        reference.insert_bitmap(&bar, rng, Bitmap::new(1, vec![i as u8; 3])).unwrap();
+    }
+    db.delete_intersecting_objects(&foo, Range::new(10, 20)).unwrap();
+    reference.delete_intersecting_objects(&foo, Range::new(10, 20)).unwrap();
+    db.delete_bitmap(&bar, 1, Range::new(5, 12)).unwrap();
+    reference.delete_bitmap(&bar, 1, Range::new(5, 12)).unwrap();
+    db.rename_table(&bar, &"baz".to_string()).unwrap();
+    reference.rename_table(&bar, &"baz".to_string()).unwrap();
+
+    assert_eq!(db.tables(), reference.tables());
+    assert_eq!(db.query_object(&foo, Range::new(0, 100)).unwrap().map(|e| e.unwrap()).collect::<Vec<_>>(),
+               reference.query_object(&foo, Range::new(0, 100)).unwrap().map(|e| e.unwrap()).collect::<Vec<_>>());
+    assert_eq!(db.query_bitmap(&"baz".to_string(), Range::new(0, 100)).unwrap().map(|e| e.map(|(r, b)| (r, b.to_bitmap())).unwrap()).collect::<Vec<(Range, Bitmap)>>(),
+               reference.query_bitmap(&"baz".to_string(), Range::new(0, 100)).unwrap().map(|e| e.map(|(r, b)| (r, b.to_bitmap())).unwrap()).collect::<Vec<(Range, Bitmap)>>());
+    assert!(db.query_object(&bar, Range::new(0, 100)).is_none());
+}
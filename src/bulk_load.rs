@@ -0,0 +1,196 @@
+extern crate memrange;
+
+use self::memrange::Range;
+
+use std::collections::BTreeMap;
+use std::u64;
+
+use db::DB;
+use content::Object;
+use content::Bitmap;
+use dberror::DBError;
+use journal::Entry;
+use backend::StorageBackend;
+use serialize::Serialized;
+use persistent_tree::PersistentTree;
+
+#[cfg(test)] use table_options::{TableOptions, OverlapPolicy};
+
+/// Fails unless `next` sorts strictly after `previous`, ordered by start and
+/// then by end address.
+pub fn check_sorted(previous: Option<Range>, next: Range) -> Result<(), DBError> {
+    if let Some(previous) = previous {
+        if (previous.min, previous.max) >= (next.min, next.max) {
+            return Err(DBError::UnsortedInput{previous: previous, next: next});
+        }
+    }
+    return Ok(());
+}
+
+/// Builds a tree from entries sorted by `check_sorted` bottom-up in O(n),
+/// see `PersistentTree::from_sorted`.
+pub fn balanced_tree<T>(entries: Vec<(Range, T)>) -> PersistentTree<T> {
+    return PersistentTree::from_sorted(entries);
+}
+
+fn sorted_tree<T: Serialized, I>(iter: I) -> Result<PersistentTree<T>, DBError>
+    where I: IntoIterator<Item=(Range, T)> {
+    let mut entries = vec![];
+    let mut previous = None;
+    for (rng, data) in iter {
+        try!(check_sorted(previous, rng));
+        try!(data.validate(rng));
+        previous = Some(rng);
+        entries.push((rng, data));
+    }
+    return Ok(balanced_tree(entries));
+}
+
+/// Splits a stream sorted by table into one tree per table.
+fn sorted_tables<T: Serialized, I>(iter: I) -> Result<BTreeMap<String, PersistentTree<T>>, DBError>
+    where I: IntoIterator<Item=(String, Range, T)> {
+    let mut res = BTreeMap::new();
+    let mut current: Option<(String, Vec<(Range, T)>)> = None;
+    for (table, rng, data) in iter {
+        let same_table = current.as_ref().map(|&(ref name, _)| *name == table).unwrap_or(false);
+        if !same_table {
+            if let Some((name, entries)) = current.take() {
+                res.insert(name, try!(sorted_tree(entries)));
+            }
+            if res.contains_key(&table) || res.keys().next_back().map(|last| *last > table).unwrap_or(false) {
+                return Err(DBError::FileFormat(format!("input is not sorted by table at table {:?}", table)));
+            }
+            current = Some((table, vec![]));
+        }
+        current.as_mut().unwrap().1.push((rng, data));
+    }
+    if let Some((name, entries)) = current {
+        res.insert(name, try!(sorted_tree(entries)));
+    }
+    return Ok(res);
+}
+
+impl DB {
+    /// Builds a DB from objects and bitmaps sorted by table name and range.
+    /// Bitmaps are stored as given, adjacent ones are not merged.
+    #[must_use]
+    pub fn from_sorted_iter<O, B>(objects: O, bitmaps: B) -> Result<DB, DBError>
+        where O: IntoIterator<Item=(String, Range, Object)>,
+              B: IntoIterator<Item=(String, Range, Bitmap)> {
+        let mut db = DB::new_from_shared_data(try!(sorted_tables(objects)), try!(sorted_tables(bitmaps)));
+        let names = db.tables().into_iter().cloned().collect::<Vec<String>>();
+        for name in names {
+            try!(db.backend.add_table(&name));
+        }
+        return Ok(db);
+    }
+
+    /// Loads objects sorted by range into `table`, which must not contain
+    /// any objects yet. If the table has options, the objects are inserted
+    /// one by one with `insert_object` in a single transaction, so they are
+    /// packed, checked against the overlap policy and coalesced.
+    #[must_use]
+    pub fn load_sorted_objects<I>(&mut self, table: &String, iter: I) -> Result<(), DBError>
+        where I: IntoIterator<Item=(Range, Object)> {
+        try!(self.backend.materialize(table));
+        if self.backend.obj_map.get(table).map(|tree| !tree.is_empty()).unwrap_or(false) {
+            return Err(DBError::TableNotEmpty(table.clone()));
+        }
+        let tree = try!(sorted_tree(iter));
+        if self.options.contains_key(table) {
+            return self.transaction(|db| {
+                for (rng, data) in tree.range(0, u64::MAX) {
+                    try!(db.insert_object(table, rng, data.clone()));
+                }
+                return Ok(());
+            });
+        }
+        try!(self.backend_add_table(table));
+        for (rng, data) in tree.range(0, u64::MAX) {
+            self.log(Entry::InsertObject(table, rng, data));
+        }
+        self.record_loaded_objects(table, tree.range(0, u64::MAX).map(|(rng, _)| rng));
+        self.backend.obj_map.insert(table.clone(), tree);
+        return Ok(());
+    }
+
+    /// Loads bitmaps sorted by range into `table`, which must not contain
+    /// any bitmaps yet. Bitmaps are stored as given, adjacent ones are not
+    /// merged.
+    #[must_use]
+    pub fn load_sorted_bitmaps<I>(&mut self, table: &String, iter: I) -> Result<(), DBError>
+        where I: IntoIterator<Item=(Range, Bitmap)> {
+        try!(self.backend.materialize(table));
+        if self.backend.bit_map.get(table).map(|tree| !tree.is_empty()).unwrap_or(false) {
+            return Err(DBError::TableNotEmpty(table.clone()));
+        }
+        let tree = try!(sorted_tree(iter));
+        try!(self.backend_add_table(table));
+        for (rng, data) in tree.range(0, u64::MAX) {
+            self.log(Entry::InsertBitmap(table, rng, data));
+        }
+        self.record_loaded_bitmaps(table, tree.range(0, u64::MAX).map(|(rng, _)| rng));
+        self.backend.bit_map.insert(table.clone(), tree);
+        return Ok(());
+    }
+}
+
+#[test]
+fn test_from_sorted_iter() {
+    let (foo, bar) = ("foo".to_string(), "bar".to_string());
+    let objects = (0..100).map(|i| (if i < 50 { bar.clone() } else { foo.clone() }, Range::new(i * 10, i * 10 + 5), Object::new(vec![i as u8])));
+    let bitmaps = vec![(foo.clone(), Range::new(0, 1), Bitmap::new(1, vec![1, 2]))];
+    let db = DB::from_sorted_iter(objects, bitmaps).unwrap();
+    assert_eq!(db.tables(), vec![&bar, &foo]);
+    let found = db.query_object(&foo, Range::new(500, 519)).unwrap().map(|e| e.map(|(r, o)| (r, o.into_owned())).unwrap()).collect::<Vec<_>>();
+    assert_eq!(found, vec![(Range::new(500, 505), Object::new(vec![50])), (Range::new(510, 515), Object::new(vec![51]))]);
+    assert_eq!(db.query_object(&bar, Range::new(0, 10000)).unwrap().count(), 50);
+    assert_eq!(db.query_bitmap(&bar, Range::new(0, 10000)).unwrap().count(), 0);
+
+    let unsorted = vec![(foo.clone(), Range::new(5, 6), Object::new(vec![])), (foo.clone(), Range::new(5, 5), Object::new(vec![]))];
+    match DB::from_sorted_iter(unsorted, vec![]) {
+        Err(DBError::UnsortedInput{previous, next}) => assert_eq!((previous, next), (Range::new(5, 6), Range::new(5, 5))),
+        _ => panic!("expected unsorted input error"),
+    }
+    let tables_unsorted = vec![(foo.clone(), Range::new(5, 6), Object::new(vec![])), (bar.clone(), Range::new(5, 6), Object::new(vec![]))];
+    assert!(DB::from_sorted_iter(tables_unsorted, vec![]).is_err());
+    let invalid = vec![(foo.clone(), Range::new(0, 1), Bitmap::new(1, vec![1]))];
+    assert!(DB::from_sorted_iter(vec![], invalid).is_err());
+}
+
+#[test]
+fn test_load_sorted() {
+    let tbl = "foo".to_string();
+    let mut db = DB::new();
+    db.insert_bitmap(&tbl, Range::new(0, 0), Bitmap::new(1, vec![1])).unwrap();
+    db.load_sorted_objects(&tbl, (0..10).map(|i| (Range::new(i, i), Object::new(vec![])))).unwrap();
+    assert_eq!(db.query_object(&tbl, Range::new(0, 100)).unwrap().count(), 10);
+    match db.load_sorted_objects(&tbl, vec![]) {
+        Err(DBError::TableNotEmpty(ref t)) => assert_eq!(t, &tbl),
+        _ => panic!("loaded into a table with objects"),
+    }
+    match db.load_sorted_bitmaps(&tbl, vec![]) {
+        Err(DBError::TableNotEmpty(ref t)) => assert_eq!(t, &tbl),
+        _ => panic!("loaded into a table with bitmaps"),
+    }
+}
+
+#[test]
+fn test_load_sorted_with_options() {
+    let (foo, bar, baz) = ("foo".to_string(), "bar".to_string(), "baz".to_string());
+    let mut db = DB::new();
+    db.set_table_options(&foo, TableOptions{multimap: true, ..TableOptions::default()}).unwrap();
+    db.set_table_options(&bar, TableOptions{coalesce: true, ..TableOptions::default()}).unwrap();
+    db.set_table_options(&baz, TableOptions{overlap: OverlapPolicy::Reject, ..TableOptions::default()}).unwrap();
+
+    db.load_sorted_objects(&foo, vec![(Range::new(0, 1), Object::new(vec![1]))]).unwrap();
+    let values = db.query_object_values(&foo, Range::new(0, 100)).unwrap().map(|e| e.unwrap()).collect::<Vec<_>>();
+    assert_eq!(values, vec![(Range::new(0, 1), vec![Object::new(vec![1])])]);
+
+    db.load_sorted_objects(&bar, vec![(Range::new(0, 1), Object::new(vec![1])), (Range::new(2, 3), Object::new(vec![1]))]).unwrap();
+    let ranges = db.query_object(&bar, Range::new(0, 100)).unwrap().map(|e| e.unwrap().0).collect::<Vec<Range>>();
+    assert_eq!(ranges, vec![Range::new(0, 3)]);
+
+    assert!(db.load_sorted_objects(&baz, vec![(Range::new(0, 5), Object::new(vec![1])), (Range::new(2, 3), Object::new(vec![2]))]).is_err());
+    assert_eq!(db.query_object(&baz, Range::new(0, 100)).unwrap().count(), 0);
+}
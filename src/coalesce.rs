@@ -0,0 +1,75 @@
+use memrange::Range;
+
+use db::DB;
+use backend::StorageBackend;
+use content::Object;
+use dberror::DBError;
+
+#[cfg(test)] use table_options::{TableOptions, OverlapPolicy};
+
+impl<B: StorageBackend> DB<B> {
+    pub(crate) fn is_coalescing(&self, table: &String) -> bool {
+        return self.options.get(table).map(|options| options.coalesce).unwrap_or(false);
+    }
+
+    /// The range `d` covers once it is merged with all objects holding the
+    /// same data that touch or intersect `r`, together with the ranges of
+    /// these objects.
+    pub(crate) fn coalesced_range(&self, table: &String, r: Range, d: &Object) -> Result<(Range, Vec<Range>), DBError> {
+        let mut range = r;
+        let mut partners = vec![];
+        loop {
+            let mut found = vec![];
+            for entry in self.query_object(table, range.get_extended()).into_iter().flatten() {
+                let (rng, data) = try!(entry);
+                if *data == *d && !partners.contains(&rng) {
+                    found.push(rng);
+                }
+            }
+            if found.is_empty() {
+                return Ok((range, partners));
+            }
+            for rng in found {
+                range = range.get_union(&rng);
+                partners.push(rng);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn object_contents(db: &DB, tbl: &String) -> Vec<(Range, Object)> {
+    return db.query_object(tbl, Range::new(0, 100)).unwrap().map(|e| e.map(|(r, d)| (r, d.into_owned())).unwrap()).collect();
+}
+
+#[test]
+fn test_coalesce() {
+    let foo = "foo".to_string();
+    let (a, b) = (Object::new(vec![1]), Object::new(vec![2]));
+    let mut db = DB::new();
+    db.set_table_options(&foo, TableOptions{coalesce: true, ..TableOptions::default()}).unwrap();
+    for i in 0..10 {
+        db.insert_object(&foo, Range::new(i, i), a.clone()).unwrap();
+    }
+    db.insert_object(&foo, Range::new(10, 12), b.clone()).unwrap();
+    db.insert_object(&foo, Range::new(20, 25), a.clone()).unwrap();
+    assert_eq!(object_contents(&db, &foo), vec![(Range::new(0, 9), a.clone()),
+                                                (Range::new(10, 12), b.clone()),
+                                                (Range::new(20, 25), a.clone())]);
+
+    // bridging the gap merges with the objects on both sides
+    db.insert_object(&foo, Range::new(13, 19), b.clone()).unwrap();
+    db.insert_object(&foo, Range::new(11, 21), b.clone()).unwrap();
+    assert_eq!(object_contents(&db, &foo), vec![(Range::new(0, 9), a.clone()),
+                                                (Range::new(10, 21), b.clone()),
+                                                (Range::new(20, 25), a.clone())]);
+
+    // with an overlap policy the merged range counts
+    assert!(db.set_table_options(&foo, TableOptions{coalesce: true, overlap: OverlapPolicy::Evict, ..TableOptions::default()}).is_err());
+    db.delete_object(&foo, Range::new(20, 25)).unwrap();
+    db.set_table_options(&foo, TableOptions{coalesce: true, overlap: OverlapPolicy::Evict, ..TableOptions::default()}).unwrap();
+    db.insert_object(&foo, Range::new(8, 9), b.clone()).unwrap();
+    assert_eq!(object_contents(&db, &foo), vec![(Range::new(8, 21), b.clone())]);
+
+    assert!(db.set_table_options(&foo, TableOptions{coalesce: true, multimap: true, ..TableOptions::default()}).is_err());
+}
@@ -1,6 +1,8 @@
 use ::memrange::Range;
 use std::borrow::Cow;
 
+use dberror::DBError;
+
 #[derive(Clone, PartialEq, Debug, RustcEncodable, RustcDecodable)]
 pub struct Bitmap {
     pub entry_size: u64,
@@ -12,7 +14,7 @@ pub struct BitmapSlice<'a>{
     pub data: Cow<'a,[u8]>,
 }
 
-#[derive(Debug, RustcEncodable, RustcDecodable)]
+#[derive(Clone, PartialEq, Debug, RustcEncodable, RustcDecodable)]
 pub struct Object {
     pub data: Vec<u8>
 }
@@ -29,6 +31,31 @@ impl<'a> BitmapSlice<'a> {
     pub fn new_from_borrowed<'db>(b: &'db Bitmap) -> BitmapSlice<'db>{
         return BitmapSlice{entry_size: b.entry_size, data: Cow::Borrowed(&b.data)}
     }
+
+    /// Borrows the part of `data`, the entries of `data_range`, that lies
+    /// within `restriction_range`.
+    pub fn new_from_bytes(entry_size: u64, data: &'a [u8], data_range: Range, mut restriction_range: Range) -> Result<BitmapSlice<'a>, DBError> {
+        let invalid = DBError::InvalidSubrange{data_range: data_range, restriction_range: restriction_range};
+        if !restriction_range.intersect(&data_range) {
+            return Err(invalid);
+        }
+        restriction_range = restriction_range.get_intersection(&data_range);
+        let startoffset = (restriction_range.min - data_range.min).checked_mul(entry_size);
+        let endoffset = range_entries(restriction_range).and_then(|n| n.checked_mul(entry_size)).and_then(|len| startoffset.and_then(|start| start.checked_add(len)));
+        match (startoffset, endoffset) {
+            (Some(start), Some(end)) if end <= data.len() as u64 => {
+                let slice = &data[start as usize .. end as usize ];
+                return Ok(BitmapSlice{entry_size: entry_size, data: Cow::Borrowed(slice)})
+            }
+            _ => return Err(invalid),
+        }
+    }
+}
+
+/// Number of addresses in `range`, or `None` if it spans the whole `u64`
+/// address space and the count does not fit.
+pub fn range_entries(range: Range) -> Option<u64> {
+    return (range.max - range.min).checked_add(1);
 }
 
 impl Object {
@@ -44,21 +71,22 @@ impl Bitmap {
         return Bitmap{entry_size: es, data: data}
     }
 
-    pub fn to_subslice(&self, data_range: Range,  mut restriction_range: Range) -> BitmapSlice{
+    /// Checks that the bitmap holds exactly one entry for every address in `range`.
+    pub fn check_length(&self, range: Range) -> Result<(), DBError> {
+        let len = range_entries(range).and_then(|n| self.entry_size.checked_mul(n));
+        if len != Some(self.data.len() as u64) {
+            return Err(DBError::InvalidBitmapLength{range: range, entry_size: self.entry_size, got: self.data.len() as u64});
+        }
+        return Ok(());
+    }
 
-        restriction_range = restriction_range.get_intersection(&data_range);
-        let num_entries = restriction_range.len();
-        let startoffset = (restriction_range.min - data_range.min)*self.entry_size;
-        let endoffset = startoffset+num_entries*self.entry_size;
-        assert!( startoffset <= self.data.len() as u64 );
-        assert!( endoffset <= self.data.len() as u64 );
-        let slice = &self.data[startoffset as usize .. endoffset as usize ];
-        return BitmapSlice{entry_size: self.entry_size, data: Cow::Borrowed(slice)}
+    pub fn to_subslice(&self, data_range: Range, restriction_range: Range) -> Result<BitmapSlice, DBError> {
+        return BitmapSlice::new_from_bytes(self.entry_size, &self.data, data_range, restriction_range);
     }
 
-    pub fn to_subbitmap(&self, data_range: Range, restriction_range: Range) -> Bitmap{
-        let BitmapSlice{data: slice, ..} = self.to_subslice(data_range, restriction_range);
-        return Bitmap{entry_size: self.entry_size, data: slice.to_vec()}
+    pub fn to_subbitmap(&self, data_range: Range, restriction_range: Range) -> Result<Bitmap, DBError> {
+        let BitmapSlice{data: slice, ..} = try!(self.to_subslice(data_range, restriction_range));
+        return Ok(Bitmap{entry_size: self.entry_size, data: slice.to_vec()})
     }
 
     fn copy_to_buffer(&self, offset: u64, buffer: &mut Vec<u8>) {
@@ -67,19 +95,26 @@ impl Bitmap {
         }
     }
 
-    pub fn merge_bitmaps(self, data_range: Range, merge_partners: Vec<(Range, Bitmap)>) -> (Range, Bitmap) {
+    pub fn merge_bitmaps(self, data_range: Range, merge_partners: Vec<(Range, Bitmap)>) -> Result<(Range, Bitmap), DBError> {
 
+        try!(self.check_length(data_range));
         let mut new_range = data_range.clone();
         for &(rng, ref map) in &merge_partners {
-            assert_eq!(map.entry_size, self.entry_size);
+            if map.entry_size != self.entry_size {
+                return Err(DBError::EntrySizeMismatch{expected: self.entry_size, got: map.entry_size});
+            }
+            try!(map.check_length(rng));
             new_range = new_range.get_union(&rng)
         }
 
         if new_range == data_range {
-            return (data_range, self);
+            return Ok((data_range, self));
         }
 
-        let combined_len = ( new_range.len()*self.entry_size ) as usize;
+        let combined_len = match range_entries(new_range).and_then(|n| n.checked_mul(self.entry_size)) {
+            Some(len) if len <= usize::max_value() as u64 => len as usize,
+            _ => return Err(DBError::InvalidBitmapLength{range: new_range, entry_size: self.entry_size, got: self.data.len() as u64}),
+        };
         let mut combined = Vec::with_capacity(combined_len);
         combined.resize(combined_len, 0);
         for &(ref rng, ref cont) in &merge_partners {
@@ -88,6 +123,6 @@ impl Bitmap {
         }
         self.copy_to_buffer( (data_range.min - new_range.min)*self.entry_size, &mut combined );
 
-        return (new_range, Bitmap::new(self.entry_size, combined));
+        return Ok((new_range, Bitmap::new(self.entry_size, combined)));
     }
 }
@@ -3,139 +3,265 @@ extern crate memrange;
 
 use self::memrange::Range;
 use self::theban_interval_tree::IntervalTree;
-use self::theban_interval_tree::RangePairIter;
 
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::u64;
+#[cfg(test)] use std::borrow::Cow;
 
 use content::Object;
 use content::Bitmap;
+use dberror::DBError;
 use db_iterator::BitmapSliceIter;
-
-pub struct DB {
-    pub obj_map: BTreeMap<String, IntervalTree<Object>>,
-    pub bit_map: BTreeMap<String, IntervalTree<Bitmap>>,
+use journal::{Journal, Entry};
+use backend::{StorageBackend, MemoryBackend};
+use incremental::SavedDir;
+use table_options::TableOptions;
+use transaction::UndoLog;
+use persistent_tree::PersistentTree;
+use bulk_load::balanced_tree;
+
+pub struct DB<B: StorageBackend = MemoryBackend> {
+    pub(crate) backend: B,
+    pub(crate) journal: Option<Journal>,
+    /// Options of the tables that have any set.
+    pub(crate) options: BTreeMap<String, TableOptions>,
+    /// Tables changed since the last `save_incremental`. Changes made to the
+    /// backend directly are not tracked.
+    pub(crate) dirty: BTreeSet<String>,
+    pub(crate) saved_to: Option<SavedDir>,
+    pub(crate) undo: UndoLog,
+    /// Generation of the last journal whose records the tables contain, 0 if
+    /// they were never checkpointed.
+    pub(crate) generation: u64,
 }
 
 impl DB {
     pub fn new() -> DB {
-        return DB { obj_map: BTreeMap::new(), bit_map: BTreeMap::new() };
+        return DB::with_backend(MemoryBackend::new());
     }
 
     pub fn new_from_data(obj_map: BTreeMap<String, IntervalTree<Object>>, bit_map: BTreeMap<String, IntervalTree<Bitmap>>) -> DB {
-        return DB { obj_map: obj_map, bit_map: bit_map };
+        return DB::new_from_shared_data(obj_map.into_iter().map(|(name, tree)| (name, persistent_copy(&tree))).collect(),
+                                        bit_map.into_iter().map(|(name, tree)| (name, persistent_copy(&tree))).collect());
+    }
+
+    pub(crate) fn new_from_shared_data(obj_map: BTreeMap<String, PersistentTree<Object>>, bit_map: BTreeMap<String, PersistentTree<Bitmap>>) -> DB {
+        return DB::with_backend(MemoryBackend::new_from_shared_data(obj_map, bit_map));
+    }
+}
+
+/// Copies `tree` into the tree type used by the memory backend.
+fn persistent_copy<T: Clone>(tree: &IntervalTree<T>) -> PersistentTree<T> {
+    return balanced_tree(tree.range(0, u64::MAX).map(|(rng, data)| (rng, data.clone())).collect());
+}
+
+fn add_trunkated_version_of_bitmap(parts: &mut Vec<(Range, Bitmap)>,
+                                              old_range: Range,
+                                              old_bitmap: &Bitmap,
+                                              range_to_remove: Range) -> Result<(), DBError> {
+
+    let (first,last) = old_range.get_difference(&range_to_remove);
+    if let Some(first_part) = first {
+        parts.push((first_part, try!(old_bitmap.to_subbitmap(old_range, first_part))));
+    }
+    if let Some(last_part) = last {
+        parts.push((last_part, try!(old_bitmap.to_subbitmap(old_range, last_part))));
+    }
+    return Ok(());
+}
+
+impl<B: StorageBackend> DB<B> {
+    /// A DB storing its tables in `backend`.
+    pub fn with_backend(backend: B) -> DB<B> {
+        return DB { backend: backend, journal: None, options: BTreeMap::new(), dirty: BTreeSet::new(), saved_to: None, undo: UndoLog::default(), generation: 0 };
+    }
+
+    pub fn backend(&self) -> &B {
+        return &self.backend;
+    }
+
+    /// Changes made through the returned reference are neither journaled nor
+    /// tracked for `save_incremental`, and a rollback does not undo them.
+    pub fn backend_mut(&mut self) -> &mut B {
+        return &mut self.backend;
+    }
+
+    /// Logs a change that succeeded, see `prepare_log`.
+    pub(crate) fn log(&mut self, entry: Entry) {
+        let prepared = self.prepare_log(entry);
+        self.log_prepared(prepared);
+    }
+
+    /// Stores `d` at `r`. Depending on the `OverlapPolicy` of the table,
+    /// objects intersecting `r` are kept, make the insert fail or are removed.
+    /// In a coalescing table `d` is merged with equal neighbours first.
+    #[must_use]
+    pub fn insert_object(&mut self, table: &String, r: Range, d: Object) -> Result<(), DBError> {
+        let (range, merged) = if self.is_coalescing(table) { try!(self.coalesced_range(table, r, &d)) } else { (r, vec![]) };
+        let evicted = try!(self.overlapping_objects(table, range, &merged));
+        let entry = self.prepare_log(Entry::InsertObject(table, r, &d));
+        for rng in merged.into_iter().chain(evicted) {
+            try!(self.backend_delete_object(table, rng));
+        }
+        try!(self.store_object(table, range, d));
+        self.log_prepared(entry);
+        return Ok(());
     }
 
-    pub fn insert_object(&mut self, table: &String, r: Range, d: Object) {
-        self.add_table(table);
-        let mut tree = self.obj_map.get_mut(table).unwrap();
-        tree.insert(r, d);
+    /// Inserts `d` without looking at the overlap policy, the caller has to
+    /// log the insert.
+    pub(crate) fn store_object(&mut self, table: &String, r: Range, d: Object) -> Result<(), DBError> {
+        let d = if self.is_multimap(table) { try!(self.with_object_value(table, r, d)) } else { d };
+        return self.backend_insert_object(table, r, d);
     }
 
-    pub fn query_object(&self, table: &String, r: Range) -> Option<RangePairIter<Object>> {
-        return self.obj_map.get(table).map(|tree| tree.range(r.min, r.max));
+    /// Objects intersecting `r`. In a multimap table this yields the packed
+    /// values of each range, see `query_object_values`.
+    pub fn query_object<'a>(&'a self, table: &String, r: Range) -> Option<B::ObjectIter<'a>> {
+        return self.backend.query_objects(table, r);
     }
     
-    pub fn query_bitmap<'a>(&'a self, table: &String, r: Range) -> Option<BitmapSliceIter<'a>> {
-        return self.bit_map.get(table).map(|tree| BitmapSliceIter::new(tree.range(r.min, r.max), r));
+    pub fn query_bitmap<'a>(&'a self, table: &String, r: Range) -> Option<BitmapSliceIter<'a, B::BitmapIter<'a>>> {
+        return self.backend.query_bitmaps(table, r).map(|iter| BitmapSliceIter::new(iter, r));
     }
 
-    pub fn delete_object(&mut self, table: &String, r: Range) {
-        if let Some(mut tree) = self.obj_map.get_mut(table) {
-            tree.delete(r)
-        };
+    #[must_use]
+    pub fn delete_object(&mut self, table: &String, r: Range) -> Result<(), DBError> {
+        try!(self.backend_delete_object(table, r));
+        self.log(Entry::DeleteObject(table, r));
+        return Ok(());
     }
 
-    pub fn delete_intersecting_objects(&mut self, table: &String, r: Range) {
-        let ranges = if let Some(iter) = self.query_object(table, r) {
-            iter.map(|(range, _)| range.clone()).collect::<Vec<Range>>()
-        } else {
-            vec![]
-        };
+    #[must_use]
+    pub fn delete_intersecting_objects(&mut self, table: &String, r: Range) -> Result<(), DBError> {
+        let mut ranges = vec![];
+        if let Some(iter) = self.query_object(table, r) {
+            for entry in iter {
+                let (range, _) = try!(entry);
+                ranges.push(range);
+            }
+        }
         for range in ranges {
-            self.delete_object(table, range)
+            try!(self.backend_delete_object(table, range));
         }
+        self.log(Entry::DeleteIntersectingObjects(table, r));
+        return Ok(());
     }
 
-    fn get_overlaping_bitmaps(&mut self,
+    fn get_overlaping_bitmaps(&self,
                               table: &String,
                               r: Range,
                               entry_size: u64)
-                              -> Vec<(Range, Bitmap)> {
+                              -> Result<Vec<(Range, Bitmap)>, DBError> {
         let mut res = vec![];
-        if let Some(iter) = self.bit_map.get(table).map(|tree| tree.range(r.min,r.max)) {
-            for (rng, cont) in iter {
+        if let Some(iter) = self.backend.query_bitmaps(table, r) {
+            for entry in iter {
+                let (rng, cont) = try!(entry);
                 if cont.entry_size == entry_size && rng.intersect(&r) {
-                    res.push((rng, cont.clone()));
+                    res.push((rng, cont.into_owned()));
                 }
             }
         }
-        return res;
+        return Ok(res);
     }
 
-    fn delete_bitmaps_from_tree(&mut self, table: &String, bitmaps: &Vec<(Range, Bitmap)>) {
-        for &(rng, _) in bitmaps {
-            self.bit_map.get_mut(table).map(|mut tree| { tree.delete(rng) });
-        };
+    fn delete_bitmaps_from_backend(&mut self, table: &String, ranges: &Vec<Range>) -> Result<(), DBError> {
+        for &rng in ranges {
+            try!(self.backend_delete_bitmap(table, rng));
+        }
+        return Ok(());
     }
 
-    pub fn insert_bitmap(&mut self, table: &String, r: Range, d: Bitmap) {
-            assert_eq!(d.data.len() as u64, d.entry_size * r.len());
-            self.add_table(table);
+    #[must_use]
+    pub fn insert_bitmap(&mut self, table: &String, r: Range, d: Bitmap) -> Result<(), DBError> {
+            try!(d.check_length(r));
+            let entry = self.prepare_log(Entry::InsertBitmap(table, r, &d));
+            try!(self.backend_add_table(table));
 
-            let merge_partners = self.get_overlaping_bitmaps(table, r.get_extended(), d.entry_size);
+            let merge_partners = try!(self.get_overlaping_bitmaps(table, r.get_extended(), d.entry_size));
 
-            self.delete_bitmaps_from_tree(table, &merge_partners);
-            let (new_range, new_bitmap) = d.merge_bitmaps(r, merge_partners);
-
-            let mut tree = self.bit_map.get_mut(table).unwrap();
-            tree.insert(new_range, new_bitmap);
+            let partner_ranges = merge_partners.iter().map(|&(rng, _)| rng).collect::<Vec<Range>>();
+            let (new_range, new_bitmap) = try!(d.merge_bitmaps(r, merge_partners));
+            try!(self.delete_bitmaps_from_backend(table, &partner_ranges));
+            try!(self.backend_insert_bitmap(table, new_range, new_bitmap));
+            self.log_prepared(entry);
+            return Ok(());
     }
 
-    fn insert_subrange_bitmap(&mut self,
-                              table: &String,
-                              old_range: Range,
-                              new_range: Range,
-                              old_bitmap: &Bitmap,
-                                ) {
-        let data = old_bitmap.to_subbitmap(old_range, new_range);
-        let mut tree = self.bit_map.get_mut(table).unwrap();
-        tree.insert(new_range, data);
-    }
-
-    fn add_trunkated_version_of_bitmap(&mut self,
-                                       table: &String,
-                                       old_range: Range,
-                                       old_bitmap: Bitmap,
-                                       range_to_remove: Range) {
-
-        let (first,last) = old_range.get_difference(&range_to_remove);
-        if let Some(first_part) = first {
-            self.insert_subrange_bitmap(table, old_range, first_part, &old_bitmap)
+    #[must_use]
+    pub fn delete_bitmap(&mut self, table: &String,entry_size: u64, range_to_remove: Range) -> Result<(), DBError> {
+        let bitmaps_to_delete = try!(self.get_overlaping_bitmaps(table, range_to_remove, entry_size));
+        let mut remaining = vec![];
+        for &(rng, ref data) in &bitmaps_to_delete {
+            try!(add_trunkated_version_of_bitmap(&mut remaining, rng, data, range_to_remove.get_extended()));
+        }
+        let ranges = bitmaps_to_delete.iter().map(|&(rng, _)| rng).collect::<Vec<Range>>();
+        try!(self.delete_bitmaps_from_backend(table, &ranges));
+        for (rng, data) in remaining {
+            try!(self.backend_insert_bitmap(table, rng, data));
         }
-        if let Some(last_part) = last {
-            self.insert_subrange_bitmap(table, old_range, last_part, &old_bitmap)
+        self.log(Entry::DeleteBitmap(table, entry_size, range_to_remove));
+        return Ok(());
+    }
+
+    pub fn has_table(& self, table: &String) -> bool {
+        return self.backend.has_table(table);
+    }
+
+    /// Names of all tables in ascending order.
+    pub fn tables(&self) -> Vec<&String> {
+        return self.backend.tables();
+    }
+
+    #[must_use]
+    pub fn create_table(&mut self, table: &String) -> Result<(), DBError> {
+        if self.has_table(table) {
+            return Err(DBError::TableExists(table.clone()));
         }
+        try!(self.backend_add_table(table));
+        self.log(Entry::CreateTable(table));
+        return Ok(());
     }
 
-    pub fn delete_bitmap(&mut self, table: &String,entry_size: u64, range_to_remove: Range) {
-        let bitmaps_to_delete = self.get_overlaping_bitmaps(table, range_to_remove, entry_size);
-        self.delete_bitmaps_from_tree(table, &bitmaps_to_delete);
-        for (rng, data) in bitmaps_to_delete {
-            self.add_trunkated_version_of_bitmap(table, rng, data, range_to_remove.get_extended());
+    /// Removes the table together with all objects and bitmaps stored in it.
+    #[must_use]
+    pub fn drop_table(&mut self, table: &String) -> Result<(), DBError> {
+        if !self.has_table(table) {
+            return Err(DBError::NoSuchTable(table.clone()));
         }
+        self.set_options(table, None);
+        try!(self.backend_drop_table(table));
+        self.log(Entry::DropTable(table));
+        return Ok(());
     }
 
-    fn add_table(&mut self, table: &String) {
-        if !self.has_table(&table) {
-            self.obj_map.insert(table.clone(), IntervalTree::new());
-            self.bit_map.insert(table.clone(), IntervalTree::new());
+    #[must_use]
+    pub fn rename_table(&mut self, from: &String, to: &String) -> Result<(), DBError> {
+        if !self.has_table(from) {
+            return Err(DBError::NoSuchTable(from.clone()));
         }
+        if self.has_table(to) {
+            return Err(DBError::TableExists(to.clone()));
+        }
+        if let Some(options) = self.options.get(from).cloned() {
+            self.set_options(from, None);
+            self.set_options(to, Some(options));
+        }
+        try!(self.backend_rename_table(from, to));
+        self.log(Entry::RenameTable(from, to));
+        return Ok(());
     }
 
-    pub fn has_table(& self, table: &String) -> bool {
-        assert!(self.obj_map.contains_key(table) == self.bit_map.contains_key(table));
-        return self.obj_map.contains_key(table);
+    /// Removes all objects and bitmaps from the table but keeps the table itself.
+    #[must_use]
+    pub fn clear_table(&mut self, table: &String) -> Result<(), DBError> {
+        if !self.has_table(table) {
+            return Err(DBError::NoSuchTable(table.clone()));
+        }
+        try!(self.backend_clear_table(table));
+        self.log(Entry::ClearTable(table));
+        return Ok(());
     }
 }
 
@@ -145,22 +271,22 @@ fn test_ranges() {
     let tbl = "foo".to_string();
     db.insert_object(&tbl,
               Range::new(3, 4),
-              Object{data: "foo".into() } );
+              Object{data: "foo".into() } ).unwrap();
     db.insert_object(&tbl,
               Range::new(4, 5),
-              Object{data: "foo".into() } );
+              Object{data: "foo".into() } ).unwrap();
     db.insert_object(&tbl,
               Range::new(5, 6),
-              Object{ data:"foo".into() } );
+              Object{ data:"foo".into() } ).unwrap();
     let mut is = db.query_object(&tbl, Range::new(4, 4))
                    .unwrap()
-                   .map(|(r, _)| r.clone())
+                   .map(|e| e.unwrap().0)
                    .collect::<Vec<Range>>();
     assert_eq!(is, vec![Range::new(3, 4), Range::new(4, 5)]);
-    db.delete_intersecting_objects(&tbl, Range::new(3, 4));
+    db.delete_intersecting_objects(&tbl, Range::new(3, 4)).unwrap();
     is = db.query_object(&tbl, Range::new(0, 100))
            .unwrap()
-           .map(|(r, _)| r.clone())
+           .map(|e| e.unwrap().0)
            .collect::<Vec<Range>>();
     assert_eq!(is, vec![Range::new(5, 6)]);
 
@@ -171,7 +297,7 @@ fn test_ranges() {
 fn query_bitmap_test(db: &mut DB, tbl: &String, rng: Range) -> Vec<(Range,Bitmap)>{
     return db.query_bitmap(&tbl, rng)
                    .unwrap()
-                   .map(|(r, data)| (r.clone(), data.to_bitmap()) )
+                   .map(|e| e.map(|(r, data)| (r, data.to_bitmap())).unwrap() )
                    .collect::<Vec<(Range,Bitmap)>>();
 }
 
@@ -183,12 +309,12 @@ fn test_bitmaps_insert() {
     db.insert_bitmap(&tbl,
               Range::new(2, 7),
               Bitmap{ entry_size: 1, data: "foofoo".into() }
-              );
+              ).unwrap();
 
     db.insert_bitmap(&tbl,
               Range::new(5, 10),
               Bitmap{ entry_size: 1, data: "barbar".into() }
-              );
+              ).unwrap();
 
     let is = query_bitmap_test(&mut db, &tbl, Range::new(0, 50));
     assert_eq!(is, vec![(Range::new(2, 10), Bitmap{entry_size: 1, data: "foobarbar".into() } ) ]);
@@ -196,7 +322,7 @@ fn test_bitmaps_insert() {
     db.insert_bitmap(&tbl,
               Range::new(7, 9),
               Bitmap{ entry_size: 1, data: "goo".into() }
-              );
+              ).unwrap();
 
     let is = query_bitmap_test(&mut db, &tbl, Range::new(0, 50));
     assert_eq!(is, vec![(Range::new(2, 10), Bitmap{entry_size: 1, data: "foobagoor".into() } ) ]);
@@ -204,7 +330,7 @@ fn test_bitmaps_insert() {
     db.insert_bitmap(&tbl,
               Range::new(7, 9),
               Bitmap{ entry_size: 2, data: "googoo".into() }
-              );
+              ).unwrap();
 
     let is = query_bitmap_test(&mut db, &tbl, Range::new(0, 50));
     assert_eq!(is, vec![
@@ -217,9 +343,9 @@ fn test_bitmaps_insert() {
                (Range::new(2, 3), Bitmap{entry_size: 1, data: "fo".into() } ), 
                ]);
 
-    db.delete_bitmap(&tbl,1, Range::new(0, 1000));
-    db.delete_bitmap(&tbl,2, Range::new(0, 1000));
-    db.delete_bitmap(&tbl,3, Range::new(0, 1000));
+    db.delete_bitmap(&tbl,1, Range::new(0, 1000)).unwrap();
+    db.delete_bitmap(&tbl,2, Range::new(0, 1000)).unwrap();
+    db.delete_bitmap(&tbl,3, Range::new(0, 1000)).unwrap();
 
     let is = query_bitmap_test(&mut db, &tbl, Range::new(0, 1000));
     assert_eq!(is, vec![ ]);
@@ -227,8 +353,8 @@ fn test_bitmaps_insert() {
     db.insert_bitmap(&tbl,
               Range::new(0, 10),
               Bitmap{ entry_size: 1, data: "googooazabu".into() }
-              );
-    db.delete_bitmap(&tbl,1, Range::new(2, 3));
+              ).unwrap();
+    db.delete_bitmap(&tbl,1, Range::new(2, 3)).unwrap();
     let is = query_bitmap_test(&mut db, &tbl, Range::new(0, 1000));
 
     assert_eq!(is, vec![
@@ -236,7 +362,7 @@ fn test_bitmaps_insert() {
                (Range::new(4, 10), Bitmap{entry_size: 1, data: "ooazabu".into() } ), 
                ]);
 
-    db.delete_bitmap(&tbl,1, Range::new(0, 0));
+    db.delete_bitmap(&tbl,1, Range::new(0, 0)).unwrap();
     let is = query_bitmap_test(&mut db, &tbl, Range::new(0, 1000));
 
     assert_eq!(is, vec![
@@ -245,3 +371,74 @@ fn test_bitmaps_insert() {
                ]);
 
 }
+
+#[test]
+fn test_bitmaps_invalid() {
+    let mut db = DB::new();
+    let tbl = "tbl".to_string();
+
+    match db.insert_bitmap(&tbl, Range::new(2, 7), Bitmap{ entry_size: 2, data: "foofoo".into() }) {
+        Err(DBError::InvalidBitmapLength{range, entry_size: 2, got: 6}) => assert_eq!(range, Range::new(2, 7)),
+        _ => panic!("bitmap with wrong length was accepted"),
+    }
+    assert!(!db.has_table(&tbl));
+
+    db.insert_bitmap(&tbl, Range::new(2, 4), Bitmap{ entry_size: 2, data: "foofoo".into() }).unwrap();
+    let bitmap = db.backend.bit_map.get(&tbl).unwrap().get(Range::new(2, 4)).unwrap();
+    assert!(bitmap.to_subslice(Range::new(2, 4), Range::new(5, 6)).is_err());
+    assert!(bitmap.to_subslice(Range::new(2, 5), Range::new(2, 5)).is_err());
+    assert!(bitmap.clone().merge_bitmaps(Range::new(2, 4), vec![(Range::new(5, 5), Bitmap::new(1, "x".into()))]).is_err());
+
+    // the length of the full address range does not fit in a u64
+    let full = Range::new(0, u64::max_value());
+    assert!(Bitmap::new(1, "x".into()).check_length(full).is_err());
+    assert!(db.insert_bitmap(&tbl, full, Bitmap::new(1, "x".into())).is_err());
+    let merged = Bitmap::new(1, "x".into()).merge_bitmaps(Range::new(0, 0), vec![(Range::new(u64::max_value(), u64::max_value()), Bitmap::new(1, "y".into()))]);
+    assert!(merged.is_err());
+
+    // a bitmap that does not match its range is reported by the iterator
+    let entries = vec![Ok((Range::new(0, 3), Cow::Owned(Bitmap::new(1, "x".into()))))];
+    let mut iter = BitmapSliceIter::new(entries.into_iter(), Range::new(0, 3));
+    assert!(iter.next().unwrap().is_err());
+}
+
+#[test]
+fn test_table_management() {
+    let mut db = DB::new();
+    let (foo, bar, baz) = ("foo".to_string(), "bar".to_string(), "baz".to_string());
+
+    db.create_table(&foo).unwrap();
+    db.insert_object(&bar, Range::new(1, 2), Object::new("bar".into())).unwrap();
+    db.insert_bitmap(&bar, Range::new(1, 2), Bitmap::new(1, "ba".into())).unwrap();
+    assert_eq!(db.tables(), vec![&bar, &foo]);
+
+    match db.create_table(&foo) {
+        Err(DBError::TableExists(ref t)) if *t == foo => {},
+        _ => panic!("table was created twice"),
+    }
+    match db.rename_table(&baz, &foo) {
+        Err(DBError::NoSuchTable(ref t)) if *t == baz => {},
+        _ => panic!("missing table was renamed"),
+    }
+    match db.rename_table(&bar, &foo) {
+        Err(DBError::TableExists(ref t)) if *t == foo => {},
+        _ => panic!("table was renamed over an existing one"),
+    }
+
+    db.rename_table(&bar, &baz).unwrap();
+    assert_eq!(db.tables(), vec![&baz, &foo]);
+    assert!(db.query_object(&bar, Range::new(0, 10)).is_none());
+    assert_eq!(db.query_object(&baz, Range::new(0, 10)).unwrap().count(), 1);
+    assert_eq!(db.query_bitmap(&baz, Range::new(0, 10)).unwrap().count(), 1);
+
+    db.clear_table(&baz).unwrap();
+    assert!(db.has_table(&baz));
+    assert_eq!(db.query_object(&baz, Range::new(0, 10)).unwrap().count(), 0);
+    assert_eq!(db.query_bitmap(&baz, Range::new(0, 10)).unwrap().count(), 0);
+
+    db.drop_table(&baz).unwrap();
+    db.drop_table(&foo).unwrap();
+    assert!(db.tables().is_empty());
+    assert!(db.drop_table(&foo).is_err());
+    assert!(db.clear_table(&foo).is_err());
+}
@@ -1,16 +1,19 @@
-use ::theban_interval_tree::RangePairIter;
 use ::memrange::Range;
 
+use std::borrow::Cow;
+
+use backend::MemoryIter;
 use content::Bitmap;
 use content::BitmapSlice;
+use dberror::DBError;
 
-pub struct BitmapSliceIter<'a> {
-    orig: RangePairIter<'a, Bitmap>,
+pub struct BitmapSliceIter<'a, I = MemoryIter<'a, Bitmap>> where I: Iterator<Item=Result<(Range, Cow<'a, Bitmap>), DBError>> {
+    orig: I,
     orig_rng: Range,
 }
 
-impl<'a> BitmapSliceIter<'a> {
-    pub fn new(orig: RangePairIter<Bitmap>, rng: Range) -> BitmapSliceIter {
+impl<'a, I> BitmapSliceIter<'a, I> where I: Iterator<Item=Result<(Range, Cow<'a, Bitmap>), DBError>> {
+    pub fn new(orig: I, rng: Range) -> BitmapSliceIter<'a, I> {
         return BitmapSliceIter{orig: orig, orig_rng: rng};
     }
 
@@ -19,14 +22,18 @@ impl<'a> BitmapSliceIter<'a> {
     }
 }
 
-impl<'a> Iterator for BitmapSliceIter<'a> {
+impl<'a, I> Iterator for BitmapSliceIter<'a, I> where I: Iterator<Item=Result<(Range, Cow<'a, Bitmap>), DBError>> {
 
-    type Item = (Range,BitmapSlice<'a>);
+    type Item = Result<(Range,BitmapSlice<'a>), DBError>;
 
-    fn next(&mut self) -> Option<(Range,BitmapSlice<'a>)> {
-        if let Some(n) =  self.orig.get_next_node() {
-            return Some(( n.key.get_intersection(&self.orig_rng), n.data.to_subslice(n.key, self.orig_rng)) )
-        }
-        return None
+    fn next(&mut self) -> Option<Result<(Range,BitmapSlice<'a>), DBError>> {
+        let rng = self.orig_rng;
+        return self.orig.next().map(|entry| entry.and_then(|(key, data)| {
+            let slice = match data {
+                Cow::Borrowed(data) => try!(data.to_subslice(key, rng)),
+                Cow::Owned(data) => BitmapSlice::new_from_owned(try!(data.to_subbitmap(key, rng))),
+            };
+            return Ok((key.get_intersection(&rng), slice));
+        }));
     }
 }
@@ -1,6 +1,7 @@
 extern crate rmp;
 
 use std;
+use memrange::Range;
 
 quick_error! {
     #[derive(Debug)]
@@ -13,6 +14,47 @@ quick_error! {
             description("File fromat error")
             display("File format error: {}", err)
         }
+        NoSuchTable(table: String) {
+            description("No such table")
+            display("No such table: {}", table)
+        }
+        TableExists(table: String) {
+            description("Table exists")
+            display("Table exists: {}", table)
+        }
+        TableNotEmpty(table: String) {
+            description("Table not empty")
+            display("Table not empty: {}", table)
+        }
+        RangeOverflow { range: Range, delta: i64 } {
+            description("Range overflow")
+            display("Range overflow: {:?} can not be moved by {}", range, delta)
+        }
+        Overlap { range: Range, existing: Range } {
+            description("Overlapping object")
+            display("Overlapping object: {:?} intersects the object at {:?}", range, existing)
+        }
+        InvalidBitmapLength { range: Range, entry_size: u64, got: u64 } {
+            description("Invalid bitmap length")
+            display("Invalid bitmap length: {:?} with entry size {} can not hold {} bytes", range, entry_size, got)
+        }
+        EntrySizeMismatch { expected: u64, got: u64 } {
+            description("Entry size mismatch")
+            display("Entry size mismatch: expected {}, got {}", expected, got)
+        }
+        InvalidSubrange { data_range: Range, restriction_range: Range } {
+            description("Invalid subrange")
+            display("Invalid subrange: {:?} is not part of the bitmap at {:?}", restriction_range, data_range)
+        }
+        UnsortedInput { previous: Range, next: Range } {
+            description("Unsorted input")
+            display("Unsorted input: {:?} follows {:?}", next, previous)
+        }
+        Parse { offset: u64, path: String, err: Box<DBError> } {
+            description("Parse error")
+            display("Parse error at byte {} in {}: {}", offset, path, err)
+            cause(&**err)
+        }
         ParseString(err: String) {
             description("Parse string error")
             display("Parse string error")
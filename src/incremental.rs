@@ -0,0 +1,220 @@
+extern crate rmp;
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use db::DB;
+use dberror::DBError;
+use atomic_file;
+use persistent_tree::PersistentTree;
+use serialize::{DBReader, ReadLimits, write_string, parse_string, parse_table_name};
+
+#[cfg(test)] use memrange::Range;
+#[cfg(test)] use content::Object;
+
+/// The manifest names the file of every table: the magic followed by
+/// `[generation, {table: file}]`. Table files are regular DB files holding a
+/// single table and are never modified, a save writes new files for the
+/// changed tables and then replaces the manifest.
+const MANIFEST_NAME: &'static str = "MANIFEST";
+const MANIFEST_MAGIC: &'static [u8; 8] = b"THEBANMF";
+const TABLE_FILE_SUFFIX: &'static str = ".table";
+
+/// The directory a DB was last saved to or loaded from.
+pub struct SavedDir {
+    dir: PathBuf,
+    generation: u64,
+    files: BTreeMap<String, String>,
+}
+
+/// Table names may contain anything and be arbitrarily long, so file names
+/// only number the tables written by a save; the manifest maps them back.
+fn table_file_name(generation: u64, index: usize) -> String {
+    return format!("t{}.{}{}", generation, index, TABLE_FILE_SUFFIX);
+}
+
+fn write_manifest(mut w: &mut Write, generation: u64, files: &BTreeMap<String, String>) -> Result<(), DBError> {
+    try!(w.write_all(MANIFEST_MAGIC));
+    try!(rmp::encode::write_array_len(&mut w, 2));
+    try!(rmp::encode::write_u64(&mut w, generation));
+    try!(rmp::encode::write_map_len(&mut w, files.len() as u32));
+    for (table, file) in files {
+        try!(write_string(table, &mut w));
+        try!(write_string(file, &mut w));
+    }
+    return Ok(());
+}
+
+/// Reads the manifest in `dir`. Returns None if there is none.
+fn read_manifest(dir: &Path) -> Result<Option<(u64, BTreeMap<String, String>)>, DBError> {
+    let mut input = match File::open(dir.join(MANIFEST_NAME)) {
+        Ok(f) => BufReader::new(f),
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(DBError::IO(e)),
+    };
+    let mut r = DBReader::new(&mut input, ReadLimits::default());
+    r.push_field("manifest");
+    let res = read_manifest_body(&mut r);
+    return res.map(Some).map_err(|e| r.context(e));
+}
+
+fn read_manifest_body(r: &mut DBReader) -> Result<(u64, BTreeMap<String, String>), DBError> {
+    let mut magic = [0u8; 8];
+    if try!(rmp::decode::read_full(r, &mut magic)) != magic.len() || &magic != MANIFEST_MAGIC {
+        return Err(r.error("not a theban db manifest"));
+    }
+    if try!(rmp::decode::read_array_size(r)) != 2 {
+        return Err(r.error("manifest should have length 2"));
+    }
+    let generation = try!(rmp::decode::read_u64(r));
+    let len = try!(rmp::decode::read_map_size(r));
+    try!(r.count_tables(len as u64));
+    let mut files = BTreeMap::new();
+    for i in 0..len {
+        let table = try!(parse_table_name(r, i));
+        r.push_table(&table);
+        let file = try!(parse_string(r));
+        if file.contains('/') || file.contains('\\') || !file.ends_with(TABLE_FILE_SUFFIX) {
+            return Err(r.error(format!("invalid table file name {}", file)));
+        }
+        r.pop_path();
+        files.insert(table, file);
+    }
+    return Ok((generation, files));
+}
+
+impl DB {
+    /// Loads a DB saved with `save_incremental`.
+    #[must_use]
+    pub fn new_from_dir(dirname: &String) -> Result<DB, DBError> {
+        let dir = Path::new(dirname);
+        let (generation, files) = match try!(read_manifest(dir)) {
+            Some(manifest) => manifest,
+            None => return Err(DBError::FileFormat(format!("{} has no manifest", dirname))),
+        };
+        let mut db = DB::new();
+        for (table, file) in &files {
+            let part = try!(DB::new_from_file(&dir.join(file).to_string_lossy().into_owned()));
+            if part.tables() != vec![table] {
+                return Err(DBError::FileFormat(format!("{} does not hold table {}", file, table)));
+            }
+            db.merge_tables(&part);
+        }
+        db.saved_to = Some(SavedDir{dir: dir.to_path_buf(), generation: generation, files: files});
+        return Ok(db);
+    }
+
+    /// Tables changed since the DB was last saved with `save_incremental`.
+    pub fn dirty_tables(&self) -> Vec<&String> {
+        return self.dirty.iter().collect();
+    }
+
+    fn save_table(&self, table: &String, path: &Path) -> Result<(), DBError> {
+        let mut part = DB::new();
+        let objects = try!(self.backend.object_tree(table)).cloned().unwrap_or(PersistentTree::new());
+        let bitmaps = try!(self.backend.bitmap_tree(table)).cloned().unwrap_or(PersistentTree::new());
+        part.backend.obj_map.insert(table.clone(), objects);
+        part.backend.bit_map.insert(table.clone(), bitmaps);
+        if let Some(options) = self.options.get(table) {
+            part.options.insert(table.clone(), options.clone());
+        }
+        return part.save_to_file(&path.to_string_lossy().into_owned());
+    }
+
+    /// Saves the DB to the directory `dirname`, one file per table. If the DB
+    /// was saved to or loaded from that directory before, only the files of
+    /// tables changed since then are written, see `dirty_tables`.
+    ///
+    /// A save replaces the manifest of the directory atomically, so after a
+    /// crash the directory holds either the previous or the new state.
+    #[must_use]
+    pub fn save_incremental(&mut self, dirname: &String) -> Result<(), DBError> {
+        let dir = Path::new(dirname);
+        try!(fs::create_dir_all(dir));
+        let (generation, mut files, full) = match self.saved_to {
+            Some(ref saved) if saved.dir == dir => (saved.generation + 1, saved.files.clone(), false),
+            _ => {
+                let generation = try!(read_manifest(dir)).map(|(generation, _)| generation + 1).unwrap_or(1);
+                (generation, BTreeMap::new(), true)
+            }
+        };
+        let names = self.tables().into_iter().cloned().collect::<BTreeSet<String>>();
+        for (index, table) in names.iter().filter(|&table| full || self.dirty.contains(table)).enumerate() {
+            let file = table_file_name(generation, index);
+            try!(self.save_table(table, &dir.join(&file)));
+            files.insert(table.clone(), file);
+        }
+        files.retain(|table, _| names.contains(table));
+        try!(atomic_file::write_atomically(&dir.join(MANIFEST_NAME), |w| write_manifest(w, generation, &files)));
+
+        // files of older generations are no longer referenced
+        for entry in try!(fs::read_dir(dir)) {
+            let name = try!(entry).file_name().to_string_lossy().into_owned();
+            if name.ends_with(TABLE_FILE_SUFFIX) && !files.values().any(|file| *file == name) {
+                try!(fs::remove_file(dir.join(&name)));
+            }
+        }
+        self.saved_to = Some(SavedDir{dir: dir.to_path_buf(), generation: generation, files: files});
+        self.dirty.clear();
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+fn table_files(dir: &Path) -> Vec<String> {
+    let mut files = fs::read_dir(dir).unwrap()
+                       .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                       .filter(|name| name.ends_with(TABLE_FILE_SUFFIX))
+                       .collect::<Vec<String>>();
+    files.sort();
+    return files;
+}
+
+#[test]
+fn test_save_incremental() {
+    let dir = ::std::env::temp_dir().join(format!("theban_db_incremental_{}", ::std::process::id()));
+    let dirname = dir.to_string_lossy().into_owned();
+    let (foo, bar, baz) = ("foo".to_string(), "bar".to_string(), "b/z".to_string());
+    let mut db = DB::new();
+    db.insert_object(&foo, Range::new(0, 1), Object::new(vec![1])).unwrap();
+    db.insert_object(&bar, Range::new(0, 1), Object::new(vec![2])).unwrap();
+    db.create_table(&baz).unwrap();
+    assert_eq!(db.dirty_tables(), vec![&baz, &bar, &foo]);
+    db.save_incremental(&dirname).unwrap();
+    assert!(db.dirty_tables().is_empty());
+    assert_eq!(table_files(&dir), vec!["t1.0.table", "t1.1.table", "t1.2.table"]);
+
+    db.insert_object(&foo, Range::new(5, 6), Object::new(vec![3])).unwrap();
+    db.drop_table(&bar).unwrap();
+    assert_eq!(db.dirty_tables(), vec![&bar, &foo]);
+    db.save_incremental(&dirname).unwrap();
+    assert_eq!(table_files(&dir), vec!["t1.0.table", "t2.0.table"]);
+
+    let mut loaded = DB::new_from_dir(&dirname).unwrap();
+    assert_eq!(loaded.tables(), vec![&baz, &foo]);
+    assert_eq!(loaded.query_object(&foo, Range::new(0, 10)).unwrap().count(), 2);
+    loaded.rename_table(&baz, &bar).unwrap();
+    loaded.save_incremental(&dirname).unwrap();
+    assert_eq!(table_files(&dir), vec!["t2.0.table", "t3.0.table"]);
+    assert_eq!(DB::new_from_dir(&dirname).unwrap().tables(), vec![&bar, &foo]);
+
+    // a different directory gets all tables
+    let other = ::std::env::temp_dir().join(format!("theban_db_incremental_other_{}", ::std::process::id()));
+    loaded.save_incremental(&other.to_string_lossy().into_owned()).unwrap();
+    assert_eq!(table_files(&other).len(), 2);
+
+    // names far beyond the file name limit of the file system
+    let long = "x".repeat(1000);
+    loaded.insert_object(&long, Range::new(0, 1), Object::new(vec![4])).unwrap();
+    loaded.save_incremental(&dirname).unwrap();
+    let reloaded = DB::new_from_dir(&dirname).unwrap();
+    assert_eq!(reloaded.tables(), vec![&bar, &foo, &long]);
+    assert_eq!(reloaded.query_object(&long, Range::new(0, 10)).unwrap().count(), 1);
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&other).unwrap();
+}
@@ -0,0 +1,634 @@
+extern crate rmp;
+
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{BufWriter, Cursor};
+use std::path::{Path, PathBuf};
+
+use memrange::Range;
+
+use content::Bitmap;
+use content::Object;
+use db::DB;
+use backend::StorageBackend;
+use dberror::DBError;
+use table_options::TableOptions;
+use atomic_file;
+use serialize::{Serialized, DBReader, ReadLimits, write_vec, write_string, write_range, parse_bindata, parse_string, parse_range};
+
+#[cfg(test)] use shared_db::SharedDB;
+#[cfg(test)] use table_options::OverlapPolicy;
+
+const OP_INSERT_OBJECT: u64 = 0;
+const OP_DELETE_OBJECT: u64 = 1;
+const OP_DELETE_INTERSECTING_OBJECTS: u64 = 2;
+const OP_INSERT_BITMAP: u64 = 3;
+const OP_DELETE_BITMAP: u64 = 4;
+const OP_CREATE_TABLE: u64 = 5;
+const OP_DROP_TABLE: u64 = 6;
+const OP_RENAME_TABLE: u64 = 7;
+const OP_CLEAR_TABLE: u64 = 8;
+const OP_BATCH: u64 = 9;
+const OP_SET_TABLE_OPTIONS: u64 = 10;
+const OP_DELETE_OBJECT_VALUE: u64 = 11;
+/// First record of a journal, holds its generation.
+const OP_GENERATION: u64 = 12;
+
+/// A single mutation of the DB as it is recorded in the journal.
+pub enum Entry<'a> {
+    InsertObject(&'a String, Range, &'a Object),
+    DeleteObject(&'a String, Range),
+    DeleteObjectValue(&'a String, Range, &'a Object),
+    DeleteIntersectingObjects(&'a String, Range),
+    InsertBitmap(&'a String, Range, &'a Bitmap),
+    DeleteBitmap(&'a String, u64, Range),
+    CreateTable(&'a String),
+    DropTable(&'a String),
+    RenameTable(&'a String, &'a String),
+    ClearTable(&'a String),
+    SetTableOptions(&'a String, &'a TableOptions),
+}
+
+impl<'a> Entry<'a> {
+    /// Tables changed by the entry.
+    pub fn tables(&self) -> Vec<&'a String> {
+        return match *self {
+            Entry::InsertObject(table, _, _) | Entry::DeleteObject(table, _) | Entry::DeleteObjectValue(table, _, _) |
+            Entry::DeleteIntersectingObjects(table, _) | Entry::InsertBitmap(table, _, _) |
+            Entry::DeleteBitmap(table, _, _) | Entry::CreateTable(table) |
+            Entry::DropTable(table) | Entry::ClearTable(table) |
+            Entry::SetTableOptions(table, _) => vec![table],
+            Entry::RenameTable(from, to) => vec![from, to],
+        };
+    }
+
+    fn write(&self, mut w: &mut Write) -> Result<(), DBError> {
+        match *self {
+            Entry::InsertObject(table, r, obj) => {
+                try!(rmp::encode::write_array_len(&mut w, 5));
+                try!(rmp::encode::write_uint(&mut w, OP_INSERT_OBJECT));
+                try!(write_string(table, &mut w));
+                try!(write_range(r, &mut w));
+                try!(obj.write(&mut w));
+            }
+            Entry::DeleteObject(table, r) => {
+                try!(rmp::encode::write_array_len(&mut w, 4));
+                try!(rmp::encode::write_uint(&mut w, OP_DELETE_OBJECT));
+                try!(write_string(table, &mut w));
+                try!(write_range(r, &mut w));
+            }
+            Entry::DeleteObjectValue(table, r, obj) => {
+                try!(rmp::encode::write_array_len(&mut w, 5));
+                try!(rmp::encode::write_uint(&mut w, OP_DELETE_OBJECT_VALUE));
+                try!(write_string(table, &mut w));
+                try!(write_range(r, &mut w));
+                try!(obj.write(&mut w));
+            }
+            Entry::DeleteIntersectingObjects(table, r) => {
+                try!(rmp::encode::write_array_len(&mut w, 4));
+                try!(rmp::encode::write_uint(&mut w, OP_DELETE_INTERSECTING_OBJECTS));
+                try!(write_string(table, &mut w));
+                try!(write_range(r, &mut w));
+            }
+            Entry::InsertBitmap(table, r, bitmap) => {
+                try!(rmp::encode::write_array_len(&mut w, 5));
+                try!(rmp::encode::write_uint(&mut w, OP_INSERT_BITMAP));
+                try!(write_string(table, &mut w));
+                try!(write_range(r, &mut w));
+                try!(bitmap.write(&mut w));
+            }
+            Entry::DeleteBitmap(table, entry_size, r) => {
+                try!(rmp::encode::write_array_len(&mut w, 5));
+                try!(rmp::encode::write_uint(&mut w, OP_DELETE_BITMAP));
+                try!(write_string(table, &mut w));
+                try!(rmp::encode::write_uint(&mut w, entry_size));
+                try!(write_range(r, &mut w));
+            }
+            Entry::CreateTable(table) => {
+                try!(rmp::encode::write_array_len(&mut w, 2));
+                try!(rmp::encode::write_uint(&mut w, OP_CREATE_TABLE));
+                try!(write_string(table, &mut w));
+            }
+            Entry::DropTable(table) => {
+                try!(rmp::encode::write_array_len(&mut w, 2));
+                try!(rmp::encode::write_uint(&mut w, OP_DROP_TABLE));
+                try!(write_string(table, &mut w));
+            }
+            Entry::RenameTable(from, to) => {
+                try!(rmp::encode::write_array_len(&mut w, 3));
+                try!(rmp::encode::write_uint(&mut w, OP_RENAME_TABLE));
+                try!(write_string(from, &mut w));
+                try!(write_string(to, &mut w));
+            }
+            Entry::ClearTable(table) => {
+                try!(rmp::encode::write_array_len(&mut w, 2));
+                try!(rmp::encode::write_uint(&mut w, OP_CLEAR_TABLE));
+                try!(write_string(table, &mut w));
+            }
+            Entry::SetTableOptions(table, options) => {
+                try!(rmp::encode::write_array_len(&mut w, 3));
+                try!(rmp::encode::write_uint(&mut w, OP_SET_TABLE_OPTIONS));
+                try!(write_string(table, &mut w));
+                try!(options.write(&mut w));
+            }
+        }
+        return Ok(());
+    }
+}
+
+fn expect_len(len: u32, expected: u32, op: u64) -> Result<(), DBError> {
+    if len != expected {
+        return Err(DBError::FileFormat(format!("journal operation {} should have length {}", op, expected)));
+    }
+    return Ok(());
+}
+
+/// The generation stored in `record`, None if it is no generation record.
+fn parse_generation(record: &[u8]) -> Option<u64> {
+    let mut cursor = Cursor::new(record);
+    let mut r = DBReader::new(&mut cursor, ReadLimits::default());
+    return match (rmp::decode::read_array_size(&mut r), rmp::decode::read_u64_loosely(&mut r)) {
+        (Ok(2), Ok(OP_GENERATION)) => rmp::decode::read_u64_loosely(&mut r).ok(),
+        _ => None,
+    };
+}
+
+/// Decodes one journal record and applies it to `db`.
+fn apply_record(db: &mut DB, r: &mut DBReader) -> Result<(), DBError> {
+    let len = try!(rmp::decode::read_array_size(r));
+    let op = try!(rmp::decode::read_u64_loosely(r));
+    if op == OP_BATCH {
+        try!(expect_len(len, 2, op));
+        let count = try!(rmp::decode::read_array_size(r));
+        for i in 0..count {
+            r.push_index(i as u64);
+            try!(apply_record(db, r));
+            r.pop_path();
+        }
+        return Ok(());
+    }
+    let table = try!(parse_string(r));
+    match op {
+        OP_INSERT_OBJECT => {
+            try!(expect_len(len, 5, op));
+            let rng = try!(parse_range(r));
+            let obj = try!(Object::read(r));
+            try!(db.insert_object(&table, rng, obj));
+        }
+        OP_DELETE_OBJECT => {
+            try!(expect_len(len, 4, op));
+            try!(db.delete_object(&table, try!(parse_range(r))));
+        }
+        OP_DELETE_OBJECT_VALUE => {
+            try!(expect_len(len, 5, op));
+            let rng = try!(parse_range(r));
+            let obj = try!(Object::read(r));
+            try!(db.delete_object_value(&table, rng, &obj));
+        }
+        OP_DELETE_INTERSECTING_OBJECTS => {
+            try!(expect_len(len, 4, op));
+            try!(db.delete_intersecting_objects(&table, try!(parse_range(r))));
+        }
+        OP_INSERT_BITMAP => {
+            try!(expect_len(len, 5, op));
+            let rng = try!(parse_range(r));
+            let bitmap = try!(Bitmap::read(r));
+            try!(db.insert_bitmap(&table, rng, bitmap));
+        }
+        OP_DELETE_BITMAP => {
+            try!(expect_len(len, 5, op));
+            let entry_size = try!(rmp::decode::read_u64_loosely(r));
+            try!(db.delete_bitmap(&table, entry_size, try!(parse_range(r))));
+        }
+        OP_CREATE_TABLE => {
+            try!(expect_len(len, 2, op));
+            try!(db.create_table(&table));
+        }
+        OP_DROP_TABLE => {
+            try!(expect_len(len, 2, op));
+            try!(db.drop_table(&table));
+        }
+        OP_RENAME_TABLE => {
+            try!(expect_len(len, 3, op));
+            let to = try!(parse_string(r));
+            try!(db.rename_table(&table, &to));
+        }
+        OP_CLEAR_TABLE => {
+            try!(expect_len(len, 2, op));
+            try!(db.clear_table(&table));
+        }
+        OP_SET_TABLE_OPTIONS => {
+            try!(expect_len(len, 3, op));
+            let options = try!(TableOptions::read(r));
+            try!(db.set_table_options(&table, options));
+        }
+        _ => return Err(DBError::FileFormat(format!("unknown journal operation {}", op))),
+    }
+    return Ok(());
+}
+
+/// Replays every complete record of the journal at `path` on top of `db`.
+/// Returns the length of the valid prefix; anything behind it is the torn
+/// tail of a write that was interrupted by a crash. A journal whose
+/// generation `db` already contains is not replayed and its valid prefix is
+/// empty. Journals written before generations were introduced start without
+/// a generation record and are always replayed.
+fn replay(db: &mut DB, path: &Path) -> Result<u64, DBError> {
+    let mut buf = vec![];
+    try!(try!(File::open(path)).read_to_end(&mut buf));
+    let total = buf.len() as u64;
+    let mut cursor = Cursor::new(buf);
+    let mut frames = DBReader::new(&mut cursor, ReadLimits::default());
+    let mut valid = 0;
+    let mut index = 0;
+    while valid < total {
+        // A frame that cannot be read completely was torn, a complete frame
+        // that does not decode is real corruption.
+        let record = match parse_bindata(&mut frames) {
+            Ok(record) => record,
+            Err(_) => break,
+        };
+        if let (0, Some(generation)) = (index, parse_generation(&record)) {
+            if generation <= db.generation {
+                return Ok(0);
+            }
+            if generation != db.generation + 1 {
+                return Err(DBError::FileFormat(format!("journal generation {} does not follow snapshot generation {}", generation, db.generation)));
+            }
+            valid = frames.position();
+            index += 1;
+            continue;
+        }
+        let mut record_cursor = Cursor::new(record);
+        let mut r = DBReader::new(&mut record_cursor, ReadLimits::default());
+        r.push_field("journal");
+        r.push_index(index);
+        if let Err(e) = apply_record(db, &mut r) {
+            return Err(DBError::Parse{offset: valid, path: r.path(), err: Box::new(e)});
+        }
+        valid = frames.position();
+        index += 1;
+    }
+    return Ok(valid);
+}
+
+/// Append-only log of all mutations since the last checkpoint. Write errors
+/// are latched and reported by the next `sync` or `checkpoint`.
+///
+/// Every checkpoint starts a new generation of the journal. Its number is
+/// the first record of the journal and stored in the snapshot once all its
+/// records are contained in it.
+///
+/// Mutations made inside a transaction are buffered and written as a single
+/// batch record when the outermost transaction commits.
+pub struct Journal {
+    path: PathBuf,
+    snapshot: PathBuf,
+    file: BufWriter<File>,
+    generation: u64,
+    error: Option<DBError>,
+    batch: Vec<u8>,
+    batch_len: u32,
+    depth: u32,
+}
+
+/// Position in the batch buffer a rolled back transaction returns to.
+pub struct BatchMark {
+    len: usize,
+    records: u32,
+}
+
+/// An entry encoded ahead of the change it describes, see `DB::prepare_log`.
+pub struct PreparedEntry {
+    record: Option<Vec<u8>>,
+    tables: Vec<String>,
+}
+
+impl Journal {
+    /// Opens the journal at `path` for appending, truncated to `valid_len`
+    /// bytes. An empty journal starts with the record of `generation`.
+    fn open(path: &Path, snapshot: &Path, valid_len: u64, generation: u64) -> Result<Journal, DBError> {
+        let file = try!(OpenOptions::new().create(true).append(true).open(path));
+        if try!(file.metadata()).len() != valid_len {
+            try!(file.set_len(valid_len));
+            try!(file.sync_all());
+        }
+        let mut journal = Journal{path: path.to_path_buf(), snapshot: snapshot.to_path_buf(), file: BufWriter::new(file),
+                                  generation: generation, error: None, batch: vec![], batch_len: 0, depth: 0};
+        if valid_len == 0 {
+            try!(journal.write_generation());
+        }
+        return Ok(journal);
+    }
+
+    fn write_generation(&mut self) -> Result<(), DBError> {
+        let mut record = vec![];
+        try!(rmp::encode::write_array_len(&mut record, 2));
+        try!(rmp::encode::write_uint(&mut record, OP_GENERATION));
+        try!(rmp::encode::write_uint(&mut record, self.generation));
+        try!(write_vec(&record, &mut self.file));
+        try!(self.file.flush());
+        try!(self.file.get_ref().sync_all());
+        return Ok(());
+    }
+
+    /// Encodes `entry` for `append_record`. None once the journal failed.
+    fn encode(&mut self, entry: &Entry) -> Option<Vec<u8>> {
+        if self.error.is_some() {
+            return None;
+        }
+        let mut record = vec![];
+        if let Err(e) = entry.write(&mut record) {
+            self.error = Some(e);
+            return None;
+        }
+        return Some(record);
+    }
+
+    fn append_record(&mut self, record: Vec<u8>) {
+        if self.error.is_some() {
+            return;
+        }
+        if self.depth > 0 {
+            self.batch_len += 1;
+            self.batch.extend_from_slice(&record);
+            return;
+        }
+        if let Err(e) = write_vec(&record, &mut self.file) {
+            self.error = Some(e);
+        }
+    }
+
+    pub fn begin(&mut self) -> BatchMark {
+        self.depth += 1;
+        return BatchMark{len: self.batch.len(), records: self.batch_len};
+    }
+
+    pub fn commit(&mut self) {
+        self.depth -= 1;
+        if self.depth > 0 || self.batch_len == 0 {
+            return;
+        }
+        let res = self.write_batch();
+        self.batch.clear();
+        self.batch_len = 0;
+        if let Err(e) = res {
+            if self.error.is_none() {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn write_batch(&mut self) -> Result<(), DBError> {
+        let mut record = vec![];
+        try!(rmp::encode::write_array_len(&mut record, 2));
+        try!(rmp::encode::write_uint(&mut record, OP_BATCH));
+        try!(rmp::encode::write_array_len(&mut record, self.batch_len));
+        record.extend_from_slice(&self.batch);
+        return write_vec(&record, &mut self.file);
+    }
+
+    pub fn rollback(&mut self, mark: BatchMark) {
+        self.depth -= 1;
+        self.batch.truncate(mark.len);
+        self.batch_len = mark.records;
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        return self.depth > 0;
+    }
+
+    pub fn sync(&mut self) -> Result<(), DBError> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        try!(self.file.flush());
+        try!(self.file.get_ref().sync_data());
+        return Ok(());
+    }
+
+    /// Empties the journal and starts `generation`.
+    fn restart(&mut self, generation: u64) -> Result<(), DBError> {
+        try!(self.file.flush());
+        try!(self.file.get_ref().set_len(0));
+        self.generation = generation;
+        return self.write_generation();
+    }
+}
+
+impl<B: StorageBackend> DB<B> {
+    /// Encodes `entry` before the change it describes consumes its data.
+    /// Changes are logged once they succeeded, so a change that fails never
+    /// ends up in the journal and replay does not trip over it.
+    pub(crate) fn prepare_log(&mut self, entry: Entry) -> PreparedEntry {
+        let record = self.journal.as_mut().and_then(|journal| journal.encode(&entry));
+        return PreparedEntry{record: record, tables: entry.tables().into_iter().cloned().collect()};
+    }
+
+    pub(crate) fn log_prepared(&mut self, entry: PreparedEntry) {
+        if let (Some(journal), Some(record)) = (self.journal.as_mut(), entry.record) {
+            journal.append_record(record);
+        }
+        self.dirty.extend(entry.tables);
+    }
+}
+
+impl DB {
+    /// Opens the database stored in the snapshot file `snapshot` (starting
+    /// empty if it does not exist yet), replays the journal `journal` on top
+    /// of it and records all further mutations in that journal.
+    #[must_use]
+    pub fn open_journaled(snapshot: &String, journal: &String) -> Result<DB, DBError> {
+        let snapshot_path = Path::new(snapshot);
+        let journal_path = Path::new(journal);
+        let mut db = if snapshot_path.exists() { try!(DB::new_from_file(snapshot)) } else { DB::new() };
+        let valid_len = if journal_path.exists() { try!(replay(&mut db, journal_path)) } else { 0 };
+        let generation = db.generation + 1;
+        db.journal = Some(try!(Journal::open(journal_path, snapshot_path, valid_len, generation)));
+        try!(atomic_file::sync_parent_dir(journal_path));
+        return Ok(db);
+    }
+
+    /// Makes every mutation recorded so far durable.
+    #[must_use]
+    pub fn sync_journal(&mut self) -> Result<(), DBError> {
+        return match self.journal {
+            Some(ref mut journal) => journal.sync(),
+            None => Ok(()),
+        };
+    }
+
+    /// Writes a full snapshot and empties the journal. The snapshot records
+    /// the generation of the journal, so after a crash between the two steps
+    /// `open_journaled` skips the journal instead of replaying it a second
+    /// time.
+    #[must_use]
+    pub fn checkpoint(&mut self) -> Result<(), DBError> {
+        let (snapshot, generation) = match self.journal {
+            Some(ref mut journal) => {
+                if journal.in_transaction() {
+                    return Err(DBError::Protocol("cannot checkpoint inside a transaction".into()));
+                }
+                try!(journal.sync());
+                (journal.snapshot.to_string_lossy().into_owned(), journal.generation)
+            }
+            None => return Err(DBError::Protocol("checkpoint requires a journaled DB".into())),
+        };
+        let previous = self.generation;
+        self.generation = generation;
+        if let Err(e) = self.save_to_file(&snapshot) {
+            self.generation = previous;
+            return Err(e);
+        }
+        if let Some(ref mut journal) = self.journal {
+            try!(journal.restart(generation + 1));
+        }
+        return Ok(());
+    }
+
+    /// Makes the journal durable and detaches it, later mutations are not
+    /// journaled.
+    #[must_use]
+    pub fn close_journal(&mut self) -> Result<(), DBError> {
+        if let Some(ref mut journal) = self.journal {
+            if journal.in_transaction() {
+                return Err(DBError::Protocol("cannot close the journal inside a transaction".into()));
+            }
+            try!(journal.sync());
+        }
+        self.journal = None;
+        return Ok(());
+    }
+
+    pub fn journal_path(&self) -> Option<&Path> {
+        return self.journal.as_ref().map(|j| j.path.as_path());
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn temp_paths(name: &str) -> (String, String) {
+    let dir = ::std::env::temp_dir();
+    let snapshot = dir.join(format!("theban_db_{}_{}.db", name, ::std::process::id()));
+    let journal = dir.join(format!("theban_db_{}_{}.log", name, ::std::process::id()));
+    let _ = ::std::fs::remove_file(&snapshot);
+    let _ = ::std::fs::remove_file(&journal);
+    return (snapshot.to_string_lossy().into_owned(), journal.to_string_lossy().into_owned());
+}
+
+#[cfg(test)]
+fn object_ranges(db: &DB, tbl: &String) -> Vec<Range> {
+    return db.query_object(tbl, Range::new(0, 100))
+             .map(|iter| iter.map(|e| e.unwrap().0).collect())
+             .unwrap_or(vec![]);
+}
+
+#[test]
+fn test_journal_replay() {
+    let (snapshot, journal) = temp_paths("replay");
+    let tbl = "foo".to_string();
+    let empty_len;
+    {
+        let mut db = DB::open_journaled(&snapshot, &journal).unwrap();
+        empty_len = ::std::fs::metadata(&journal).unwrap().len();
+        db.insert_object(&tbl, Range::new(3, 4), Object::new("foo".into())).unwrap();
+        db.insert_object(&tbl, Range::new(5, 6), Object::new("bar".into())).unwrap();
+        db.insert_object(&tbl, Range::new(8, 9), Object::new("baz".into())).unwrap();
+        db.delete_intersecting_objects(&tbl, Range::new(4, 5)).unwrap();
+        db.insert_bitmap(&tbl, Range::new(0, 2), Bitmap::new(1, "goo".into())).unwrap();
+        db.delete_bitmap(&tbl, 1, Range::new(2, 2)).unwrap();
+        let tmp = "tmp".to_string();
+        db.rename_table(&tbl, &tmp).unwrap();
+        db.create_table(&tbl).unwrap();
+        db.drop_table(&tbl).unwrap();
+        db.rename_table(&tmp, &tbl).unwrap();
+        db.create_table(&tmp).unwrap();
+        db.sync_journal().unwrap();
+    }
+
+    // a torn record at the end of the journal is dropped
+    OpenOptions::new().append(true).open(&journal).unwrap().write_all(&[0xa5, b'x']).unwrap();
+
+    let mut db = DB::open_journaled(&snapshot, &journal).unwrap();
+    assert_eq!(db.tables(), vec![&tbl, &"tmp".to_string()]);
+    assert_eq!(object_ranges(&db, &tbl), vec![Range::new(8, 9)]);
+    let bitmaps = db.query_bitmap(&tbl, Range::new(0, 10)).unwrap()
+                    .map(|e| e.map(|(r, b)| (r, b.to_bitmap())).unwrap())
+                    .collect::<Vec<(Range, Bitmap)>>();
+    assert_eq!(bitmaps, vec![(Range::new(0, 1), Bitmap::new(1, "go".into()))]);
+
+    db.checkpoint().unwrap();
+    assert_eq!(::std::fs::metadata(&journal).unwrap().len(), empty_len);
+    db.delete_object(&tbl, Range::new(8, 9)).unwrap();
+    db.sync_journal().unwrap();
+    drop(db);
+
+    let db = DB::open_journaled(&snapshot, &journal).unwrap();
+    assert_eq!(object_ranges(&db, &tbl), vec![]);
+
+    // a journaled DB can only be shared once its journal is closed
+    let mut db = SharedDB::from_db(db).err().unwrap();
+    db.close_journal().unwrap();
+    assert!(SharedDB::from_db(db).is_ok());
+    ::std::fs::remove_file(&snapshot).unwrap();
+    ::std::fs::remove_file(&journal).unwrap();
+}
+
+#[test]
+fn test_checkpoint_crash() {
+    let (snapshot, journal) = temp_paths("checkpoint_crash");
+    let (foo, bar) = ("foo".to_string(), "bar".to_string());
+    let stale = {
+        let mut db = DB::open_journaled(&snapshot, &journal).unwrap();
+        db.insert_object(&foo, Range::new(0, 1), Object::new(vec![1])).unwrap();
+        db.rename_table(&foo, &bar).unwrap();
+        db.create_table(&foo).unwrap();
+        db.sync_journal().unwrap();
+        let stale = ::std::fs::read(&journal).unwrap();
+        db.checkpoint().unwrap();
+        stale
+    };
+    // a crash after the snapshot was written but before the journal was emptied
+    ::std::fs::write(&journal, &stale).unwrap();
+
+    let mut db = DB::open_journaled(&snapshot, &journal).unwrap();
+    assert_eq!(db.tables(), vec![&bar, &foo]);
+    assert_eq!(object_ranges(&db, &bar), vec![Range::new(0, 1)]);
+    assert_eq!(object_ranges(&db, &foo), vec![]);
+    db.insert_object(&foo, Range::new(2, 3), Object::new(vec![2])).unwrap();
+    db.sync_journal().unwrap();
+    drop(db);
+    let db = DB::open_journaled(&snapshot, &journal).unwrap();
+    assert_eq!(object_ranges(&db, &foo), vec![Range::new(2, 3)]);
+    drop(db);
+    let lazy = DB::open_lazy(&snapshot).unwrap();
+    assert_eq!(object_ranges(&lazy, &bar), vec![Range::new(0, 1)]);
+    assert!(!DB::verify_file(&snapshot).unwrap().truncated);
+
+    // the journal does not fit a snapshot older than the one it continues
+    ::std::fs::remove_file(&snapshot).unwrap();
+    assert!(DB::open_journaled(&snapshot, &journal).is_err());
+    ::std::fs::remove_file(&journal).unwrap();
+}
+
+#[test]
+fn test_failed_changes_are_not_journaled() {
+    let (snapshot, journal) = temp_paths("failed");
+    let tbl = "foo".to_string();
+    {
+        let mut db = DB::open_journaled(&snapshot, &journal).unwrap();
+        db.set_table_options(&tbl, TableOptions{multimap: true, overlap: OverlapPolicy::Reject, ..TableOptions::default()}).unwrap();
+        db.insert_object(&tbl, Range::new(0, 9), Object::new(vec![1])).unwrap();
+        assert!(db.insert_object(&tbl, Range::new(5, 15), Object::new(vec![2])).is_err());
+        // a value list that can not be unpacked makes the insert fail after
+        // the overlap policy was checked
+        db.backend_mut().insert_object(&tbl, Range::new(20, 29), Object::new(vec![0xc1])).unwrap();
+        db.checkpoint().unwrap();
+        assert!(db.insert_object(&tbl, Range::new(20, 29), Object::new(vec![3])).is_err());
+        db.sync_journal().unwrap();
+    }
+
+    let db = DB::open_journaled(&snapshot, &journal).unwrap();
+    assert_eq!(object_ranges(&db, &tbl), vec![Range::new(0, 9), Range::new(20, 29)]);
+    ::std::fs::remove_file(&snapshot).unwrap();
+    ::std::fs::remove_file(&journal).unwrap();
+}
@@ -0,0 +1,293 @@
+extern crate memrange;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use db::DB;
+use backend::MemoryBackend;
+use content::Object;
+use content::Bitmap;
+use dberror::DBError;
+use serialize::{Serialized, TableDirectory};
+use persistent_tree::PersistentTree;
+
+#[cfg(test)] use memrange::Range;
+#[cfg(test)] use std::io::{Seek, SeekFrom, Write};
+
+/// The file a lazily opened DB loads its tables from.
+struct LazySource {
+    file: Mutex<File>,
+    directory: TableDirectory,
+}
+
+/// A tree that is read from the file on first access.
+pub struct LazyTree<T> {
+    offset: u64,
+    tree: OnceLock<PersistentTree<T>>,
+}
+
+impl<T: Serialized> LazyTree<T> {
+    fn new(offset: u64) -> LazyTree<T> {
+        return LazyTree{offset: offset, tree: OnceLock::new()};
+    }
+
+    fn get(&self, source: &LazySource, section: &str, name: &String) -> Result<&PersistentTree<T>, DBError> {
+        if let Some(tree) = self.tree.get() {
+            return Ok(tree);
+        }
+        let tree = {
+            let mut file = source.file.lock().expect("lazy file lock poisoned");
+            try!(source.directory.read_tree(&mut file, section, name, self.offset))
+        };
+        // a concurrent reader may have been faster, both trees are the same
+        let _ = self.tree.set(tree);
+        return Ok(self.tree.get().unwrap());
+    }
+
+    fn is_loaded(&self) -> bool {
+        return self.tree.get().is_some();
+    }
+}
+
+/// Tables of a lazily opened DB that have not been modified since the file
+/// was opened. Once a table is modified it moves to the regular maps.
+#[derive(Clone)]
+pub struct LazyTables {
+    source: Arc<LazySource>,
+    objects: BTreeMap<String, Arc<LazyTree<Object>>>,
+    bitmaps: BTreeMap<String, Arc<LazyTree<Bitmap>>>,
+}
+
+impl LazyTables {
+    pub fn names(&self) -> Vec<&String> {
+        return self.objects.keys().chain(self.bitmaps.keys()).collect();
+    }
+
+    pub fn contains(&self, table: &String) -> bool {
+        return self.objects.contains_key(table) || self.bitmaps.contains_key(table);
+    }
+
+    /// Copy that only holds `table`.
+    pub fn restrict(&self, table: &String) -> LazyTables {
+        let mut res = LazyTables{source: self.source.clone(), objects: BTreeMap::new(), bitmaps: BTreeMap::new()};
+        if let Some(tree) = self.objects.get(table) {
+            res.objects.insert(table.clone(), tree.clone());
+        }
+        if let Some(tree) = self.bitmaps.get(table) {
+            res.bitmaps.insert(table.clone(), tree.clone());
+        }
+        return res;
+    }
+
+    /// Adds the tables of `other`, which must come from the same file.
+    pub fn merge(&mut self, other: &LazyTables) {
+        assert!(Arc::ptr_eq(&self.source, &other.source), "lazy tables from different files");
+        self.objects.extend(other.objects.iter().map(|(name, tree)| (name.clone(), tree.clone())));
+        self.bitmaps.extend(other.bitmaps.iter().map(|(name, tree)| (name.clone(), tree.clone())));
+    }
+
+    pub fn remove(&mut self, table: &String) {
+        self.objects.remove(table);
+        self.bitmaps.remove(table);
+    }
+}
+
+impl DB {
+    /// Opens the database stored in `filename` without loading any table.
+    /// Only the table directory at the end of the file is read, a table is
+    /// loaded when it is first accessed and can be evicted again with
+    /// `evict_table`. Files written without a directory are loaded eagerly.
+    ///
+    /// If the file turns out to be damaged, a query that implicitly loads a
+    /// table yields the error as its first entry, a mutation returns it.
+    #[must_use]
+    pub fn open_lazy(filename: &String) -> Result<DB, DBError> {
+        let mut file = try!(File::open(filename));
+        let directory = match try!(TableDirectory::read(&mut file)) {
+            Some(directory) => directory,
+            None => return DB::new_from_file(filename),
+        };
+        let objects = directory.objects.iter().map(|(name, &offset)| (name.clone(), Arc::new(LazyTree::new(offset)))).collect();
+        let bitmaps = directory.bitmaps.iter().map(|(name, &offset)| (name.clone(), Arc::new(LazyTree::new(offset)))).collect();
+        let options = directory.options.clone();
+        let source = LazySource{file: Mutex::new(file), directory: directory};
+        let mut db = DB::new();
+        db.options = options;
+        db.backend.lazy = Some(LazyTables{source: Arc::new(source), objects: objects, bitmaps: bitmaps});
+        return Ok(db);
+    }
+
+    /// Loads `table` if it was not loaded yet.
+    #[must_use]
+    pub fn load_table(&self, table: &String) -> Result<(), DBError> {
+        if let Some(ref lazy) = self.backend.lazy {
+            if let Some(tree) = lazy.objects.get(table) {
+                try!(tree.get(&lazy.source, "objects", table));
+            }
+            if let Some(tree) = lazy.bitmaps.get(table) {
+                try!(tree.get(&lazy.source, "bitmaps", table));
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn is_table_loaded(&self, table: &String) -> bool {
+        return match self.backend.lazy {
+            Some(ref lazy) => lazy.objects.get(table).map(|t| t.is_loaded()).unwrap_or(true) &&
+                              lazy.bitmaps.get(table).map(|t| t.is_loaded()).unwrap_or(true),
+            None => true,
+        };
+    }
+
+    /// Drops the loaded copy of a table that has not been modified since the
+    /// DB was opened with `open_lazy`. Returns false for any other table.
+    pub fn evict_table(&mut self, table: &String) -> bool {
+        let lazy = match self.backend.lazy {
+            Some(ref mut lazy) => lazy,
+            None => return false,
+        };
+        if !lazy.contains(table) {
+            return false;
+        }
+        if let Some(tree) = lazy.objects.get_mut(table) {
+            let offset = tree.offset;
+            *tree = Arc::new(LazyTree::new(offset));
+        }
+        if let Some(tree) = lazy.bitmaps.get_mut(table) {
+            let offset = tree.offset;
+            *tree = Arc::new(LazyTree::new(offset));
+        }
+        return true;
+    }
+}
+
+impl MemoryBackend {
+    pub(crate) fn object_tree(&self, table: &String) -> Result<Option<&PersistentTree<Object>>, DBError> {
+        if let Some(tree) = self.obj_map.get(table) {
+            return Ok(Some(tree));
+        }
+        return match self.lazy {
+            Some(ref lazy) => match lazy.objects.get(table) {
+                Some(tree) => tree.get(&lazy.source, "objects", table).map(Some),
+                None => Ok(None),
+            },
+            None => Ok(None),
+        };
+    }
+
+    pub(crate) fn bitmap_tree(&self, table: &String) -> Result<Option<&PersistentTree<Bitmap>>, DBError> {
+        if let Some(tree) = self.bit_map.get(table) {
+            return Ok(Some(tree));
+        }
+        return match self.lazy {
+            Some(ref lazy) => match lazy.bitmaps.get(table) {
+                Some(tree) => tree.get(&lazy.source, "bitmaps", table).map(Some),
+                None => Ok(None),
+            },
+            None => Ok(None),
+        };
+    }
+
+    /// Moves `table` from the lazily loaded tables to the regular maps before
+    /// it is modified. A table that fails to load stays where it is.
+    pub(crate) fn materialize(&mut self, table: &String) -> Result<(), DBError> {
+        let (objects, bitmaps) = match self.lazy {
+            Some(ref lazy) => {
+                let objects = match lazy.objects.get(table) {
+                    Some(tree) => Some(try!(tree.get(&lazy.source, "objects", table)).clone()),
+                    None => None,
+                };
+                let bitmaps = match lazy.bitmaps.get(table) {
+                    Some(tree) => Some(try!(tree.get(&lazy.source, "bitmaps", table)).clone()),
+                    None => None,
+                };
+                (objects, bitmaps)
+            }
+            None => return Ok(()),
+        };
+        if let Some(ref mut lazy) = self.lazy {
+            lazy.remove(table);
+        }
+        if let Some(tree) = objects {
+            self.obj_map.insert(table.clone(), tree);
+        }
+        if let Some(tree) = bitmaps {
+            self.bit_map.insert(table.clone(), tree);
+        }
+        return Ok(());
+    }
+
+    /// All object tables, loading those that were not loaded yet.
+    pub(crate) fn object_tables(&self) -> Result<BTreeMap<String, PersistentTree<Object>>, DBError> {
+        let mut res = self.obj_map.clone();
+        if let Some(ref lazy) = self.lazy {
+            for (name, tree) in &lazy.objects {
+                res.insert(name.clone(), try!(tree.get(&lazy.source, "objects", name)).clone());
+            }
+        }
+        return Ok(res);
+    }
+
+    pub(crate) fn bitmap_tables(&self) -> Result<BTreeMap<String, PersistentTree<Bitmap>>, DBError> {
+        let mut res = self.bit_map.clone();
+        if let Some(ref lazy) = self.lazy {
+            for (name, tree) in &lazy.bitmaps {
+                res.insert(name.clone(), try!(tree.get(&lazy.source, "bitmaps", name)).clone());
+            }
+        }
+        return Ok(res);
+    }
+}
+
+#[test]
+fn test_open_lazy() {
+    let path = ::std::env::temp_dir().join(format!("theban_db_lazy_{}", ::std::process::id()));
+    let filename = path.to_string_lossy().into_owned();
+    let (foo, bar, baz) = ("foo".to_string(), "bar".to_string(), "baz".to_string());
+    let mut db = DB::new();
+    db.insert_object(&foo, Range::new(3, 4), Object::new(vec![1])).unwrap();
+    db.insert_object(&bar, Range::new(3, 4), Object::new(vec![2])).unwrap();
+    db.insert_bitmap(&bar, Range::new(0, 1), Bitmap::new(1, vec![1, 2])).unwrap();
+    db.save_to_file(&filename).unwrap();
+
+    let mut db = DB::open_lazy(&filename).unwrap();
+    assert_eq!(db.tables(), vec![&bar, &foo]);
+    assert!(!db.is_table_loaded(&foo) && !db.is_table_loaded(&bar));
+    assert_eq!(db.query_object(&foo, Range::new(0, 10)).unwrap().count(), 1);
+    assert!(!db.is_table_loaded(&foo));
+    assert_eq!(db.query_bitmap(&foo, Range::new(0, 10)).unwrap().count(), 0);
+    assert!(db.is_table_loaded(&foo) && !db.is_table_loaded(&bar));
+    assert!(db.evict_table(&foo));
+    assert!(!db.is_table_loaded(&foo));
+
+    db.load_table(&bar).unwrap();
+    let snap = db.snapshot();
+    db.insert_bitmap(&bar, Range::new(2, 2), Bitmap::new(1, vec![3])).unwrap();
+    assert!(!db.evict_table(&bar));
+    db.rename_table(&foo, &baz).unwrap();
+    assert_eq!(snap.query_bitmap(&bar, Range::new(0, 10)).unwrap().count(), 1);
+
+    db.save_to_file(&filename).unwrap();
+    let db = DB::open_lazy(&filename).unwrap();
+    assert_eq!(db.tables(), vec![&bar, &baz]);
+    let bitmaps = db.query_bitmap(&bar, Range::new(0, 10)).unwrap()
+                    .map(|e| e.map(|(r, b)| (r, b.to_bitmap())).unwrap())
+                    .collect::<Vec<(Range, Bitmap)>>();
+    assert_eq!(bitmaps, vec![(Range::new(0, 2), Bitmap::new(1, vec![1, 2, 3]))]);
+    assert_eq!(db.query_object(&baz, Range::new(0, 10)).unwrap().count(), 1);
+
+    // a damaged table is reported by the accesses that load it
+    let mut db = DB::open_lazy(&filename).unwrap();
+    let offset = db.backend.lazy.as_ref().unwrap().objects[&baz].offset;
+    let mut file = ::std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.write_all(&[0xc1]).unwrap();
+    drop(file);
+    let mut iter = db.query_object(&baz, Range::new(0, 10)).unwrap();
+    assert!(iter.next().unwrap().is_err() && iter.next().is_none());
+    assert!(db.insert_object(&baz, Range::new(5, 6), Object::new(vec![3])).is_err());
+    assert!(db.load_table(&baz).is_err());
+    assert!(db.evict_table(&baz));
+    ::std::fs::remove_file(&path).unwrap();
+}
@@ -5,13 +5,43 @@ extern crate rustc_serialize;
 
 pub mod dberror;
 pub mod db;
+mod backend;
 mod serialize;
 mod content;
 mod db_iterator;
+mod atomic_file;
+mod journal;
+mod shared_db;
+mod snapshot;
+mod transaction;
+mod write_batch;
+mod bulk_load;
+mod lazy;
+mod mapped_db;
+mod paged;
+mod incremental;
+mod table_options;
+mod multimap;
+mod punch;
+mod coalesce;
+mod relocate;
+mod persistent_tree;
 
 pub use db::DB;
+pub use backend::{StorageBackend, MemoryBackend, MemoryIter};
+pub use shared_db::SharedDB;
+pub use snapshot::Snapshot;
+pub use write_batch::WriteBatch;
 pub use content::Bitmap;
 pub use content::BitmapSlice;
 pub use content::Object;
+pub use table_options::{TableOptions, OverlapPolicy};
 pub use dberror::DBError;
+pub use serialize::VerifyReport;
+pub use serialize::ReadLimits;
+pub use serialize::DBStream;
+pub use serialize::Record;
 pub use db_iterator::BitmapSliceIter;
+pub use multimap::ObjectValuesIter;
+pub use mapped_db::{MappedDB, MappedObjectIter, MappedBitmapIter};
+pub use paged::{PagedDB, PagedBackend, PagedObjectIter, PagedBitmapIter};
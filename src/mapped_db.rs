@@ -0,0 +1,461 @@
+extern crate rmp;
+extern crate memmap;
+extern crate memrange;
+
+use self::memmap::Mmap;
+use self::memrange::Range;
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::io::prelude::*;
+use std::fs::File;
+use std::path::Path;
+use std::u64;
+
+use db::DB;
+use persistent_tree::PersistentTree;
+use content::Object;
+use content::Bitmap;
+use content::BitmapSlice;
+use content::range_entries;
+use dberror::DBError;
+use atomic_file;
+use serialize::{DBReader, ReadLimits, write_string, parse_table_name, SECTION_NAMES};
+
+/// A mapped file starts with the magic and the version as little endian u64
+/// and ends with the offset of the directory followed by the magic again.
+const MAPPED_MAGIC: &'static [u8; 8] = b"THEBANMM";
+const MAPPED_VERSION: u64 = 1;
+const HEADER_SIZE: u64 = 16;
+const TRAILER_SIZE: u64 = 16;
+
+/// Every entry of a table is stored as six little endian u64: start, end,
+/// the largest end of this and all previous entries, data offset, data length
+/// and entry size (0 for objects). Entries are sorted like in the trees, the
+/// data of a table follows its entries padded to 8 bytes.
+const ENTRY_SIZE: u64 = 48;
+const FIELD_MIN: usize = 0;
+const FIELD_MAX: usize = 1;
+const FIELD_MAX_END: usize = 2;
+const FIELD_DATA_OFFSET: usize = 3;
+const FIELD_DATA_LEN: usize = 4;
+const FIELD_ENTRY_SIZE: usize = 5;
+
+fn read_u64_le(buf: &[u8], pos: usize) -> u64 {
+    return buf[pos..pos+8].iter().rev().fold(0u64, |acc, &b| acc << 8 | b as u64);
+}
+
+fn write_u64_le(w: &mut Write, val: u64) -> Result<(), DBError> {
+    let bytes = (0..8).map(|i| (val >> (8 * i)) as u8).collect::<Vec<u8>>();
+    try!(w.write_all(&bytes));
+    return Ok(());
+}
+
+fn padding(len: u64) -> u64 {
+    return (8 - len % 8) % 8;
+}
+
+/// Data that can be stored in a mapped table.
+trait MappedData {
+    fn entry_size(&self) -> u64;
+    fn bytes(&self) -> &Vec<u8>;
+}
+
+impl MappedData for Object {
+    fn entry_size(&self) -> u64 {
+        return 0;
+    }
+
+    fn bytes(&self) -> &Vec<u8> {
+        return &self.data;
+    }
+}
+
+impl MappedData for Bitmap {
+    fn entry_size(&self) -> u64 {
+        return self.entry_size;
+    }
+
+    fn bytes(&self) -> &Vec<u8> {
+        return &self.data;
+    }
+}
+
+/// Writes the entries and data of `tree` at `pos`, returns the number of
+/// entries and advances `pos` past the data.
+fn write_mapped_table<T: MappedData>(tree: &PersistentTree<T>, pos: &mut u64, w: &mut Write) -> Result<u64, DBError> {
+    let entries = tree.range(0, u64::MAX).collect::<Vec<(Range, &T)>>();
+    let mut data_offset = *pos + ENTRY_SIZE * entries.len() as u64;
+    let mut max_end = 0;
+    for &(rng, data) in &entries {
+        let len = data.bytes().len() as u64;
+        max_end = ::std::cmp::max(max_end, rng.max);
+        for &field in &[rng.min, rng.max, max_end, data_offset, len, data.entry_size()] {
+            try!(write_u64_le(w, field));
+        }
+        data_offset += len + padding(len);
+    }
+    for &(_, data) in &entries {
+        try!(w.write_all(data.bytes()));
+        try!(w.write_all(&[0u8; 8][0..padding(data.bytes().len() as u64) as usize]));
+    }
+    *pos = data_offset;
+    return Ok(entries.len() as u64);
+}
+
+#[derive(Clone, Copy)]
+struct MappedTable {
+    offset: usize,
+    count: usize,
+}
+
+struct MappedEntry<'a> {
+    range: Range,
+    entry_size: u64,
+    data: &'a [u8],
+}
+
+impl MappedTable {
+    fn field(&self, buf: &[u8], index: usize, field: usize) -> u64 {
+        return read_u64_le(buf, self.offset + index * ENTRY_SIZE as usize + field * 8);
+    }
+
+    fn entry<'a>(&self, buf: &'a [u8], index: usize) -> MappedEntry<'a> {
+        let offset = self.field(buf, index, FIELD_DATA_OFFSET) as usize;
+        let len = self.field(buf, index, FIELD_DATA_LEN) as usize;
+        return MappedEntry{range: Range::new(self.field(buf, index, FIELD_MIN), self.field(buf, index, FIELD_MAX)),
+                           entry_size: self.field(buf, index, FIELD_ENTRY_SIZE),
+                           data: &buf[offset..offset+len]};
+    }
+
+    /// Checks every entry so queries can trust the table. `data_end` is the
+    /// end of the data area, the start of the directory.
+    fn validate(&self, buf: &[u8], data_end: u64, bitmaps: bool, r: &DBReader) -> Result<(), DBError> {
+        let mut previous: Option<Range> = None;
+        let mut max_end = 0;
+        for i in 0..self.count {
+            let (min, max) = (self.field(buf, i, FIELD_MIN), self.field(buf, i, FIELD_MAX));
+            if min > max {
+                return Err(r.error(format!("entry {} has invalid range [{}, {}]", i, min, max)));
+            }
+            let rng = Range::new(min, max);
+            if let Some(previous) = previous {
+                if (previous.min, previous.max) >= (rng.min, rng.max) {
+                    return Err(r.error(format!("entry {} is not sorted", i)));
+                }
+            }
+            max_end = ::std::cmp::max(max_end, max);
+            if self.field(buf, i, FIELD_MAX_END) != max_end {
+                return Err(r.error(format!("entry {} has a wrong maximal end", i)));
+            }
+            let (offset, len) = (self.field(buf, i, FIELD_DATA_OFFSET), self.field(buf, i, FIELD_DATA_LEN));
+            if offset.checked_add(len).map(|end| end > data_end).unwrap_or(true) {
+                return Err(r.error(format!("data of entry {} is out of range", i)));
+            }
+            let entry_size = self.field(buf, i, FIELD_ENTRY_SIZE);
+            if bitmaps && range_entries(rng).and_then(|n| entry_size.checked_mul(n)) != Some(len) {
+                return Err(r.error(format!("bitmap of entry {} does not match its range", i)));
+            }
+            if !bitmaps && entry_size != 0 {
+                return Err(r.error(format!("object of entry {} has an entry size", i)));
+            }
+            previous = Some(rng);
+        }
+        return Ok(());
+    }
+
+    /// Entries that may intersect `r`: all entries before the first one
+    /// starting after `r`, skipping those whose maximal end lies before it.
+    fn candidates(&self, buf: &[u8], r: Range) -> (usize, usize) {
+        let end = partition(self.count, |i| self.field(buf, i, FIELD_MIN) <= r.max);
+        let start = partition(end, |i| self.field(buf, i, FIELD_MAX_END) < r.min);
+        return (start, end);
+    }
+}
+
+/// Index of the first element in `0..len` for which `pred` is false, `pred`
+/// has to be true for a prefix and false for the rest.
+fn partition<F: Fn(usize) -> bool>(len: usize, pred: F) -> usize {
+    let (mut low, mut high) = (0, len);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if pred(mid) {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    return low;
+}
+
+struct MappedEntries<'a> {
+    buf: &'a [u8],
+    table: MappedTable,
+    pos: usize,
+    end: usize,
+    query: Range,
+}
+
+impl<'a> MappedEntries<'a> {
+    fn new(buf: &'a [u8], table: MappedTable, query: Range) -> MappedEntries<'a> {
+        let (start, end) = table.candidates(buf, query);
+        return MappedEntries{buf: buf, table: table, pos: start, end: end, query: query};
+    }
+}
+
+impl<'a> Iterator for MappedEntries<'a> {
+    type Item = MappedEntry<'a>;
+
+    fn next(&mut self) -> Option<MappedEntry<'a>> {
+        while self.pos < self.end {
+            let entry = self.table.entry(self.buf, self.pos);
+            self.pos += 1;
+            if entry.range.max >= self.query.min {
+                return Some(entry);
+            }
+        }
+        return None;
+    }
+}
+
+/// Objects of a `MappedDB` query, the data is borrowed from the mapping.
+pub struct MappedObjectIter<'a> {
+    entries: MappedEntries<'a>,
+}
+
+impl<'a> Iterator for MappedObjectIter<'a> {
+    type Item = (Range, &'a [u8]);
+
+    fn next(&mut self) -> Option<(Range, &'a [u8])> {
+        return self.entries.next().map(|entry| (entry.range, entry.data));
+    }
+}
+
+/// Bitmaps of a `MappedDB` query, restricted to the queried range like
+/// `BitmapSliceIter`.
+pub struct MappedBitmapIter<'a> {
+    entries: MappedEntries<'a>,
+}
+
+impl<'a> Iterator for MappedBitmapIter<'a> {
+    type Item = Result<(Range, BitmapSlice<'a>), DBError>;
+
+    fn next(&mut self) -> Option<Result<(Range, BitmapSlice<'a>), DBError>> {
+        let query = self.entries.query;
+        return self.entries.next().map(|entry| {
+            let slice = try!(BitmapSlice::new_from_bytes(entry.entry_size, entry.data, entry.range, query));
+            return Ok((entry.range.get_intersection(&query), slice));
+        });
+    }
+}
+
+fn read_mapped_tables(r: &mut DBReader, buf: &[u8], section: usize, data_end: u64) -> Result<BTreeMap<String, MappedTable>, DBError> {
+    r.push_field(SECTION_NAMES[section]);
+    let len = try!(rmp::decode::read_map_size(r));
+    try!(r.count_tables(len as u64));
+    let mut res = BTreeMap::new();
+    for i in 0..len {
+        let name = try!(parse_table_name(r, i));
+        r.push_table(&name);
+        if try!(rmp::decode::read_array_size(r)) != 2 {
+            return Err(r.error("table entry should have length 2"));
+        }
+        let offset = try!(rmp::decode::read_u64(r));
+        let count = try!(rmp::decode::read_u64(r));
+        let end = count.checked_mul(ENTRY_SIZE).and_then(|len| len.checked_add(offset));
+        if offset < HEADER_SIZE || end.map(|end| end > data_end).unwrap_or(true) {
+            return Err(r.error(format!("table at {} with {} entries is out of range", offset, count)));
+        }
+        let table = MappedTable{offset: offset as usize, count: count as usize};
+        try!(table.validate(buf, data_end, section == 1, r));
+        r.pop_path();
+        res.insert(name, table);
+    }
+    r.pop_path();
+    return Ok(res);
+}
+
+/// Read-only DB that answers queries directly from a memory mapped file
+/// written with `DB::save_mapped`. Any number of processes can map the same
+/// file and share its pages, nothing is deserialized except the directory.
+///
+/// The file must not be modified while it is mapped. `save_mapped` replaces
+/// files atomically, so existing mappings keep seeing the old contents.
+pub struct MappedDB {
+    map: Mmap,
+    objects: BTreeMap<String, MappedTable>,
+    bitmaps: BTreeMap<String, MappedTable>,
+}
+
+impl MappedDB {
+    /// Maps `filename` and validates its directory and entries.
+    #[must_use]
+    pub fn open(filename: &String) -> Result<MappedDB, DBError> {
+        let file = try!(File::open(filename));
+        let map = try!(unsafe { Mmap::map(&file) });
+        let (objects, bitmaps) = try!(MappedDB::read_directory(&map));
+        return Ok(MappedDB{map: map, objects: objects, bitmaps: bitmaps});
+    }
+
+    fn read_directory(buf: &[u8]) -> Result<(BTreeMap<String, MappedTable>, BTreeMap<String, MappedTable>), DBError> {
+        let len = buf.len() as u64;
+        if len < HEADER_SIZE + TRAILER_SIZE || &buf[0..8] != MAPPED_MAGIC {
+            return Err(DBError::FileFormat("not a mapped theban db".into()));
+        }
+        let version = read_u64_le(buf, 8);
+        if version != MAPPED_VERSION {
+            return Err(DBError::FileFormat(format!("unsupported mapped version {}", version)));
+        }
+        let trailer = (len - TRAILER_SIZE) as usize;
+        if &buf[trailer+8..] != MAPPED_MAGIC {
+            return Err(DBError::FileFormat("mapped file is truncated".into()));
+        }
+        let dir_offset = read_u64_le(buf, trailer);
+        if dir_offset < HEADER_SIZE || dir_offset > trailer as u64 {
+            return Err(DBError::FileFormat(format!("directory offset {} is out of range", dir_offset)));
+        }
+        let mut input = &buf[dir_offset as usize..trailer];
+        let mut r = DBReader::new_at(&mut input, ReadLimits::default(), dir_offset);
+        r.push_field("directory");
+        let res = rmp::decode::read_array_size(&mut r).map_err(DBError::from).and_then(|len| {
+            if len != 2 {
+                return Err(r.error("directory should have length 2"));
+            }
+            let objects = try!(read_mapped_tables(&mut r, buf, 0, dir_offset));
+            let bitmaps = try!(read_mapped_tables(&mut r, buf, 1, dir_offset));
+            return Ok((objects, bitmaps));
+        });
+        return res.map_err(|e| r.context(e));
+    }
+
+    pub fn query_object<'a>(&'a self, table: &String, r: Range) -> Option<MappedObjectIter<'a>> {
+        return self.objects.get(table).map(|&t| MappedObjectIter{entries: MappedEntries::new(&self.map, t, r)});
+    }
+
+    pub fn query_bitmap<'a>(&'a self, table: &String, r: Range) -> Option<MappedBitmapIter<'a>> {
+        return self.bitmaps.get(table).map(|&t| MappedBitmapIter{entries: MappedEntries::new(&self.map, t, r)});
+    }
+
+    pub fn has_table(&self, table: &String) -> bool {
+        return self.objects.contains_key(table) || self.bitmaps.contains_key(table);
+    }
+
+    /// Names of all tables in ascending order.
+    pub fn tables(&self) -> Vec<&String> {
+        let names = self.objects.keys().chain(self.bitmaps.keys()).collect::<BTreeSet<&String>>();
+        return names.into_iter().collect();
+    }
+}
+
+impl DB {
+    /// Writes the DB in the layout read by `MappedDB`.
+    #[must_use]
+    pub fn write_mapped(&self, mut w: &mut Write) -> Result<(), DBError> {
+        let objects = try!(self.backend.object_tables());
+        let bitmaps = try!(self.backend.bitmap_tables());
+        try!(w.write_all(MAPPED_MAGIC));
+        try!(write_u64_le(w, MAPPED_VERSION));
+        let mut pos = HEADER_SIZE;
+        let mut object_dir = vec![];
+        for (name, tree) in &objects {
+            let offset = pos;
+            object_dir.push((name, offset, try!(write_mapped_table(tree, &mut pos, w))));
+        }
+        let mut bitmap_dir = vec![];
+        for (name, tree) in &bitmaps {
+            let offset = pos;
+            bitmap_dir.push((name, offset, try!(write_mapped_table(tree, &mut pos, w))));
+        }
+        let dir_offset = pos;
+        try!(rmp::encode::write_array_len(&mut w, 2));
+        for dir in &[object_dir, bitmap_dir] {
+            try!(rmp::encode::write_map_len(&mut w, dir.len() as u32));
+            for &(name, offset, count) in dir {
+                try!(write_string(name, w));
+                try!(rmp::encode::write_array_len(&mut w, 2));
+                try!(rmp::encode::write_u64(&mut w, offset));
+                try!(rmp::encode::write_u64(&mut w, count));
+            }
+        }
+        try!(write_u64_le(w, dir_offset));
+        try!(w.write_all(MAPPED_MAGIC));
+        return Ok(());
+    }
+
+    /// Atomically replaces `filename` with a file that can be opened with
+    /// `MappedDB::open`.
+    #[must_use]
+    pub fn save_mapped(&self, filename: &String) -> Result<(), DBError> {
+        return atomic_file::write_atomically(Path::new(filename), |w| self.write_mapped(w));
+    }
+}
+
+#[cfg(test)]
+fn mapped_sample_db() -> DB {
+    let (foo, bar) = ("foo".to_string(), "bar".to_string());
+    let mut db = DB::new();
+    db.insert_object(&foo, Range::new(0, 100), Object::new(vec![1, 2, 3])).unwrap();
+    db.insert_object(&foo, Range::new(5, 6), Object::new(vec![4])).unwrap();
+    db.insert_object(&foo, Range::new(50, 60), Object::new(vec![])).unwrap();
+    db.insert_object(&foo, Range::new(200, u64::MAX), Object::new(vec![5; 9])).unwrap();
+    db.insert_bitmap(&bar, Range::new(10, 13), Bitmap::new(2, vec![1, 2, 3, 4, 5, 6, 7, 8])).unwrap();
+    db.insert_bitmap(&bar, Range::new(20, 20), Bitmap::new(1, vec![9])).unwrap();
+    return db;
+}
+
+#[test]
+fn test_mapped_queries() {
+    let path = ::std::env::temp_dir().join(format!("theban_db_mapped_{}", ::std::process::id()));
+    let filename = path.to_string_lossy().into_owned();
+    let db = mapped_sample_db();
+    db.save_mapped(&filename).unwrap();
+    let mapped = MappedDB::open(&filename).unwrap();
+    let (foo, bar) = ("foo".to_string(), "bar".to_string());
+    assert_eq!(mapped.tables(), db.tables());
+    assert!(mapped.query_object(&"baz".to_string(), Range::new(0, 10)).is_none());
+
+    for &(min, max) in &[(0, u64::MAX), (7, 49), (6, 6), (61, 199), (101, 150), (300, 400), (13, 20)] {
+        let rng = Range::new(min, max);
+        let expected = db.query_object(&foo, rng).unwrap().map(|e| e.map(|(r, o)| (r, o.into_owned().data)).unwrap()).collect::<Vec<(Range, Vec<u8>)>>();
+        assert_eq!(mapped.query_object(&foo, rng).unwrap().map(|(r, d)| (r, d.to_vec())).collect::<Vec<(Range, Vec<u8>)>>(), expected);
+        let expected = db.query_bitmap(&bar, rng).unwrap().map(|e| e.map(|(r, b)| (r, b.to_bitmap())).unwrap()).collect::<Vec<(Range, Bitmap)>>();
+        assert_eq!(mapped.query_bitmap(&bar, rng).unwrap().map(|e| e.map(|(r, b)| (r, b.to_bitmap())).unwrap()).collect::<Vec<(Range, Bitmap)>>(), expected);
+    }
+    let bitmaps = mapped.query_bitmap(&bar, Range::new(11, 12)).unwrap().map(|e| e.map(|(r, b)| (r, b.to_bitmap())).unwrap()).collect::<Vec<(Range, Bitmap)>>();
+    assert_eq!(bitmaps, vec![(Range::new(11, 12), Bitmap::new(2, vec![3, 4, 5, 6]))]);
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_mapped_malformed() {
+    let mut bin = vec![];
+    mapped_sample_db().write_mapped(&mut bin).unwrap();
+    assert!(MappedDB::read_directory(&bin).is_ok());
+    for len in 0..bin.len() {
+        assert!(MappedDB::read_directory(&bin[0..len]).is_err());
+    }
+    // the first object of foo claims to end before it starts
+    let mut corrupt = bin.clone();
+    corrupt[HEADER_SIZE as usize + 8] = 0;
+    corrupt[HEADER_SIZE as usize] = 1;
+    assert!(MappedDB::read_directory(&corrupt).is_err());
+
+    // a bitmap entry that claims to cover the whole address space
+    let mut db = DB::new();
+    db.insert_bitmap(&"bar".to_string(), Range::new(7, 7), Bitmap::new(1, vec![9])).unwrap();
+    let mut bin = vec![];
+    db.write_mapped(&mut bin).unwrap();
+    let mut pattern = vec![];
+    for _ in 0..3 {
+        pattern.extend_from_slice(&[7, 0, 0, 0, 0, 0, 0, 0]);
+    }
+    let entry = bin.windows(pattern.len()).position(|w| w == &pattern[..]).unwrap();
+    for i in 0..8 {
+        bin[entry + i] = 0;
+        bin[entry + 8 + i] = 0xff;
+        bin[entry + 16 + i] = 0xff;
+    }
+    assert!(MappedDB::read_directory(&bin).is_err());
+    let full = Range::new(0, u64::MAX);
+    assert!(BitmapSlice::new_from_bytes(1, &[9], full, full).is_err());
+}
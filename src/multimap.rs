@@ -0,0 +1,166 @@
+extern crate rmp;
+
+use memrange::Range;
+
+use std::borrow::Cow;
+use std::io::Cursor;
+
+use db::DB;
+use backend::StorageBackend;
+use content::Object;
+use dberror::DBError;
+use journal::Entry;
+use serialize::{Serialized, DBReader, ReadLimits};
+
+#[cfg(test)] use table_options::TableOptions;
+
+/// A multimap table stores the values of a range as a single object holding
+/// a msgpack array of them, in the order they were inserted. Adding or
+/// removing a value rewrites the whole array.
+fn pack_values(values: &Vec<Object>) -> Object {
+    let mut data = vec![];
+    rmp::encode::write_array_len(&mut data, values.len() as u32).expect("writing to a Vec cannot fail");
+    for value in values {
+        value.write(&mut data).expect("writing to a Vec cannot fail");
+    }
+    return Object::new(data);
+}
+
+fn unpack_values(obj: &Object) -> Result<Vec<Object>, DBError> {
+    let mut input = Cursor::new(&obj.data[..]);
+    let mut r = DBReader::new(&mut input, ReadLimits::default());
+    let len = try!(rmp::decode::read_array_size(&mut r));
+    let mut values = vec![];
+    for _ in 0..len {
+        values.push(try!(Object::read(&mut r)));
+    }
+    if r.position() != obj.data.len() as u64 {
+        return Err(r.error("trailing bytes after value list"));
+    }
+    return Ok(values);
+}
+
+/// Iterator returned by `DB::query_object_values`.
+pub struct ObjectValuesIter<'a, I> where I: Iterator<Item=Result<(Range, Cow<'a, Object>), DBError>> {
+    orig: I,
+    multimap: bool,
+}
+
+impl<'a, I> Iterator for ObjectValuesIter<'a, I> where I: Iterator<Item=Result<(Range, Cow<'a, Object>), DBError>> {
+    type Item = Result<(Range, Vec<Object>), DBError>;
+
+    fn next(&mut self) -> Option<Result<(Range, Vec<Object>), DBError>> {
+        let multimap = self.multimap;
+        return self.orig.next().map(|entry| entry.and_then(|(rng, obj)| {
+            if multimap {
+                return unpack_values(&obj).map(|values| (rng, values));
+            }
+            return Ok((rng, vec![obj.into_owned()]));
+        }));
+    }
+}
+
+impl<B: StorageBackend> DB<B> {
+    pub(crate) fn is_multimap(&self, table: &String) -> bool {
+        return self.options.get(table).map(|options| options.multimap).unwrap_or(false);
+    }
+
+    /// Values stored at exactly the range `r`.
+    fn values_at(&self, table: &String, r: Range) -> Result<Vec<Object>, DBError> {
+        return match try!(self.backend.get_object(table, r)) {
+            Some(obj) if self.is_multimap(table) => unpack_values(&obj),
+            Some(obj) => Ok(vec![obj]),
+            None => Ok(vec![]),
+        };
+    }
+
+    /// The packed values of `r` in a multimap table once `d` is added. A
+    /// value that is already stored at `r` is not added a second time.
+    pub(crate) fn with_object_value(&self, table: &String, r: Range, d: Object) -> Result<Object, DBError> {
+        let mut values = try!(self.values_at(table, r));
+        if !values.contains(&d) {
+            values.push(d);
+        }
+        return Ok(pack_values(&values));
+    }
+
+    /// Objects intersecting `r` with all values stored at their range. In
+    /// tables that are no multimap tables every range has a single value.
+    pub fn query_object_values<'a>(&'a self, table: &String, r: Range) -> Option<ObjectValuesIter<'a, B::ObjectIter<'a>>> {
+        let multimap = self.is_multimap(table);
+        return self.query_object(table, r).map(|iter| ObjectValuesIter{orig: iter, multimap: multimap});
+    }
+
+    /// Removes the value `d` from the range `r`. `delete_object` removes all
+    /// values of a range.
+    #[must_use]
+    pub fn delete_object_value(&mut self, table: &String, r: Range, d: &Object) -> Result<(), DBError> {
+        let values = try!(self.values_at(table, r));
+        if !values.contains(d) {
+            return Ok(());
+        }
+        let remaining = values.into_iter().filter(|value| value != d).collect::<Vec<Object>>();
+        if remaining.is_empty() {
+            try!(self.backend_delete_object(table, r));
+        } else {
+            try!(self.backend_insert_object(table, r, pack_values(&remaining)));
+        }
+        self.log(Entry::DeleteObjectValue(table, r, d));
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+fn all_values(db: &DB, tbl: &String) -> Vec<(Range, Vec<Object>)> {
+    return db.query_object_values(tbl, Range::new(0, 100)).unwrap().map(|e| e.unwrap()).collect();
+}
+
+#[test]
+fn test_multimap() {
+    let (foo, bar) = ("foo".to_string(), "bar".to_string());
+    let (a, b, c) = (Object::new(vec![1]), Object::new(vec![2]), Object::new(vec![3]));
+    let mut db = DB::new();
+    db.set_table_options(&foo, TableOptions{multimap: true, ..TableOptions::default()}).unwrap();
+    db.insert_object(&foo, Range::new(0, 10), a.clone()).unwrap();
+    db.insert_object(&foo, Range::new(0, 10), b.clone()).unwrap();
+    db.insert_object(&foo, Range::new(0, 10), a.clone()).unwrap();
+    db.insert_object(&foo, Range::new(5, 6), c.clone()).unwrap();
+    assert_eq!(all_values(&db, &foo), vec![(Range::new(0, 10), vec![a.clone(), b.clone()]),
+                                           (Range::new(5, 6), vec![c.clone()])]);
+
+    db.delete_object_value(&foo, Range::new(0, 10), &a).unwrap();
+    db.dirty.clear();
+    db.delete_object_value(&foo, Range::new(5, 6), &a).unwrap();
+    db.delete_object_value(&foo, Range::new(5, 7), &c).unwrap();
+    assert!(db.dirty_tables().is_empty());
+    assert_eq!(all_values(&db, &foo), vec![(Range::new(0, 10), vec![b.clone()]),
+                                           (Range::new(5, 6), vec![c.clone()])]);
+    db.delete_object_value(&foo, Range::new(0, 10), &b).unwrap();
+    assert_eq!(all_values(&db, &foo), vec![(Range::new(5, 6), vec![c.clone()])]);
+
+    db.insert_object(&foo, Range::new(5, 6), a.clone()).unwrap();
+    let loaded = DB::deserialize(db.serialize().unwrap()).unwrap();
+    assert_eq!(all_values(&loaded, &foo), vec![(Range::new(5, 6), vec![c.clone(), a.clone()])]);
+    db.delete_object(&foo, Range::new(5, 6)).unwrap();
+    assert!(all_values(&db, &foo).is_empty());
+
+    // plain tables keep a single value per range
+    db.insert_object(&bar, Range::new(0, 10), a.clone()).unwrap();
+    db.insert_object(&bar, Range::new(0, 10), b.clone()).unwrap();
+    db.delete_object_value(&bar, Range::new(0, 10), &a).unwrap();
+    assert_eq!(all_values(&db, &bar), vec![(Range::new(0, 10), vec![b.clone()])]);
+    db.delete_object_value(&bar, Range::new(0, 10), &b).unwrap();
+    assert!(all_values(&db, &bar).is_empty());
+}
+
+#[test]
+fn test_multimap_malformed_values() {
+    let foo = "foo".to_string();
+    let mut db = DB::new();
+    db.set_table_options(&foo, TableOptions{multimap: true, ..TableOptions::default()}).unwrap();
+    db.backend_mut().insert_object(&foo, Range::new(0, 10), Object::new(vec![1, 2, 3])).unwrap();
+    let mut iter = db.query_object_values(&foo, Range::new(0, 100)).unwrap();
+    assert!(iter.next().unwrap().is_err());
+    assert!(iter.next().is_none());
+    assert!(db.insert_object(&foo, Range::new(0, 10), Object::new(vec![4])).is_err());
+}
@@ -0,0 +1,602 @@
+extern crate memrange;
+
+use self::memrange::Range;
+
+use std::cmp;
+use std::collections::HashSet;
+
+use dberror::DBError;
+use super::pager::{Pager, PageId, PAGE_SIZE, get_u64, put_u64};
+
+/// Every node starts with its kind and the number of entries or children.
+const KIND_LEAF: u8 = 1;
+const KIND_INTERNAL: u8 = 2;
+const NODE_HEADER: usize = 16;
+
+/// A leaf entry is start, end, entry size, data length and the first
+/// overflow page (0 if the data is stored inline), followed by inline data.
+const LEAF_ENTRY_HEADER: usize = 40;
+/// A child is the smallest key below it, the largest end below it and its page.
+const CHILD_SIZE: usize = 32;
+
+/// Larger data is moved to a chain of overflow pages, so a leaf always holds
+/// enough entries to be split in two.
+const INLINE_LIMIT: usize = 512;
+/// Overflow pages start with the next page of the chain.
+const OVERFLOW_DATA: usize = PAGE_SIZE - 8;
+/// A tree only grows in height by splitting a full root, so no tree that
+/// fits in the u64 page space is deeper than this. Deeper paths only come
+/// from child pointers that form a cycle.
+const MAX_DEPTH: usize = 64;
+
+type Key = (u64, u64);
+
+fn key(r: Range) -> Key {
+    return (r.min, r.max);
+}
+
+fn corrupt(page: PageId) -> DBError {
+    return DBError::FileFormat(format!("page {} is corrupt", page));
+}
+
+/// Stores `data` in a chain of overflow pages and returns the first one.
+pub fn write_overflow(pager: &mut Pager, data: &[u8]) -> Result<PageId, DBError> {
+    let mut next = 0;
+    // written back to front so every page knows its successor
+    for chunk in data.chunks(OVERFLOW_DATA).rev() {
+        let id = try!(pager.allocate());
+        let page = try!(pager.write(id));
+        put_u64(page, 0, next);
+        page[8..8+chunk.len()].copy_from_slice(chunk);
+        next = id;
+    }
+    return Ok(next);
+}
+
+pub fn read_overflow(pager: &mut Pager, first: PageId, len: u64) -> Result<Vec<u8>, DBError> {
+    let mut data = Vec::with_capacity(cmp::min(len, 1 << 20) as usize);
+    let mut id = first;
+    let mut visited = HashSet::new();
+    while (data.len() as u64) < len {
+        if id == 0 {
+            return Err(DBError::FileFormat(format!("overflow chain at page {} is too short", first)));
+        }
+        if !visited.insert(id) {
+            return Err(corrupt(id));
+        }
+        let page = try!(pager.read(id));
+        let n = cmp::min(len - data.len() as u64, OVERFLOW_DATA as u64) as usize;
+        data.extend_from_slice(&page[8..8+n]);
+        id = get_u64(page, 0);
+    }
+    return Ok(data);
+}
+
+pub fn free_overflow(pager: &mut Pager, first: PageId, len: u64) -> Result<(), DBError> {
+    let mut id = first;
+    let mut visited = HashSet::new();
+    for _ in 0..(len + OVERFLOW_DATA as u64 - 1) / OVERFLOW_DATA as u64 {
+        if id == 0 || !visited.insert(id) {
+            return Err(corrupt(id));
+        }
+        let next = get_u64(try!(pager.read(id)), 0);
+        try!(pager.free(id));
+        id = next;
+    }
+    return Ok(());
+}
+
+#[derive(Clone)]
+enum Payload {
+    Inline(Vec<u8>),
+    Overflow(u64, PageId),
+}
+
+#[derive(Clone)]
+pub struct LeafEntry {
+    pub range: Range,
+    pub entry_size: u64,
+    payload: Payload,
+}
+
+impl LeafEntry {
+    pub fn read_data(&self, pager: &mut Pager) -> Result<Vec<u8>, DBError> {
+        return match self.payload {
+            Payload::Inline(ref data) => Ok(data.clone()),
+            Payload::Overflow(len, first) => read_overflow(pager, first, len),
+        };
+    }
+
+    fn free(&self, pager: &mut Pager) -> Result<(), DBError> {
+        return match self.payload {
+            Payload::Inline(_) => Ok(()),
+            Payload::Overflow(len, first) => free_overflow(pager, first, len),
+        };
+    }
+
+    fn encoded_len(&self) -> usize {
+        return match self.payload {
+            Payload::Inline(ref data) => LEAF_ENTRY_HEADER + data.len(),
+            Payload::Overflow(_, _) => LEAF_ENTRY_HEADER,
+        };
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Child {
+    key: Range,
+    max_end: u64,
+    page: PageId,
+}
+
+enum Node {
+    Leaf(Vec<LeafEntry>),
+    Internal(Vec<Child>),
+}
+
+impl Node {
+    fn read(pager: &mut Pager, id: PageId) -> Result<Node, DBError> {
+        let page = try!(pager.read(id));
+        let count = get_u64(page, 8);
+        if page[0] == KIND_INTERNAL {
+            if count == 0 || count > ((PAGE_SIZE - NODE_HEADER) / CHILD_SIZE) as u64 {
+                return Err(corrupt(id));
+            }
+            let children = (0..count as usize).map(|i| {
+                let pos = NODE_HEADER + i * CHILD_SIZE;
+                Child{key: Range::new(get_u64(page, pos), get_u64(page, pos + 8)),
+                      max_end: get_u64(page, pos + 16), page: get_u64(page, pos + 24)}
+            }).collect::<Vec<Child>>();
+            if children.iter().any(|c| c.key.min > c.key.max || c.page == 0 || c.page == id) {
+                return Err(corrupt(id));
+            }
+            return Ok(Node::Internal(children));
+        }
+        if page[0] != KIND_LEAF || count == 0 {
+            return Err(corrupt(id));
+        }
+        let mut entries = vec![];
+        let mut pos = NODE_HEADER;
+        for _ in 0..count {
+            if pos + LEAF_ENTRY_HEADER > PAGE_SIZE {
+                return Err(corrupt(id));
+            }
+            let (min, max, entry_size) = (get_u64(page, pos), get_u64(page, pos + 8), get_u64(page, pos + 16));
+            let (len, first) = (get_u64(page, pos + 24), get_u64(page, pos + 32));
+            if min > max {
+                return Err(corrupt(id));
+            }
+            pos += LEAF_ENTRY_HEADER;
+            let payload = if first != 0 {
+                Payload::Overflow(len, first)
+            } else {
+                if len > INLINE_LIMIT as u64 || pos + len as usize > PAGE_SIZE {
+                    return Err(corrupt(id));
+                }
+                pos += len as usize;
+                Payload::Inline(page[pos - len as usize..pos].to_vec())
+            };
+            entries.push(LeafEntry{range: Range::new(min, max), entry_size: entry_size, payload: payload});
+        }
+        return Ok(Node::Leaf(entries));
+    }
+
+    fn write(&self, pager: &mut Pager, id: PageId) -> Result<(), DBError> {
+        let page = try!(pager.write(id));
+        for b in page.iter_mut() {
+            *b = 0;
+        }
+        match *self {
+            Node::Leaf(ref entries) => {
+                page[0] = KIND_LEAF;
+                put_u64(page, 8, entries.len() as u64);
+                let mut pos = NODE_HEADER;
+                for entry in entries {
+                    put_u64(page, pos, entry.range.min);
+                    put_u64(page, pos + 8, entry.range.max);
+                    put_u64(page, pos + 16, entry.entry_size);
+                    match entry.payload {
+                        Payload::Inline(ref data) => {
+                            put_u64(page, pos + 24, data.len() as u64);
+                            page[pos+LEAF_ENTRY_HEADER..pos+LEAF_ENTRY_HEADER+data.len()].copy_from_slice(data);
+                        }
+                        Payload::Overflow(len, first) => {
+                            put_u64(page, pos + 24, len);
+                            put_u64(page, pos + 32, first);
+                        }
+                    }
+                    pos += entry.encoded_len();
+                }
+            }
+            Node::Internal(ref children) => {
+                page[0] = KIND_INTERNAL;
+                put_u64(page, 8, children.len() as u64);
+                for (i, child) in children.iter().enumerate() {
+                    let pos = NODE_HEADER + i * CHILD_SIZE;
+                    put_u64(page, pos, child.key.min);
+                    put_u64(page, pos + 8, child.key.max);
+                    put_u64(page, pos + 16, child.max_end);
+                    put_u64(page, pos + 24, child.page);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    fn encoded_len(&self) -> usize {
+        return match *self {
+            Node::Leaf(ref entries) => NODE_HEADER + entries.iter().map(|e| e.encoded_len()).sum::<usize>(),
+            Node::Internal(ref children) => NODE_HEADER + children.len() * CHILD_SIZE,
+        };
+    }
+
+    fn is_empty(&self) -> bool {
+        return match *self {
+            Node::Leaf(ref entries) => entries.is_empty(),
+            Node::Internal(ref children) => children.is_empty(),
+        };
+    }
+
+    /// The child entry pointing to this node, which is stored at `id`.
+    fn summary(&self, id: PageId) -> Child {
+        return match *self {
+            Node::Leaf(ref entries) => Child{key: entries[0].range, page: id,
+                                             max_end: entries.iter().map(|e| e.range.max).max().unwrap()},
+            Node::Internal(ref children) => Child{key: children[0].key, page: id,
+                                                  max_end: children.iter().map(|c| c.max_end).max().unwrap()},
+        };
+    }
+
+    /// Moves the upper half of the node, by size, to a new node.
+    fn split_off(&mut self) -> Node {
+        return match *self {
+            Node::Leaf(ref mut entries) => {
+                let half = entries.iter().map(|e| e.encoded_len()).sum::<usize>() / 2;
+                let (mut acc, mut k) = (0, 0);
+                while k + 1 < entries.len() && acc + entries[k].encoded_len() <= half {
+                    acc += entries[k].encoded_len();
+                    k += 1;
+                }
+                Node::Leaf(entries.split_off(cmp::max(k, 1)))
+            }
+            Node::Internal(ref mut children) => {
+                let k = children.len() / 2;
+                Node::Internal(children.split_off(k))
+            }
+        };
+    }
+}
+
+/// Index of the child whose subtree holds `k`.
+fn child_index(children: &Vec<Child>, k: Key) -> Option<usize> {
+    return match children.binary_search_by_key(&k, |c| key(c.key)) {
+        Ok(i) => Some(i),
+        Err(0) => None,
+        Err(i) => Some(i - 1),
+    };
+}
+
+/// Writes `node` to `id`, splitting it if it does not fit. Returns the child
+/// entries for `id` and the new sibling.
+fn write_split(pager: &mut Pager, id: PageId, mut node: Node) -> Result<(Child, Option<Child>), DBError> {
+    if node.encoded_len() <= PAGE_SIZE {
+        try!(node.write(pager, id));
+        return Ok((node.summary(id), None));
+    }
+    let right = node.split_off();
+    let right_id = try!(pager.allocate());
+    try!(right.write(pager, right_id));
+    try!(node.write(pager, id));
+    return Ok((node.summary(id), Some(right.summary(right_id))));
+}
+
+fn insert_into(pager: &mut Pager, id: PageId, entry: LeafEntry, depth: usize) -> Result<(Child, Option<Child>), DBError> {
+    if depth > MAX_DEPTH {
+        return Err(corrupt(id));
+    }
+    let mut node = try!(Node::read(pager, id));
+    match node {
+        Node::Leaf(ref mut entries) => {
+            match entries.binary_search_by_key(&key(entry.range), |e| key(e.range)) {
+                Ok(i) => {
+                    try!(entries[i].free(pager));
+                    entries[i] = entry;
+                }
+                Err(i) => entries.insert(i, entry),
+            }
+        }
+        Node::Internal(ref mut children) => {
+            let i = child_index(children, key(entry.range)).unwrap_or(0);
+            let (child, sibling) = try!(insert_into(pager, children[i].page, entry, depth + 1));
+            children[i] = child;
+            if let Some(sibling) = sibling {
+                children.insert(i + 1, sibling);
+            }
+        }
+    }
+    return write_split(pager, id, node);
+}
+
+/// Inserts an entry into the tree at `root` (0 for an empty tree), replacing
+/// an entry with the same range. Returns the new root.
+pub fn insert(pager: &mut Pager, root: PageId, range: Range, entry_size: u64, data: Vec<u8>) -> Result<PageId, DBError> {
+    let payload = if data.len() > INLINE_LIMIT {
+        Payload::Overflow(data.len() as u64, try!(write_overflow(pager, &data)))
+    } else {
+        Payload::Inline(data)
+    };
+    let entry = LeafEntry{range: range, entry_size: entry_size, payload: payload};
+    if root == 0 {
+        let id = try!(pager.allocate());
+        try!(Node::Leaf(vec![entry]).write(pager, id));
+        return Ok(id);
+    }
+    let (child, sibling) = try!(insert_into(pager, root, entry, 0));
+    return match sibling {
+        Some(sibling) => {
+            let id = try!(pager.allocate());
+            try!(Node::Internal(vec![child, sibling]).write(pager, id));
+            Ok(id)
+        }
+        None => Ok(root),
+    };
+}
+
+enum Removed {
+    NotFound,
+    Updated(Child),
+    Emptied,
+}
+
+fn delete_from(pager: &mut Pager, id: PageId, k: Key, depth: usize) -> Result<Removed, DBError> {
+    if depth > MAX_DEPTH {
+        return Err(corrupt(id));
+    }
+    let mut node = try!(Node::read(pager, id));
+    match node {
+        Node::Leaf(ref mut entries) => {
+            match entries.binary_search_by_key(&k, |e| key(e.range)) {
+                Ok(i) => try!(entries.remove(i).free(pager)),
+                Err(_) => return Ok(Removed::NotFound),
+            }
+        }
+        Node::Internal(ref mut children) => {
+            let i = match child_index(children, k) {
+                Some(i) => i,
+                None => return Ok(Removed::NotFound),
+            };
+            match try!(delete_from(pager, children[i].page, k, depth + 1)) {
+                Removed::NotFound => return Ok(Removed::NotFound),
+                Removed::Updated(child) => children[i] = child,
+                Removed::Emptied => {
+                    children.remove(i);
+                }
+            }
+        }
+    }
+    if node.is_empty() {
+        try!(pager.free(id));
+        return Ok(Removed::Emptied);
+    }
+    try!(node.write(pager, id));
+    return Ok(Removed::Updated(node.summary(id)));
+}
+
+/// Removes the entry with exactly `range`. Nodes are not merged when they
+/// become sparse, only empty nodes are freed. Returns the new root and
+/// whether an entry was removed.
+pub fn delete(pager: &mut Pager, root: PageId, range: Range) -> Result<(PageId, bool), DBError> {
+    if root == 0 {
+        return Ok((0, false));
+    }
+    return match try!(delete_from(pager, root, key(range), 0)) {
+        Removed::NotFound => Ok((root, false)),
+        Removed::Emptied => Ok((0, true)),
+        Removed::Updated(_) => {
+            let (mut root, mut depth) = (root, 0);
+            loop {
+                let child = match try!(Node::read(pager, root)) {
+                    Node::Internal(ref children) if children.len() == 1 => children[0].page,
+                    _ => break,
+                };
+                if depth == MAX_DEPTH {
+                    return Err(corrupt(root));
+                }
+                depth += 1;
+                try!(pager.free(root));
+                root = child;
+            }
+            Ok((root, true))
+        }
+    };
+}
+
+/// Frees all pages of the tree at `root`.
+pub fn free_tree(pager: &mut Pager, root: PageId) -> Result<(), DBError> {
+    if root == 0 {
+        return Ok(());
+    }
+    return free_subtree(pager, root, 0);
+}
+
+fn free_subtree(pager: &mut Pager, root: PageId, depth: usize) -> Result<(), DBError> {
+    if depth > MAX_DEPTH {
+        return Err(corrupt(root));
+    }
+    match try!(Node::read(pager, root)) {
+        Node::Leaf(entries) => {
+            for entry in entries {
+                try!(entry.free(pager));
+            }
+        }
+        Node::Internal(children) => {
+            for child in children {
+                try!(free_subtree(pager, child.page, depth + 1));
+            }
+        }
+    }
+    return pager.free(root);
+}
+
+/// Walks the entries intersecting a range in ascending order. Only the nodes
+/// on the current path are kept in memory.
+pub struct Cursor {
+    root: PageId,
+    started: bool,
+    stack: Vec<(Node, usize)>,
+    query: Range,
+}
+
+impl Cursor {
+    pub fn new(root: PageId, query: Range) -> Cursor {
+        return Cursor{root: root, started: false, stack: vec![], query: query};
+    }
+
+    pub fn next(&mut self, pager: &mut Pager) -> Result<Option<LeafEntry>, DBError> {
+        let res = self.advance(pager);
+        if res.is_err() {
+            self.stack.clear();
+        }
+        return res;
+    }
+
+    fn advance(&mut self, pager: &mut Pager) -> Result<Option<LeafEntry>, DBError> {
+        if !self.started {
+            self.started = true;
+            if self.root != 0 {
+                self.stack.push((try!(Node::read(pager, self.root)), 0));
+            }
+        }
+        let query = self.query;
+        loop {
+            let descend = match self.stack.last_mut() {
+                None => return Ok(None),
+                Some(&mut (Node::Leaf(ref entries), ref mut pos)) => {
+                    let mut found = None;
+                    while *pos < entries.len() && found.is_none() {
+                        let entry = &entries[*pos];
+                        *pos += 1;
+                        if entry.range.min > query.max {
+                            *pos = entries.len();
+                        } else if entry.range.max >= query.min {
+                            found = Some(entry.clone());
+                        }
+                    }
+                    if found.is_some() {
+                        return Ok(found);
+                    }
+                    None
+                }
+                Some(&mut (Node::Internal(ref children), ref mut pos)) => {
+                    let mut descend = None;
+                    while *pos < children.len() && descend.is_none() {
+                        let child = children[*pos];
+                        *pos += 1;
+                        if child.key.min > query.max {
+                            *pos = children.len();
+                        } else if child.max_end >= query.min {
+                            descend = Some(child.page);
+                        }
+                    }
+                    descend
+                }
+            };
+            match descend {
+                Some(id) => {
+                    if self.stack.len() > MAX_DEPTH {
+                        return Err(corrupt(id));
+                    }
+                    let node = try!(Node::read(pager, id));
+                    self.stack.push((node, 0));
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn collect(pager: &mut Pager, root: PageId, query: Range) -> Vec<(Range, Vec<u8>)> {
+    let mut cursor = Cursor::new(root, query);
+    let mut res = vec![];
+    while let Some(entry) = cursor.next(pager).unwrap() {
+        res.push((entry.range, entry.read_data(pager).unwrap()));
+    }
+    return res;
+}
+
+#[test]
+fn test_btree_matches_reference() {
+    use std::collections::BTreeMap;
+    use super::pager::temp_page_file;
+
+    let path = temp_page_file("btree");
+    let mut pager = Pager::open(&path, 16).unwrap();
+    let mut reference = BTreeMap::new();
+    let mut root = 0;
+    let mut seed = 12345u64;
+    let mut next = || { seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407); seed >> 33 };
+    for i in 0..3000 {
+        let min = next() % 100000;
+        let rng = Range::new(min, min + next() % 500);
+        let data = vec![i as u8; if i % 100 == 0 { 5000 } else { (i % 20) as usize }];
+        root = insert(&mut pager, root, rng, 0, data.clone()).unwrap();
+        reference.insert(key(rng), data);
+    }
+    let keys = reference.keys().cloned().collect::<Vec<Key>>();
+    for (i, &(min, max)) in keys.iter().enumerate() {
+        if i % 3 != 0 {
+            let (new_root, found) = delete(&mut pager, root, Range::new(min, max)).unwrap();
+            assert!(found);
+            root = new_root;
+            reference.remove(&(min, max));
+        }
+    }
+    assert!(!delete(&mut pager, root, Range::new(1, 0xffffffffff)).unwrap().1);
+    pager.flush().unwrap();
+
+    for _ in 0..50 {
+        let min = next() % 100000;
+        let query = Range::new(min, min + next() % 2000);
+        let expected = reference.iter()
+                                .filter(|&(&(min, max), _)| min <= query.max && max >= query.min)
+                                .map(|(&(min, max), data)| (Range::new(min, max), data.clone()))
+                                .collect::<Vec<(Range, Vec<u8>)>>();
+        assert_eq!(collect(&mut pager, root, query), expected);
+    }
+    assert_eq!(collect(&mut pager, root, Range::new(0, u64::max_value())).len(), reference.len());
+
+    for &(min, max) in reference.keys() {
+        root = delete(&mut pager, root, Range::new(min, max)).unwrap().0;
+    }
+    assert_eq!(root, 0);
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_btree_cycles() {
+    use super::pager::temp_page_file;
+
+    let path = temp_page_file("btree_cycles");
+    let mut pager = Pager::open(&path, 16).unwrap();
+    let (a, b, c) = (pager.allocate().unwrap(), pager.allocate().unwrap(), pager.allocate().unwrap());
+    let child = |page| Node::Internal(vec![Child{key: Range::new(0, 10), max_end: 10, page: page}]);
+    child(b).write(&mut pager, a).unwrap();
+    child(a).write(&mut pager, b).unwrap();
+    child(c).write(&mut pager, c).unwrap();
+    for &root in &[a, c] {
+        assert!(Cursor::new(root, Range::new(0, 10)).next(&mut pager).is_err());
+        assert!(insert(&mut pager, root, Range::new(5, 5), 0, vec![1]).is_err());
+        assert!(delete(&mut pager, root, Range::new(5, 5)).is_err());
+        assert!(free_tree(&mut pager, root).is_err());
+    }
+
+    // an overflow chain that points back to itself
+    let d = pager.allocate().unwrap();
+    put_u64(pager.write(d).unwrap(), 0, d);
+    assert!(read_overflow(&mut pager, d, 1 << 40).is_err());
+    assert!(free_overflow(&mut pager, d, 3 * OVERFLOW_DATA as u64).is_err());
+    ::std::fs::remove_file(&path).unwrap();
+}
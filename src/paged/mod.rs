@@ -0,0 +1,389 @@
+extern crate rmp;
+extern crate memrange;
+
+mod pager;
+mod btree;
+
+use self::memrange::Range;
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use db::DB;
+use backend::StorageBackend;
+use content::Object;
+use content::Bitmap;
+use dberror::DBError;
+use table_options::TableOptions;
+use serialize::{Serialized, DBReader, ReadLimits, write_string, parse_table_name};
+use self::pager::{Pager, PageId};
+use self::btree::Cursor;
+
+/// Number of pages (of 4 KiB) kept in the buffer cache by `PagedDB::open`.
+pub const DEFAULT_CACHE_PAGES: usize = 1024;
+
+#[derive(Clone, Copy)]
+struct TableRoots {
+    objects: PageId,
+    bitmaps: PageId,
+}
+
+/// The catalog maps table names to the roots of their trees, followed by the
+/// options of the table if it has any set.
+fn encode_catalog(tables: &BTreeMap<String, TableRoots>, options: &BTreeMap<String, TableOptions>) -> Result<Vec<u8>, DBError> {
+    let mut buf = vec![];
+    try!(rmp::encode::write_map_len(&mut buf, tables.len() as u32));
+    for (name, roots) in tables {
+        try!(write_string(name, &mut buf));
+        let table_options = options.get(name);
+        try!(rmp::encode::write_array_len(&mut buf, if table_options.is_some() { 3 } else { 2 }));
+        try!(rmp::encode::write_u64(&mut buf, roots.objects));
+        try!(rmp::encode::write_u64(&mut buf, roots.bitmaps));
+        if let Some(table_options) = table_options {
+            try!(table_options.write(&mut buf));
+        }
+    }
+    return Ok(buf);
+}
+
+/// Roots and options of all tables.
+type Catalog = (BTreeMap<String, TableRoots>, BTreeMap<String, TableOptions>);
+
+fn decode_catalog(buf: &[u8]) -> Result<Catalog, DBError> {
+    let mut input = buf;
+    let mut r = DBReader::new(&mut input, ReadLimits::default());
+    r.push_field("catalog");
+    let res = rmp::decode::read_map_size(&mut r).map_err(DBError::from).and_then(|len| {
+        try!(r.count_tables(len as u64));
+        let mut tables = BTreeMap::new();
+        let mut options = BTreeMap::new();
+        for i in 0..len {
+            let name = try!(parse_table_name(&mut r, i));
+            r.push_table(&name);
+            let fields = try!(rmp::decode::read_array_size(&mut r));
+            if fields != 2 && fields != 3 {
+                return Err(r.error("table roots should have length 2 or 3"));
+            }
+            let objects = try!(rmp::decode::read_u64(&mut r));
+            let bitmaps = try!(rmp::decode::read_u64(&mut r));
+            if fields == 3 {
+                options.insert(name.clone(), try!(TableOptions::read(&mut r)));
+            }
+            r.pop_path();
+            tables.insert(name, TableRoots{objects: objects, bitmaps: bitmaps});
+        }
+        return Ok((tables, options));
+    });
+    return res.map_err(|e| r.context(e));
+}
+
+/// Backend whose tables live in a page file instead of memory, so they can
+/// be larger than the available RAM. Every table is a pair of on-disk
+/// interval trees; only the pages on the way to an entry are read, through a
+/// buffer cache of a fixed number of pages.
+pub struct PagedBackend {
+    pager: RefCell<Pager>,
+    tables: BTreeMap<String, TableRoots>,
+    /// Table options as they are stored in the catalog.
+    options: BTreeMap<String, TableOptions>,
+    catalog_dirty: bool,
+}
+
+/// DB whose tables are stored by a `PagedBackend`.
+///
+/// Modifications are kept in the page cache, or spilled next to the file
+/// once it is full, until `flush`, which writes just the pages that changed
+/// together with the table options. A flush is atomic: it goes through a
+/// journal that is replayed if the process dies halfway. Changes that were
+/// not flushed are lost when the `PagedDB` is dropped.
+pub type PagedDB = DB<PagedBackend>;
+
+impl PagedBackend {
+    fn open(filename: &String, cache_pages: usize) -> Result<PagedBackend, DBError> {
+        let mut pager = try!(Pager::open(Path::new(filename), cache_pages));
+        let (tables, options) = match pager.catalog() {
+            (0, _) => (BTreeMap::new(), BTreeMap::new()),
+            (page, len) => try!(decode_catalog(&try!(btree::read_overflow(&mut pager, page, len)))),
+        };
+        return Ok(PagedBackend{pager: RefCell::new(pager), tables: tables, options: options, catalog_dirty: false});
+    }
+
+    fn flush(&mut self, options: &BTreeMap<String, TableOptions>) -> Result<(), DBError> {
+        let pager = self.pager.get_mut();
+        if self.catalog_dirty || self.options != *options {
+            let (old_page, old_len) = pager.catalog();
+            if old_page != 0 {
+                try!(btree::free_overflow(pager, old_page, old_len));
+            }
+            let catalog = try!(encode_catalog(&self.tables, options));
+            let page = try!(btree::write_overflow(pager, &catalog));
+            pager.set_catalog(page, catalog.len() as u64);
+            self.options = options.clone();
+            self.catalog_dirty = false;
+        }
+        return pager.flush();
+    }
+
+    fn set_root(&mut self, table: &String, bitmaps: bool, root: PageId) {
+        let roots = self.tables.get_mut(table).unwrap();
+        let old = if bitmaps { &mut roots.bitmaps } else { &mut roots.objects };
+        if *old != root {
+            *old = root;
+            self.catalog_dirty = true;
+        }
+    }
+
+    fn insert_entry(&mut self, table: &String, bitmaps: bool, r: Range, entry_size: u64, data: Vec<u8>) -> Result<(), DBError> {
+        try!(self.add_table(table));
+        let roots = self.tables[table];
+        let root = if bitmaps { roots.bitmaps } else { roots.objects };
+        let root = try!(btree::insert(self.pager.get_mut(), root, r, entry_size, data));
+        self.set_root(table, bitmaps, root);
+        return Ok(());
+    }
+
+    fn delete_entry(&mut self, table: &String, bitmaps: bool, r: Range) -> Result<(), DBError> {
+        let roots = match self.tables.get(table) {
+            Some(&roots) => roots,
+            None => return Ok(()),
+        };
+        let root = if bitmaps { roots.bitmaps } else { roots.objects };
+        let (root, _) = try!(btree::delete(self.pager.get_mut(), root, r));
+        self.set_root(table, bitmaps, root);
+        return Ok(());
+    }
+
+    fn free_table(&mut self, roots: TableRoots) -> Result<(), DBError> {
+        let pager = self.pager.get_mut();
+        try!(btree::free_tree(pager, roots.objects));
+        try!(btree::free_tree(pager, roots.bitmaps));
+        return Ok(());
+    }
+}
+
+impl StorageBackend for PagedBackend {
+    type ObjectIter<'a> = PagedObjectIter<'a>;
+    type BitmapIter<'a> = PagedBitmapIter<'a>;
+
+    fn has_table(&self, table: &String) -> bool {
+        return self.tables.contains_key(table);
+    }
+
+    fn tables(&self) -> Vec<&String> {
+        return self.tables.keys().collect();
+    }
+
+    fn add_table(&mut self, table: &String) -> Result<(), DBError> {
+        if !self.tables.contains_key(table) {
+            self.tables.insert(table.clone(), TableRoots{objects: 0, bitmaps: 0});
+            self.catalog_dirty = true;
+        }
+        return Ok(());
+    }
+
+    /// Removes the table and frees all of its pages.
+    fn drop_table(&mut self, table: &String) -> Result<(), DBError> {
+        return match self.tables.remove(table) {
+            Some(roots) => {
+                self.catalog_dirty = true;
+                self.free_table(roots)
+            }
+            None => Ok(()),
+        };
+    }
+
+    fn rename_table(&mut self, from: &String, to: &String) -> Result<(), DBError> {
+        let roots = self.tables.remove(from).unwrap_or(TableRoots{objects: 0, bitmaps: 0});
+        self.tables.insert(to.clone(), roots);
+        self.catalog_dirty = true;
+        return Ok(());
+    }
+
+    fn clear_table(&mut self, table: &String) -> Result<(), DBError> {
+        let old = self.tables.insert(table.clone(), TableRoots{objects: 0, bitmaps: 0});
+        self.catalog_dirty = true;
+        return match old {
+            Some(roots) => self.free_table(roots),
+            None => Ok(()),
+        };
+    }
+
+    fn query_objects<'a>(&'a self, table: &String, r: Range) -> Option<PagedObjectIter<'a>> {
+        return self.tables.get(table).map(|roots| PagedObjectIter{pager: &self.pager, cursor: Cursor::new(roots.objects, r)});
+    }
+
+    fn insert_object(&mut self, table: &String, r: Range, d: Object) -> Result<(), DBError> {
+        return self.insert_entry(table, false, r, 0, d.data);
+    }
+
+    fn delete_object(&mut self, table: &String, r: Range) -> Result<(), DBError> {
+        return self.delete_entry(table, false, r);
+    }
+
+    fn query_bitmaps<'a>(&'a self, table: &String, r: Range) -> Option<PagedBitmapIter<'a>> {
+        return self.tables.get(table).map(|roots| PagedBitmapIter{pager: &self.pager, cursor: Cursor::new(roots.bitmaps, r)});
+    }
+
+    fn insert_bitmap(&mut self, table: &String, r: Range, d: Bitmap) -> Result<(), DBError> {
+        return self.insert_entry(table, true, r, d.entry_size, d.data);
+    }
+
+    fn delete_bitmap(&mut self, table: &String, r: Range) -> Result<(), DBError> {
+        return self.delete_entry(table, true, r);
+    }
+}
+
+impl DB<PagedBackend> {
+    /// Opens the page file `filename`, creating an empty one if it does not exist.
+    #[must_use]
+    pub fn open(filename: &String) -> Result<PagedDB, DBError> {
+        return PagedDB::open_with_cache(filename, DEFAULT_CACHE_PAGES);
+    }
+
+    #[must_use]
+    pub fn open_with_cache(filename: &String, cache_pages: usize) -> Result<PagedDB, DBError> {
+        let backend = try!(PagedBackend::open(filename, cache_pages));
+        let options = backend.options.clone();
+        let mut db = DB::with_backend(backend);
+        db.options = options;
+        return Ok(db);
+    }
+
+    /// Writes all modifications to the file.
+    #[must_use]
+    pub fn flush(&mut self) -> Result<(), DBError> {
+        return self.backend.flush(&self.options);
+    }
+}
+
+/// Objects of a `PagedDB` query. They are read from the file as the iterator
+/// advances, so every item can fail.
+pub struct PagedObjectIter<'a> {
+    pager: &'a RefCell<Pager>,
+    cursor: Cursor,
+}
+
+impl<'a> Iterator for PagedObjectIter<'a> {
+    type Item = Result<(Range, Cow<'a, Object>), DBError>;
+
+    fn next(&mut self) -> Option<Result<(Range, Cow<'a, Object>), DBError>> {
+        let mut pager = self.pager.borrow_mut();
+        return match self.cursor.next(&mut pager) {
+            Ok(Some(entry)) => Some(entry.read_data(&mut pager).map(|data| (entry.range, Cow::Owned(Object::new(data))))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        };
+    }
+}
+
+/// Bitmaps of a `PagedDB` query, read like `PagedObjectIter`.
+pub struct PagedBitmapIter<'a> {
+    pager: &'a RefCell<Pager>,
+    cursor: Cursor,
+}
+
+impl<'a> Iterator for PagedBitmapIter<'a> {
+    type Item = Result<(Range, Cow<'a, Bitmap>), DBError>;
+
+    fn next(&mut self) -> Option<Result<(Range, Cow<'a, Bitmap>), DBError>> {
+        let mut pager = self.pager.borrow_mut();
+        return match self.cursor.next(&mut pager) {
+            Ok(Some(entry)) => Some(entry.read_data(&mut pager).map(|data| (entry.range, Cow::Owned(Bitmap::new(entry.entry_size, data))))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        };
+    }
+}
+
+#[cfg(test)]
+fn table_contents<B: StorageBackend>(db: &DB<B>, tbl: &String) -> (Vec<(Range, Object)>, Vec<(Range, Bitmap)>) {
+    let all = Range::new(0, u64::max_value());
+    let objects = db.query_object(tbl, all).unwrap().map(|e| e.map(|(r, o)| (r, o.into_owned())).unwrap()).collect();
+    let bitmaps = db.query_bitmap(tbl, all).unwrap().map(|e| e.map(|(r, b)| (r, b.to_bitmap())).unwrap()).collect();
+    return (objects, bitmaps);
+}
+
+#[test]
+fn test_paged_db_matches_memory_db() {
+    let path = pager::temp_page_file("paged_db");
+    let filename = path.to_string_lossy().into_owned();
+    let (foo, bar, baz) = ("foo".to_string(), "bar".to_string(), "baz".to_string());
+    let mut paged = PagedDB::open_with_cache(&filename, 8).unwrap();
+    let mut memory = DB::new();
+    for i in 0..2000u64 {
+        let rng = Range::new(i * 7 % 1000, i * 7 % 1000 + i % 13);
+        let obj = Object::new(vec![i as u8; (i % 700) as usize]);
+        paged.insert_object(&foo, rng, obj.clone()).unwrap();
+        memory.insert_object(&foo, rng, obj).unwrap();
+        if i % 5 == 0 {
+            let rng = Range::new(i, i + 3);
+            let bitmap = Bitmap::new(2, vec![i as u8; 8]);
+            paged.insert_bitmap(&bar, rng, bitmap.clone()).unwrap();
+            memory.insert_bitmap(&bar, rng, bitmap).unwrap();
+        }
+    }
+    for i in 0..100u64 {
+        paged.delete_object(&foo, Range::new(i * 7 % 1000, i * 7 % 1000 + i % 13)).unwrap();
+        memory.delete_object(&foo, Range::new(i * 7 % 1000, i * 7 % 1000 + i % 13)).unwrap();
+    }
+    paged.delete_intersecting_objects(&foo, Range::new(500, 520)).unwrap();
+    memory.delete_intersecting_objects(&foo, Range::new(500, 520)).unwrap();
+    paged.delete_bitmap(&bar, 2, Range::new(101, 1200)).unwrap();
+    memory.delete_bitmap(&bar, 2, Range::new(101, 1200)).unwrap();
+    assert_eq!(table_contents(&paged, &foo), table_contents(&memory, &foo));
+    assert_eq!(table_contents(&paged, &bar), table_contents(&memory, &bar));
+
+    let query = Range::new(40, 60);
+    let objects = paged.query_object(&foo, query).unwrap().map(|e| e.map(|(r, o)| (r, o.into_owned())).unwrap()).collect::<Vec<(Range, Object)>>();
+    assert_eq!(objects, memory.query_object(&foo, query).unwrap().map(|e| e.map(|(r, o)| (r, o.into_owned())).unwrap()).collect::<Vec<(Range, Object)>>());
+    let bitmaps = paged.query_bitmap(&bar, query).unwrap().map(|e| e.map(|(r, b)| (r, b.to_bitmap())).unwrap()).collect::<Vec<(Range, Bitmap)>>();
+    assert_eq!(bitmaps, memory.query_bitmap(&bar, query).unwrap().map(|e| e.map(|(r, b)| (r, b.to_bitmap())).unwrap()).collect::<Vec<(Range, Bitmap)>>());
+
+    paged.create_table(&baz).unwrap();
+    paged.flush().unwrap();
+    paged.insert_object(&baz, Range::new(1, 2), Object::new(vec![1])).unwrap();
+    drop(paged);
+
+    let mut paged = PagedDB::open(&filename).unwrap();
+    assert_eq!(paged.tables(), vec![&bar, &baz, &foo]);
+    assert_eq!(paged.query_object(&baz, Range::new(0, 10)).unwrap().count(), 0);
+    assert_eq!(table_contents(&paged, &foo), table_contents(&memory, &foo));
+    paged.drop_table(&foo).unwrap();
+    paged.rename_table(&bar, &foo).unwrap();
+    paged.clear_table(&baz).unwrap();
+    paged.flush().unwrap();
+    drop(paged);
+
+    let paged = PagedDB::open(&filename).unwrap();
+    assert_eq!(paged.tables(), vec![&baz, &foo]);
+    assert_eq!(table_contents(&paged, &foo).1, table_contents(&memory, &bar).1);
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_paged_db_options_and_transactions() {
+    let path = pager::temp_page_file("paged_db_options");
+    let filename = path.to_string_lossy().into_owned();
+    let foo = "foo".to_string();
+    let (a, b) = (Object::new(vec![1]), Object::new(vec![2]));
+    let mut paged = PagedDB::open_with_cache(&filename, 4).unwrap();
+    paged.set_table_options(&foo, TableOptions{multimap: true, ..TableOptions::default()}).unwrap();
+    paged.insert_object(&foo, Range::new(0, 10), a.clone()).unwrap();
+    paged.insert_object(&foo, Range::new(0, 10), b.clone()).unwrap();
+    let res = paged.transaction(|db| -> Result<(), DBError> {
+        try!(db.delete_object_value(&foo, Range::new(0, 10), &a));
+        try!(db.punch_hole(&foo, Range::new(5, 5)));
+        return Err(DBError::Protocol("aborted".into()));
+    });
+    assert!(res.is_err());
+    paged.flush().unwrap();
+    drop(paged);
+
+    let paged = PagedDB::open(&filename).unwrap();
+    assert!(paged.table_options(&foo).multimap);
+    let values = paged.query_object_values(&foo, Range::new(0, 100)).unwrap().map(|e| e.unwrap()).collect::<Vec<_>>();
+    assert_eq!(values, vec![(Range::new(0, 10), vec![a, b])]);
+    ::std::fs::remove_file(&path).unwrap();
+}
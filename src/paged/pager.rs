@@ -0,0 +1,479 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use dberror::DBError;
+use atomic_file::sync_parent_dir;
+use serialize::checksum::Crc32;
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// Page 0 holds the header: magic, version, page count, head of the free
+/// list and page and length of the table catalog, all little endian u64.
+const PAGE_MAGIC: &'static [u8; 8] = b"THEBANPG";
+const PAGE_VERSION: u64 = 1;
+const HEADER_VERSION: usize = 8;
+const HEADER_PAGE_COUNT: usize = 16;
+const HEADER_FREE_HEAD: usize = 24;
+const HEADER_CATALOG_PAGE: usize = 32;
+const HEADER_CATALOG_LEN: usize = 40;
+
+/// The journal holds the new contents of all pages of a flush: magic, page
+/// count, (page id, page) pairs and a CRC-32 over all of it.
+const JOURNAL_MAGIC: &'static [u8; 8] = b"THEBANPJ";
+
+pub type PageId = u64;
+
+pub fn get_u64(buf: &[u8], pos: usize) -> u64 {
+    return buf[pos..pos+8].iter().rev().fold(0u64, |acc, &b| acc << 8 | b as u64);
+}
+
+pub fn put_u64(buf: &mut [u8], pos: usize, val: u64) {
+    for i in 0..8 {
+        buf[pos + i] = (val >> (8 * i)) as u8;
+    }
+}
+
+struct CachedPage {
+    data: Vec<u8>,
+    dirty: bool,
+    used: u64,
+}
+
+/// Fixed size pages of a file behind a buffer cache.
+///
+/// Pages are evicted least recently used first once the cache holds more than
+/// `capacity` pages. An evicted page that was modified is written to a spill
+/// file next to the file, never to the file itself, and read back from there
+/// when it is used again. `flush` writes all modified pages, first to a
+/// journal next to the file and then in place, so a crash during a flush is
+/// rolled forward the next time the file is opened.
+pub struct Pager {
+    file: File,
+    path: PathBuf,
+    cache: HashMap<PageId, CachedPage>,
+    /// Modified pages that were evicted, by their offset in the spill file.
+    spilled: HashMap<PageId, u64>,
+    spill: Option<File>,
+    /// Cached pages by the time they were last used.
+    lru: BTreeMap<u64, PageId>,
+    capacity: usize,
+    clock: u64,
+    page_count: u64,
+    free_head: PageId,
+    catalog: (PageId, u64),
+    header_dirty: bool,
+}
+
+impl Pager {
+    /// Opens the page file at `path`, creating it if it does not exist.
+    pub fn open(path: &Path, capacity: usize) -> Result<Pager, DBError> {
+        let file = try!(OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path));
+        let mut pager = Pager{file: file, path: path.to_path_buf(), cache: HashMap::new(), spilled: HashMap::new(),
+                              spill: None, lru: BTreeMap::new(), capacity: capacity,
+                              clock: 0, page_count: 1, free_head: 0, catalog: (0, 0), header_dirty: true};
+        try!(pager.recover());
+        let len = try!(pager.file.metadata()).len();
+        if len == 0 {
+            try!(pager.insert_page(0, vec![0; PAGE_SIZE], true));
+            try!(pager.flush());
+            return Ok(pager);
+        }
+        let mut header = vec![0; PAGE_SIZE];
+        try!(pager.file.seek(SeekFrom::Start(0)));
+        if len % PAGE_SIZE as u64 != 0 || pager.file.read_exact(&mut header).is_err() || &header[0..8] != PAGE_MAGIC {
+            return Err(DBError::FileFormat("not a theban page file".into()));
+        }
+        let version = get_u64(&header, HEADER_VERSION);
+        if version != PAGE_VERSION {
+            return Err(DBError::FileFormat(format!("unsupported page file version {}", version)));
+        }
+        pager.page_count = get_u64(&header, HEADER_PAGE_COUNT);
+        if pager.page_count == 0 || pager.page_count > len / PAGE_SIZE as u64 {
+            return Err(DBError::FileFormat(format!("page count {} does not match the file", pager.page_count)));
+        }
+        pager.free_head = get_u64(&header, HEADER_FREE_HEAD);
+        pager.catalog = (get_u64(&header, HEADER_CATALOG_PAGE), get_u64(&header, HEADER_CATALOG_LEN));
+        pager.header_dirty = false;
+        return Ok(pager);
+    }
+
+    fn journal_path(&self) -> PathBuf {
+        let name = self.path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or("db".into());
+        return self.path.with_file_name(format!("{}-journal", name));
+    }
+
+    fn spill_path(&self) -> PathBuf {
+        let name = self.path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or("db".into());
+        return self.path.with_file_name(format!("{}-spill", name));
+    }
+
+    /// Applies a complete journal left behind by an interrupted flush. An
+    /// incomplete one is discarded, the file was not touched yet in that case.
+    fn recover(&mut self) -> Result<(), DBError> {
+        let journal = self.journal_path();
+        let mut buf = vec![];
+        match File::open(&journal) {
+            Ok(mut f) => try!(f.read_to_end(&mut buf)),
+            Err(_) => return Ok(()),
+        };
+        if let Some(pages) = parse_journal(&buf) {
+            try!(write_pages(&mut self.file, &pages));
+        }
+        try!(fs::remove_file(&journal));
+        try!(sync_parent_dir(&journal));
+        return Ok(());
+    }
+
+    pub fn catalog(&self) -> (PageId, u64) {
+        return self.catalog;
+    }
+
+    pub fn set_catalog(&mut self, page: PageId, len: u64) {
+        self.catalog = (page, len);
+        self.header_dirty = true;
+    }
+
+    fn insert_page(&mut self, id: PageId, data: Vec<u8>, dirty: bool) -> Result<(), DBError> {
+        try!(self.evict(1));
+        self.clock += 1;
+        self.lru.insert(self.clock, id);
+        self.cache.insert(id, CachedPage{data: data, dirty: dirty, used: self.clock});
+        return Ok(());
+    }
+
+    fn load(&mut self, id: PageId) -> Result<&mut CachedPage, DBError> {
+        if !self.cache.contains_key(&id) {
+            if id >= self.page_count {
+                return Err(DBError::FileFormat(format!("page {} is out of range", id)));
+            }
+            let mut data = vec![0; PAGE_SIZE];
+            let spilled = self.spilled.get(&id).cloned();
+            match (spilled, self.spill.as_mut()) {
+                (Some(offset), Some(spill)) => {
+                    try!(spill.seek(SeekFrom::Start(offset)));
+                    try!(spill.read_exact(&mut data));
+                }
+                _ => {
+                    try!(self.file.seek(SeekFrom::Start(id * PAGE_SIZE as u64)));
+                    try!(self.file.read_exact(&mut data));
+                }
+            }
+            try!(self.insert_page(id, data, spilled.is_some()));
+        }
+        let page = self.cache.get_mut(&id).unwrap();
+        self.clock += 1;
+        self.lru.remove(&page.used);
+        self.lru.insert(self.clock, id);
+        page.used = self.clock;
+        return Ok(page);
+    }
+
+    pub fn read(&mut self, id: PageId) -> Result<&[u8], DBError> {
+        return self.load(id).map(|page| &page.data[..]);
+    }
+
+    /// Returns the page for modification and marks it dirty.
+    pub fn write(&mut self, id: PageId) -> Result<&mut [u8], DBError> {
+        let page = try!(self.load(id));
+        page.dirty = true;
+        return Ok(&mut page.data[..]);
+    }
+
+    /// Returns a zeroed page, reusing freed pages first.
+    pub fn allocate(&mut self) -> Result<PageId, DBError> {
+        self.header_dirty = true;
+        if self.free_head != 0 {
+            let id = self.free_head;
+            self.free_head = get_u64(try!(self.read(id)), 0);
+            for b in try!(self.write(id)).iter_mut() {
+                *b = 0;
+            }
+            return Ok(id);
+        }
+        let id = self.page_count;
+        self.page_count += 1;
+        try!(self.insert_page(id, vec![0; PAGE_SIZE], true));
+        return Ok(id);
+    }
+
+    pub fn free(&mut self, id: PageId) -> Result<(), DBError> {
+        let next = self.free_head;
+        let page = try!(self.write(id));
+        for b in page.iter_mut() {
+            *b = 0;
+        }
+        put_u64(page, 0, next);
+        self.free_head = id;
+        self.header_dirty = true;
+        return Ok(());
+    }
+
+    /// Drops pages until `needed` more pages fit into the cache, modified
+    /// ones are spilled.
+    fn evict(&mut self, needed: usize) -> Result<(), DBError> {
+        if self.cache.len() + needed <= self.capacity {
+            return Ok(());
+        }
+        let excess = self.cache.len() + needed - self.capacity;
+        let victims = self.lru.iter().take(excess).map(|(&used, &id)| (used, id)).collect::<Vec<(u64, PageId)>>();
+        for (used, id) in victims {
+            self.lru.remove(&used);
+            let page = self.cache.remove(&id).unwrap();
+            if page.dirty {
+                try!(self.spill_page(id, &page.data));
+            }
+        }
+        return Ok(());
+    }
+
+    /// Writes a modified page to the spill file, where a previous version of
+    /// it is overwritten.
+    fn spill_page(&mut self, id: PageId, data: &[u8]) -> Result<(), DBError> {
+        if self.spill.is_none() {
+            let spill = try!(OpenOptions::new().read(true).write(true).create(true).truncate(true).open(self.spill_path()));
+            self.spill = Some(spill);
+        }
+        let spill = self.spill.as_mut().unwrap();
+        let offset = match self.spilled.get(&id) {
+            Some(&offset) => offset,
+            None => self.spilled.len() as u64 * PAGE_SIZE as u64,
+        };
+        try!(spill.seek(SeekFrom::Start(offset)));
+        try!(spill.write_all(data));
+        self.spilled.insert(id, offset);
+        return Ok(());
+    }
+
+    /// Ids of all modified pages, cached or spilled, in ascending order.
+    fn dirty_ids(&self) -> Vec<PageId> {
+        let mut ids = self.cache.iter()
+                          .filter(|&(_, page)| page.dirty)
+                          .map(|(&id, _)| id)
+                          .chain(self.spilled.keys().filter(|id| !self.cache.contains_key(id)).cloned())
+                          .collect::<Vec<PageId>>();
+        ids.sort();
+        return ids;
+    }
+
+    /// Copies the current contents of the modified page `id` to `buf`.
+    fn read_dirty(&mut self, id: PageId, buf: &mut [u8]) -> Result<(), DBError> {
+        if let Some(page) = self.cache.get(&id) {
+            buf.copy_from_slice(&page.data);
+            return Ok(());
+        }
+        let spill = self.spill.as_mut().unwrap();
+        try!(spill.seek(SeekFrom::Start(self.spilled[&id])));
+        try!(spill.read_exact(buf));
+        return Ok(());
+    }
+
+    fn write_header(&mut self) -> Result<(), DBError> {
+        let (page_count, free_head, (catalog_page, catalog_len)) = (self.page_count, self.free_head, self.catalog);
+        let header = try!(self.write(0));
+        header[0..8].copy_from_slice(PAGE_MAGIC);
+        put_u64(header, HEADER_VERSION, PAGE_VERSION);
+        put_u64(header, HEADER_PAGE_COUNT, page_count);
+        put_u64(header, HEADER_FREE_HEAD, free_head);
+        put_u64(header, HEADER_CATALOG_PAGE, catalog_page);
+        put_u64(header, HEADER_CATALOG_LEN, catalog_len);
+        self.header_dirty = false;
+        return Ok(());
+    }
+
+    /// Writes and syncs the journal for the pages `ids`. The pages are
+    /// copied one at a time, so the journal is never held in memory.
+    fn write_journal(&mut self, ids: &Vec<PageId>) -> Result<(), DBError> {
+        let mut f = BufWriter::new(try!(File::create(self.journal_path())));
+        let mut crc = Crc32::new();
+        let mut head = JOURNAL_MAGIC.to_vec();
+        head.extend_from_slice(&[0; 8]);
+        put_u64(&mut head, 8, ids.len() as u64);
+        crc.update(&head);
+        try!(f.write_all(&head));
+        let mut page = vec![0; 8 + PAGE_SIZE];
+        for &id in ids {
+            put_u64(&mut page, 0, id);
+            try!(self.read_dirty(id, &mut page[8..]));
+            crc.update(&page);
+            try!(f.write_all(&page));
+        }
+        let checksum = crc.finish();
+        try!(f.write_all(&[checksum as u8, (checksum >> 8) as u8, (checksum >> 16) as u8, (checksum >> 24) as u8]));
+        try!(f.flush());
+        try!(f.get_ref().sync_all());
+        return Ok(());
+    }
+
+    /// Makes all modifications durable.
+    pub fn flush(&mut self) -> Result<(), DBError> {
+        if self.header_dirty {
+            try!(self.write_header());
+        }
+        let ids = self.dirty_ids();
+        if ids.is_empty() {
+            return Ok(());
+        }
+        try!(self.write_journal(&ids));
+        // the journal must be found after a crash before any page is overwritten
+        let journal = self.journal_path();
+        try!(sync_parent_dir(&journal));
+        let mut page = vec![0; PAGE_SIZE];
+        for &id in &ids {
+            try!(self.read_dirty(id, &mut page));
+            try!(self.file.seek(SeekFrom::Start(id * PAGE_SIZE as u64)));
+            try!(self.file.write_all(&page));
+        }
+        try!(self.file.sync_all());
+        try!(fs::remove_file(&journal));
+        try!(sync_parent_dir(&journal));
+        for page in self.cache.values_mut() {
+            page.dirty = false;
+        }
+        self.spilled.clear();
+        if let Some(ref spill) = self.spill {
+            try!(spill.set_len(0));
+        }
+        return self.evict(0);
+    }
+}
+
+impl Drop for Pager {
+    /// The spill file only holds modifications that were not flushed, which
+    /// are lost anyway.
+    fn drop(&mut self) {
+        if self.spill.is_some() {
+            let _ = fs::remove_file(self.spill_path());
+        }
+    }
+}
+
+fn write_pages(file: &mut File, pages: &Vec<(PageId, &[u8])>) -> Result<(), DBError> {
+    for &(id, data) in pages {
+        try!(file.seek(SeekFrom::Start(id * PAGE_SIZE as u64)));
+        try!(file.write_all(data));
+    }
+    try!(file.sync_all());
+    return Ok(());
+}
+
+fn parse_journal(buf: &[u8]) -> Option<Vec<(PageId, &[u8])>> {
+    if buf.len() < 20 || &buf[0..8] != JOURNAL_MAGIC {
+        return None;
+    }
+    let count = get_u64(buf, 8);
+    let expected = count.checked_mul(8 + PAGE_SIZE as u64).and_then(|len| len.checked_add(20));
+    if expected != Some(buf.len() as u64) {
+        return None;
+    }
+    let end = buf.len() - 4;
+    let mut crc = Crc32::new();
+    crc.update(&buf[0..end]);
+    let checksum = buf[end..].iter().rev().fold(0u32, |acc, &b| acc << 8 | b as u32);
+    if crc.finish() != checksum {
+        return None;
+    }
+    return Some(buf[16..end].chunks(8 + PAGE_SIZE).map(|chunk| (get_u64(chunk, 0), &chunk[8..])).collect());
+}
+
+#[cfg(test)]
+pub fn temp_page_file(name: &str) -> PathBuf {
+    let path = ::std::env::temp_dir().join(format!("theban_db_{}_{}", name, ::std::process::id()));
+    let _ = fs::remove_file(&path);
+    return path;
+}
+
+#[test]
+fn test_pager_cache_and_free_list() {
+    let path = temp_page_file("pager");
+    let mut pager = Pager::open(&path, 2).unwrap();
+    let ids = (0..4).map(|_| pager.allocate().unwrap()).collect::<Vec<PageId>>();
+    assert_eq!(ids, vec![1, 2, 3, 4]);
+    for &id in &ids {
+        put_u64(pager.write(id).unwrap(), 0, id * 10);
+    }
+    pager.flush().unwrap();
+    assert!(pager.cache.len() <= 2);
+    for &id in &ids {
+        assert_eq!(get_u64(pager.read(id).unwrap(), 0), id * 10);
+    }
+    pager.free(2).unwrap();
+    pager.free(3).unwrap();
+    pager.flush().unwrap();
+
+    let mut pager = Pager::open(&path, 2).unwrap();
+    assert_eq!(pager.allocate().unwrap(), 3);
+    assert_eq!(pager.allocate().unwrap(), 2);
+    assert_eq!(pager.allocate().unwrap(), 5);
+    assert_eq!(get_u64(pager.read(4).unwrap(), 0), 40);
+    assert!(pager.read(6).is_err());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_pager_journal_recovery() {
+    let path = temp_page_file("pager_journal");
+    {
+        let mut pager = Pager::open(&path, 8).unwrap();
+        let id = pager.allocate().unwrap();
+        put_u64(pager.write(id).unwrap(), 0, 7);
+        pager.flush().unwrap();
+        put_u64(pager.write(id).unwrap(), 0, 8);
+        // crash after the journal was written but before the pages were
+        let ids = pager.dirty_ids();
+        pager.write_journal(&ids).unwrap();
+    }
+    let mut pager = Pager::open(&path, 8).unwrap();
+    assert_eq!(get_u64(pager.read(1).unwrap(), 0), 8);
+    assert!(!pager.journal_path().exists());
+
+    put_u64(pager.write(1).unwrap(), 0, 9);
+    let ids = pager.dirty_ids();
+    pager.write_journal(&ids).unwrap();
+    let journal = pager.journal_path();
+    let len = fs::metadata(&journal).unwrap().len();
+    OpenOptions::new().write(true).open(&journal).unwrap().set_len(len - 1).unwrap();
+    drop(pager);
+    let mut pager = Pager::open(&path, 8).unwrap();
+    assert_eq!(get_u64(pager.read(1).unwrap(), 0), 8);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_pager_spill() {
+    let path = temp_page_file("pager_spill");
+    {
+        let mut pager = Pager::open(&path, 2).unwrap();
+        let ids = (0..8).map(|_| pager.allocate().unwrap()).collect::<Vec<PageId>>();
+        for &id in &ids {
+            put_u64(pager.write(id).unwrap(), 0, id * 10);
+        }
+        assert!(pager.cache.len() <= 2);
+        assert!(pager.spill_path().exists());
+        // spilled pages are read back, and spilled again after another change
+        for &id in &ids {
+            assert_eq!(get_u64(pager.read(id).unwrap(), 0), id * 10);
+        }
+        put_u64(pager.write(1).unwrap(), 0, 11);
+        assert_eq!(fs::metadata(pager.spill_path()).unwrap().len(), 8 * PAGE_SIZE as u64);
+        pager.flush().unwrap();
+        assert!(pager.spilled.is_empty());
+
+        // unflushed changes never reach the file
+        put_u64(pager.write(2).unwrap(), 0, 1);
+        for &id in &ids[2..] {
+            pager.read(id).unwrap();
+        }
+        assert!(pager.spilled.contains_key(&2));
+    }
+    let mut pager = Pager::open(&path, 2).unwrap();
+    assert!(!pager.spill_path().exists());
+    assert_eq!(get_u64(pager.read(1).unwrap(), 0), 11);
+    assert_eq!(get_u64(pager.read(2).unwrap(), 0), 20);
+    assert_eq!(get_u64(pager.read(8).unwrap(), 0), 80);
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,405 @@
+extern crate memrange;
+
+use self::memrange::Range;
+
+use std::cmp;
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+type Link<T> = Option<Arc<Node<T>>>;
+
+#[derive(Clone)]
+struct Node<T> {
+    key: Range,
+    data: T,
+    /// Largest end of the ranges in the subtree.
+    max_end: u64,
+    height: u32,
+    left: Link<T>,
+    right: Link<T>,
+}
+
+fn cmp_key(a: Range, b: Range) -> Ordering {
+    return (a.min, a.max).cmp(&(b.min, b.max));
+}
+
+fn height<T>(link: &Link<T>) -> u32 {
+    return link.as_ref().map(|node| node.height).unwrap_or(0);
+}
+
+fn max_end<T>(link: &Link<T>) -> u64 {
+    return link.as_ref().map(|node| node.max_end).unwrap_or(0);
+}
+
+impl<T> Node<T> {
+    fn new(key: Range, data: T, left: Link<T>, right: Link<T>) -> Node<T> {
+        let mut node = Node{key: key, data: data, max_end: 0, height: 0, left: left, right: right};
+        node.update();
+        return node;
+    }
+
+    fn update(&mut self) {
+        self.height = 1 + cmp::max(height(&self.left), height(&self.right));
+        self.max_end = cmp::max(self.key.max, cmp::max(max_end(&self.left), max_end(&self.right)));
+    }
+}
+
+/// Interval tree whose nodes are shared between its copies. Cloning a tree
+/// only clones the handle to its root. A modification copies the shared nodes
+/// on the path to the changed entry and nothing else, so a copy that is kept
+/// around, like a snapshot, costs O(log n) per write to the live tree.
+///
+/// Entries are ordered by the start and then the end of their range, like in
+/// an `IntervalTree`.
+pub struct PersistentTree<T> {
+    root: Link<T>,
+    len: usize,
+}
+
+impl<T> Clone for PersistentTree<T> {
+    fn clone(&self) -> PersistentTree<T> {
+        return PersistentTree{root: self.root.clone(), len: self.len};
+    }
+}
+
+impl<T> PersistentTree<T> {
+    pub fn new() -> PersistentTree<T> {
+        return PersistentTree{root: None, len: 0};
+    }
+
+    /// Builds a balanced tree from entries sorted by range without any
+    /// rebalancing, in O(n). The ranges must be strictly ascending.
+    pub fn from_sorted(entries: Vec<(Range, T)>) -> PersistentTree<T> {
+        let len = entries.len();
+        let mut iter = entries.into_iter();
+        let root = build(&mut iter, len);
+        return PersistentTree{root: root, len: len};
+    }
+
+    pub fn len(&self) -> usize {
+        return self.len;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len == 0;
+    }
+
+    pub fn get(&self, key: Range) -> Option<&T> {
+        let mut link = &self.root;
+        while let Some(ref node) = *link {
+            link = match cmp_key(key, node.key) {
+                Ordering::Less => &node.left,
+                Ordering::Greater => &node.right,
+                Ordering::Equal => return Some(&node.data),
+            };
+        }
+        return None;
+    }
+
+    /// Entries intersecting [min, max] in ascending order.
+    pub fn range(&self, min: u64, max: u64) -> PersistentTreeIter<T> {
+        let mut iter = PersistentTreeIter{stack: vec![], query: Range::new(min, max)};
+        iter.push_left(&self.root);
+        return iter;
+    }
+
+    /// Whether both trees share their root, i.e. neither has been modified
+    /// since one was cloned from the other.
+    #[cfg(test)]
+    pub fn ptr_eq(&self, other: &PersistentTree<T>) -> bool {
+        return match (&self.root, &other.root) {
+            (&Some(ref a), &Some(ref b)) => Arc::ptr_eq(a, b),
+            (&None, &None) => true,
+            _ => false,
+        };
+    }
+}
+
+impl<T: Clone> PersistentTree<T> {
+    /// Stores `data` at `key`, replacing the entry with exactly that range.
+    pub fn insert(&mut self, key: Range, data: T) {
+        if insert_at(&mut self.root, key, data) {
+            self.len += 1;
+        }
+    }
+
+    /// Removes the entry with exactly the range `key`.
+    pub fn delete(&mut self, key: Range) -> bool {
+        if self.get(key).is_none() {
+            return false;
+        }
+        delete_at(&mut self.root, key);
+        self.len -= 1;
+        return true;
+    }
+}
+
+fn build<T, I: Iterator<Item=(Range, T)>>(entries: &mut I, n: usize) -> Link<T> {
+    if n == 0 {
+        return None;
+    }
+    let left = build(entries, n / 2);
+    let (key, data) = entries.next().expect("fewer entries than announced");
+    let right = build(entries, n - n / 2 - 1);
+    return Some(Arc::new(Node::new(key, data, left, right)));
+}
+
+fn rotate_left<T: Clone>(link: &mut Link<T>) {
+    let mut node = link.take().unwrap();
+    let mut right = Arc::make_mut(&mut node).right.take().unwrap();
+    Arc::make_mut(&mut node).right = Arc::make_mut(&mut right).left.take();
+    Arc::make_mut(&mut node).update();
+    Arc::make_mut(&mut right).left = Some(node);
+    Arc::make_mut(&mut right).update();
+    *link = Some(right);
+}
+
+fn rotate_right<T: Clone>(link: &mut Link<T>) {
+    let mut node = link.take().unwrap();
+    let mut left = Arc::make_mut(&mut node).left.take().unwrap();
+    Arc::make_mut(&mut node).left = Arc::make_mut(&mut left).right.take();
+    Arc::make_mut(&mut node).update();
+    Arc::make_mut(&mut left).right = Some(node);
+    Arc::make_mut(&mut left).update();
+    *link = Some(left);
+}
+
+/// Restores the AVL balance of the node at `link` after one of its subtrees
+/// changed. The node itself must not be shared anymore.
+fn rebalance<T: Clone>(link: &mut Link<T>) {
+    let (left, right) = match *link {
+        Some(ref node) => (height(&node.left), height(&node.right)),
+        None => return,
+    };
+    if left > right + 1 {
+        {
+            let node = Arc::make_mut(link.as_mut().unwrap());
+            let heavy_inside = node.left.as_ref().map(|l| height(&l.right) > height(&l.left)).unwrap();
+            if heavy_inside {
+                rotate_left(&mut node.left);
+            }
+        }
+        rotate_right(link);
+    } else if right > left + 1 {
+        {
+            let node = Arc::make_mut(link.as_mut().unwrap());
+            let heavy_inside = node.right.as_ref().map(|r| height(&r.left) > height(&r.right)).unwrap();
+            if heavy_inside {
+                rotate_right(&mut node.right);
+            }
+        }
+        rotate_left(link);
+    } else {
+        Arc::make_mut(link.as_mut().unwrap()).update();
+    }
+}
+
+/// Returns whether a new entry was added.
+fn insert_at<T: Clone>(link: &mut Link<T>, key: Range, data: T) -> bool {
+    let added = match *link {
+        None => {
+            *link = Some(Arc::new(Node::new(key, data, None, None)));
+            return true;
+        }
+        Some(ref mut node) => {
+            let node = Arc::make_mut(node);
+            match cmp_key(key, node.key) {
+                Ordering::Less => insert_at(&mut node.left, key, data),
+                Ordering::Greater => insert_at(&mut node.right, key, data),
+                Ordering::Equal => {
+                    node.data = data;
+                    return false;
+                }
+            }
+        }
+    };
+    rebalance(link);
+    return added;
+}
+
+/// Removes the smallest entry below `link` and returns it.
+fn take_min<T: Clone>(link: &mut Link<T>) -> (Range, T) {
+    if link.as_ref().unwrap().left.is_some() {
+        let res = take_min(&mut Arc::make_mut(link.as_mut().unwrap()).left);
+        rebalance(link);
+        return res;
+    }
+    let node = link.take().unwrap();
+    let node = Arc::try_unwrap(node).unwrap_or_else(|shared| (*shared).clone());
+    *link = node.right;
+    return (node.key, node.data);
+}
+
+/// Removes the entry at `key`, which must exist.
+fn delete_at<T: Clone>(link: &mut Link<T>, key: Range) {
+    match cmp_key(key, link.as_ref().unwrap().key) {
+        Ordering::Less => delete_at(&mut Arc::make_mut(link.as_mut().unwrap()).left, key),
+        Ordering::Greater => delete_at(&mut Arc::make_mut(link.as_mut().unwrap()).right, key),
+        Ordering::Equal => {
+            // only the handles to the children are needed, so a shared node
+            // is not copied
+            let (left, right) = {
+                let node = link.take().unwrap();
+                (node.left.clone(), node.right.clone())
+            };
+            *link = match (left, right) {
+                (None, right) => right,
+                (left, None) => left,
+                (left, right) => {
+                    let mut right = right;
+                    let (min_key, min_data) = take_min(&mut right);
+                    Some(Arc::new(Node::new(min_key, min_data, left, right)))
+                }
+            };
+        }
+    }
+    rebalance(link);
+}
+
+/// Iterator returned by `PersistentTree::range`.
+pub struct PersistentTreeIter<'a, T: 'a> {
+    /// Nodes whose left subtree has been visited already.
+    stack: Vec<&'a Node<T>>,
+    query: Range,
+}
+
+impl<'a, T> PersistentTreeIter<'a, T> {
+    fn push_left(&mut self, mut link: &'a Link<T>) {
+        while let Some(ref node) = *link {
+            // nothing in this subtree reaches the query
+            if node.max_end < self.query.min {
+                break;
+            }
+            self.stack.push(node);
+            link = &node.left;
+        }
+    }
+}
+
+impl<'a, T> Iterator for PersistentTreeIter<'a, T> {
+    type Item = (Range, &'a T);
+
+    fn next(&mut self) -> Option<(Range, &'a T)> {
+        while let Some(node) = self.stack.pop() {
+            // all following entries start behind the query as well
+            if node.key.min > self.query.max {
+                self.stack.clear();
+                return None;
+            }
+            self.push_left(&node.right);
+            if node.key.max >= self.query.min {
+                return Some((node.key, &node.data));
+            }
+        }
+        return None;
+    }
+}
+
+#[cfg(test)]
+fn check_node<T>(link: &Link<T>) -> (u32, u64) {
+    return match *link {
+        None => (0, 0),
+        Some(ref node) => {
+            let (left, right) = (check_node(&node.left), check_node(&node.right));
+            assert!((left.0 as i64 - right.0 as i64).abs() <= 1, "tree is unbalanced");
+            assert_eq!(node.height, 1 + cmp::max(left.0, right.0));
+            assert_eq!(node.max_end, cmp::max(node.key.max, cmp::max(left.1, right.1)));
+            if let Some(ref l) = node.left {
+                assert!(cmp_key(l.key, node.key) == Ordering::Less);
+            }
+            if let Some(ref r) = node.right {
+                assert!(cmp_key(r.key, node.key) == Ordering::Greater);
+            }
+            (node.height, node.max_end)
+        }
+    };
+}
+
+/// Number of nodes of `a` that are not shared with `b`.
+#[cfg(test)]
+fn unshared_nodes<T>(a: &Link<T>, b: &PersistentTree<T>) -> usize {
+    return match *a {
+        None => 0,
+        Some(ref node) => {
+            let mut shared = false;
+            let mut stack = b.root.iter().collect::<Vec<&Arc<Node<T>>>>();
+            while let Some(other) = stack.pop() {
+                if Arc::ptr_eq(node, other) {
+                    shared = true;
+                    break;
+                }
+                stack.extend(other.left.iter().chain(other.right.iter()));
+            }
+            if shared { 0 } else { 1 + unshared_nodes(&node.left, b) + unshared_nodes(&node.right, b) }
+        }
+    };
+}
+
+#[test]
+fn test_persistent_tree_matches_reference() {
+    use std::collections::BTreeMap;
+
+    let mut tree = PersistentTree::new();
+    let mut reference = BTreeMap::new();
+    let mut seed = 4711u64;
+    let mut next = || { seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407); seed >> 33 };
+    for i in 0..2000u64 {
+        let min = next() % 5000;
+        let rng = Range::new(min, min + next() % 100);
+        tree.insert(rng, i);
+        reference.insert((rng.min, rng.max), i);
+        if i % 3 == 0 {
+            let min = next() % 5000;
+            let rng = Range::new(min, min + next() % 100);
+            assert_eq!(tree.delete(rng), reference.remove(&(rng.min, rng.max)).is_some());
+        }
+    }
+    let keys = reference.keys().cloned().collect::<Vec<(u64, u64)>>();
+    for &(min, max) in keys.iter().step_by(2) {
+        assert!(tree.delete(Range::new(min, max)));
+        reference.remove(&(min, max));
+    }
+    check_node(&tree.root);
+    assert_eq!(tree.len(), reference.len());
+
+    for _ in 0..100 {
+        let min = next() % 5200;
+        let query = Range::new(min, min + next() % 300);
+        let expected = reference.iter()
+                                .filter(|&(&(min, max), _)| min <= query.max && max >= query.min)
+                                .map(|(&(min, max), &i)| (Range::new(min, max), i))
+                                .collect::<Vec<(Range, u64)>>();
+        let found = tree.range(query.min, query.max).map(|(r, &i)| (r, i)).collect::<Vec<(Range, u64)>>();
+        assert_eq!(found, expected);
+    }
+    let (&(min, max), &i) = reference.iter().next().unwrap();
+    assert_eq!(tree.get(Range::new(min, max)), Some(&i));
+
+    let sorted = PersistentTree::from_sorted(reference.iter().map(|(&(min, max), &i)| (Range::new(min, max), i)).collect());
+    check_node(&sorted.root);
+    assert_eq!(sorted.range(0, u64::max_value()).map(|(r, &i)| (r, i)).collect::<Vec<(Range, u64)>>(),
+               tree.range(0, u64::max_value()).map(|(r, &i)| (r, i)).collect::<Vec<(Range, u64)>>());
+}
+
+#[test]
+fn test_persistent_tree_sharing() {
+    let mut tree = PersistentTree::new();
+    for i in 0..1000u64 {
+        tree.insert(Range::new(i * 2, i * 2 + 1), i);
+    }
+    let copy = tree.clone();
+    assert!(tree.ptr_eq(&copy));
+
+    // only the path to the change is copied
+    tree.insert(Range::new(501, 501), 0);
+    tree.delete(Range::new(1000, 1001));
+    check_node(&tree.root);
+    assert!(!tree.ptr_eq(&copy));
+    assert!(unshared_nodes(&tree.root, &copy) <= 4 * tree.root.as_ref().unwrap().height as usize);
+    assert_eq!(copy.len(), 1000);
+    assert_eq!(copy.get(Range::new(1000, 1001)), Some(&500));
+    assert!(copy.get(Range::new(501, 501)).is_none());
+    assert_eq!(tree.len(), 1000);
+    assert_eq!(tree.range(500, 502).map(|(r, _)| r).collect::<Vec<Range>>(),
+               vec![Range::new(500, 501), Range::new(501, 501), Range::new(502, 503)]);
+}
@@ -0,0 +1,156 @@
+use memrange::Range;
+
+use db::DB;
+use backend::StorageBackend;
+use content::Object;
+use dberror::DBError;
+#[cfg(test)] use table_options::TableOptions;
+#[cfg(test)] use journal::temp_paths;
+
+/// Parts of `rng` that lie outside of `hole`.
+pub(crate) fn remaining_parts(rng: Range, hole: Range) -> Vec<Range> {
+    let mut parts = vec![];
+    if rng.min < hole.min {
+        parts.push(Range::new(rng.min, ::std::cmp::min(rng.max, hole.min - 1)));
+    }
+    if rng.max > hole.max {
+        parts.push(Range::new(::std::cmp::max(rng.min, hole.max + 1), rng.max));
+    }
+    return parts;
+}
+
+impl<B: StorageBackend> DB<B> {
+    /// Removes everything in `r` from the objects of `table`. Objects that
+    /// only partially overlap `r` are trimmed, or split in two if `r` lies
+    /// within them, and each part keeps a copy of the payload.
+    #[must_use]
+    pub fn punch_hole(&mut self, table: &String, r: Range) -> Result<(), DBError> {
+        return self.punch_hole_with(table, r, |_, value, _| value.clone());
+    }
+
+    /// Like `punch_hole`, but the payload of each remaining part is
+    /// `split(range, value, part)`, where `range` is the range of the
+    /// original object and `part` the part of it that is kept.
+    ///
+    /// All parts are computed before the table is changed, so a panic in
+    /// `split` leaves the table untouched. The parts are stored with
+    /// `insert_object`, so they are coalesced like any other insert, and the
+    /// changes are applied and journaled as a single transaction. Outside of
+    /// multimap tables, a part that lands exactly on the range of another
+    /// object or part would replace it, so this fails with
+    /// `DBError::Overlap` and leaves the table unchanged.
+    #[must_use]
+    pub fn punch_hole_with<F>(&mut self, table: &String, r: Range, mut split: F) -> Result<(), DBError>
+        where F: FnMut(Range, &Object, Range) -> Object {
+        let affected = match self.query_object_values(table, r) {
+            Some(iter) => try!(iter.collect::<Result<Vec<(Range, Vec<Object>)>, DBError>>()),
+            None => return Ok(()),
+        };
+        let multimap = self.is_multimap(table);
+        let mut parts: Vec<(Range, Object)> = vec![];
+        for &(rng, ref values) in &affected {
+            for part in remaining_parts(rng, r) {
+                // parts never intersect `r`, so they can only collide with
+                // objects that are kept or with parts of other objects
+                if !multimap && (parts.iter().any(|&(p, _)| p == part) || try!(self.backend.get_object(table, part)).is_some()) {
+                    return Err(DBError::Overlap{range: part, existing: part});
+                }
+                for value in values {
+                    parts.push((part, split(rng, value, part)));
+                }
+            }
+        }
+
+        return self.transaction(|db| {
+            for &(rng, _) in &affected {
+                try!(db.delete_object(table, rng));
+            }
+            for (part, value) in parts {
+                try!(db.insert_object(table, part, value));
+            }
+            return Ok(());
+        });
+    }
+}
+
+#[cfg(test)]
+fn object_contents(db: &DB, tbl: &String) -> Vec<(Range, Vec<Object>)> {
+    return db.query_object_values(tbl, Range::new(0, 100)).unwrap().map(|e| e.unwrap()).collect();
+}
+
+#[test]
+fn test_remaining_parts() {
+    assert_eq!(remaining_parts(Range::new(0, 10), Range::new(3, 4)), vec![Range::new(0, 2), Range::new(5, 10)]);
+    assert_eq!(remaining_parts(Range::new(0, 10), Range::new(0, 4)), vec![Range::new(5, 10)]);
+    assert_eq!(remaining_parts(Range::new(5, 10), Range::new(0, 7)), vec![Range::new(8, 10)]);
+    assert_eq!(remaining_parts(Range::new(5, 10), Range::new(0, 10)), vec![]);
+    assert_eq!(remaining_parts(Range::new(0, ::std::u64::MAX), Range::new(0, ::std::u64::MAX)), vec![]);
+}
+
+#[test]
+fn test_punch_hole() {
+    let (foo, bar) = ("foo".to_string(), "bar".to_string());
+    let mut db = DB::new();
+    db.insert_object(&foo, Range::new(0, 9), Object::new(vec![1])).unwrap();
+    db.insert_object(&foo, Range::new(20, 29), Object::new(vec![2])).unwrap();
+    db.insert_object(&foo, Range::new(40, 49), Object::new(vec![3])).unwrap();
+    db.punch_hole(&foo, Range::new(5, 24)).unwrap();
+    assert_eq!(object_contents(&db, &foo), vec![(Range::new(0, 4), vec![Object::new(vec![1])]),
+                                                (Range::new(25, 29), vec![Object::new(vec![2])]),
+                                                (Range::new(40, 49), vec![Object::new(vec![3])])]);
+
+    // one byte of payload per address
+    db.insert_object(&bar, Range::new(10, 15), Object::new(vec![10, 11, 12, 13, 14, 15])).unwrap();
+    db.punch_hole_with(&bar, Range::new(12, 13), |rng, value, part| {
+        Object::new(value.data[(part.min - rng.min) as usize..(part.max - rng.min + 1) as usize].to_vec())
+    }).unwrap();
+    assert_eq!(object_contents(&db, &bar), vec![(Range::new(10, 11), vec![Object::new(vec![10, 11])]),
+                                                (Range::new(14, 15), vec![Object::new(vec![14, 15])])]);
+
+    // every value of a multimap table is kept
+    let baz = "baz".to_string();
+    db.set_table_options(&baz, TableOptions{multimap: true, ..TableOptions::default()}).unwrap();
+    db.insert_object(&baz, Range::new(0, 9), Object::new(vec![1])).unwrap();
+    db.insert_object(&baz, Range::new(0, 9), Object::new(vec![2])).unwrap();
+    db.punch_hole(&baz, Range::new(0, 4)).unwrap();
+    assert_eq!(object_contents(&db, &baz), vec![(Range::new(5, 9), vec![Object::new(vec![1]), Object::new(vec![2])])]);
+
+    // a part that would replace another object is rejected
+    let qux = "qux".to_string();
+    db.insert_object(&qux, Range::new(0, 9), Object::new(vec![1])).unwrap();
+    db.insert_object(&qux, Range::new(0, 12), Object::new(vec![2])).unwrap();
+    let before = object_contents(&db, &qux);
+    match db.punch_hole(&qux, Range::new(10, 12)) {
+        Err(DBError::Overlap{range, ..}) => assert_eq!(range, Range::new(0, 9)),
+        _ => panic!("punched part replaced another object"),
+    }
+    assert_eq!(object_contents(&db, &qux), before);
+
+    // as is a part that would replace a part of another object
+    db.delete_object(&qux, Range::new(0, 9)).unwrap();
+    db.insert_object(&qux, Range::new(0, 15), Object::new(vec![3])).unwrap();
+    assert!(db.punch_hole(&qux, Range::new(10, 15)).is_err());
+    assert_eq!(object_contents(&db, &qux).len(), 2);
+}
+
+#[test]
+fn test_punch_hole_replay() {
+    let (snapshot, journal) = temp_paths("punch");
+    let foo = "foo".to_string();
+    let contents = {
+        let mut db = DB::open_journaled(&snapshot, &journal).unwrap();
+        db.set_table_options(&foo, TableOptions{coalesce: true, ..TableOptions::default()}).unwrap();
+        db.insert_object(&foo, Range::new(0, 9), Object::new(vec![1])).unwrap();
+        db.insert_object(&foo, Range::new(10, 19), Object::new(vec![2])).unwrap();
+        // the remaining part holds the data of its neighbour and is merged with it
+        db.punch_hole_with(&foo, Range::new(15, 19), |_, _, _| Object::new(vec![1])).unwrap();
+        db.sync_journal().unwrap();
+        object_contents(&db, &foo)
+    };
+    assert_eq!(contents, vec![(Range::new(0, 14), vec![Object::new(vec![1])])]);
+    let db = DB::open_journaled(&snapshot, &journal).unwrap();
+    assert_eq!(object_contents(&db, &foo), contents);
+    // nothing was checkpointed, so there is no snapshot
+    assert!(::std::fs::metadata(&snapshot).is_err());
+    ::std::fs::remove_file(&journal).unwrap();
+}
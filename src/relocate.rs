@@ -0,0 +1,193 @@
+use memrange::Range;
+
+use std::collections::BTreeSet;
+use std::u64;
+
+use db::DB;
+use backend::StorageBackend;
+use content::Object;
+use dberror::DBError;
+use punch::remaining_parts;
+
+#[cfg(test)] use content::Bitmap;
+#[cfg(test)] use backend::VecBackend;
+#[cfg(test)] use table_options::{TableOptions, OverlapPolicy};
+
+/// `r` moved by `delta`.
+fn shift(r: Range, delta: i64) -> Result<Range, DBError> {
+    let offset = delta.unsigned_abs();
+    let moved = if delta >= 0 {
+        r.min.checked_add(offset).and_then(|min| r.max.checked_add(offset).map(|max| (min, max)))
+    } else {
+        r.min.checked_sub(offset).and_then(|min| r.max.checked_sub(offset).map(|max| (min, max)))
+    };
+    return match moved {
+        Some((min, max)) => Ok(Range::new(min, max)),
+        None => Err(DBError::RangeOverflow{range: r, delta: delta}),
+    };
+}
+
+impl<B: StorageBackend> DB<B> {
+    /// Moves everything stored in `src` by `delta`. Objects and bitmaps that
+    /// only partially lie within `src` are split, the part outside of `src`
+    /// stays where it is; split objects keep a copy of their payload in both
+    /// parts. Moved objects are inserted with `insert_object`, so the policies
+    /// of the table apply at the new location, moved bitmaps are merged with
+    /// their new neighbours.
+    ///
+    /// Fails without changing anything if a range would leave the u64 address
+    /// space or an insert fails. Outside of multimap tables it also fails with
+    /// `DBError::Overlap` if two objects would end up at the same range. The
+    /// move is journaled as a single record.
+    #[must_use]
+    pub fn shift_range(&mut self, table: &String, src: Range, delta: i64) -> Result<(), DBError> {
+        if !self.has_table(table) {
+            return Err(DBError::NoSuchTable(table.clone()));
+        }
+        let objects = try!(self.query_object_values(table, src).into_iter().flatten().collect::<Result<Vec<(Range, Vec<Object>)>, DBError>>());
+        let mut bitmaps = vec![];
+        for entry in self.query_bitmap(table, src).into_iter().flatten() {
+            let (rng, slice) = try!(entry);
+            bitmaps.push((rng, slice.to_bitmap()));
+        }
+        let mut moved_objects = vec![];
+        for &(rng, ref values) in &objects {
+            moved_objects.push((try!(shift(rng.get_intersection(&src), delta)), values.clone()));
+        }
+        let mut moved_bitmaps = vec![];
+        for &(rng, ref bitmap) in &bitmaps {
+            moved_bitmaps.push((try!(shift(rng, delta)), bitmap.clone()));
+        }
+        if delta == 0 {
+            return Ok(());
+        }
+        if !self.is_multimap(table) {
+            // objects at the same range replace each other, so no two of
+            // the ranges stored by the move may be equal, nor may one of them
+            // be the range of an object that is not moved
+            let mut targets = BTreeSet::new();
+            let parts = objects.iter().flat_map(|&(rng, _)| remaining_parts(rng, src));
+            for rng in parts.chain(moved_objects.iter().map(|&(rng, _)| rng)) {
+                if !targets.insert((rng.min, rng.max)) ||
+                   (!objects.iter().any(|&(obj, _)| obj == rng) && try!(self.backend.get_object(table, rng)).is_some()) {
+                    return Err(DBError::Overlap{range: rng, existing: rng});
+                }
+            }
+        }
+
+        return self.transaction(|db| {
+            for &(rng, _) in &objects {
+                try!(db.delete_object(table, rng));
+            }
+            for (rng, values) in objects {
+                for part in remaining_parts(rng, src) {
+                    for value in &values {
+                        try!(db.insert_object(table, part, value.clone()));
+                    }
+                }
+            }
+            for (rng, values) in moved_objects {
+                for value in values {
+                    try!(db.insert_object(table, rng, value));
+                }
+            }
+
+            let entry_sizes = bitmaps.iter().map(|&(_, ref bitmap)| bitmap.entry_size).collect::<BTreeSet<u64>>();
+            for entry_size in entry_sizes {
+                try!(db.delete_bitmap(table, entry_size, src));
+            }
+            for (rng, bitmap) in moved_bitmaps {
+                try!(db.insert_bitmap(table, rng, bitmap));
+            }
+            return Ok(());
+        });
+    }
+
+    /// Moves the whole contents of `table` by `delta`, see `shift_range`.
+    #[must_use]
+    pub fn rebase(&mut self, table: &String, delta: i64) -> Result<(), DBError> {
+        return self.shift_range(table, Range::new(0, u64::MAX), delta);
+    }
+}
+
+#[cfg(test)]
+fn table_contents<B: StorageBackend>(db: &DB<B>, tbl: &String) -> (Vec<(Range, Object)>, Vec<(Range, Bitmap)>) {
+    let objects = db.query_object(tbl, Range::new(0, u64::MAX)).unwrap().map(|e| e.map(|(r, d)| (r, d.into_owned())).unwrap()).collect();
+    let bitmaps = db.query_bitmap(tbl, Range::new(0, u64::MAX)).unwrap().map(|e| e.map(|(r, b)| (r, b.to_bitmap())).unwrap()).collect();
+    return (objects, bitmaps);
+}
+
+#[test]
+fn test_shift() {
+    assert_eq!(shift(Range::new(10, 20), -10).unwrap(), Range::new(0, 10));
+    assert_eq!(shift(Range::new(10, 20), i64::MAX).unwrap(), Range::new(10 + i64::MAX as u64, 20 + i64::MAX as u64));
+    assert!(shift(Range::new(10, 20), -11).is_err());
+    assert!(shift(Range::new(10, u64::MAX - 1), 2).is_err());
+    assert!(shift(Range::new(1 << 63, 1 << 63), i64::MIN).is_ok());
+}
+
+#[test]
+fn test_shift_range() {
+    let foo = "foo".to_string();
+    let mut db = DB::new();
+    db.insert_object(&foo, Range::new(0, 9), Object::new(vec![1])).unwrap();
+    db.insert_object(&foo, Range::new(12, 13), Object::new(vec![2])).unwrap();
+    db.insert_object(&foo, Range::new(30, 31), Object::new(vec![3])).unwrap();
+    db.insert_bitmap(&foo, Range::new(5, 14), Bitmap::new(1, (5..15).collect())).unwrap();
+    db.insert_bitmap(&foo, Range::new(20, 21), Bitmap::new(1, vec![15, 16])).unwrap();
+
+    // moves [8, 15] right behind the bitmap at [20, 21]
+    db.shift_range(&foo, Range::new(8, 15), 14).unwrap();
+    let (objects, bitmaps) = table_contents(&db, &foo);
+    assert_eq!(objects, vec![(Range::new(0, 7), Object::new(vec![1])),
+                             (Range::new(22, 23), Object::new(vec![1])),
+                             (Range::new(26, 27), Object::new(vec![2])),
+                             (Range::new(30, 31), Object::new(vec![3]))]);
+    assert_eq!(bitmaps, vec![(Range::new(5, 7), Bitmap::new(1, vec![5, 6, 7])),
+                             (Range::new(20, 28), Bitmap::new(1, vec![15, 16, 8, 9, 10, 11, 12, 13, 14]))]);
+
+    // nothing changes if a range would overflow
+    let before = table_contents(&db, &foo);
+    match db.rebase(&foo, -6) {
+        Err(DBError::RangeOverflow{range, delta}) => assert_eq!((range, delta), (Range::new(0, 7), -6)),
+        res => panic!("unexpected result {:?}", res),
+    }
+    assert!(table_contents(&db, &foo) == before);
+
+    db.rebase(&foo, 0x1000).unwrap();
+    let (objects, bitmaps) = table_contents(&db, &foo);
+    assert_eq!(objects[0], (Range::new(0x1000, 0x1007), Object::new(vec![1])));
+    assert_eq!(bitmaps[1].0, Range::new(0x1014, 0x101c));
+    assert!(db.rebase(&"bar".to_string(), 1).is_err());
+
+    // the moved parts of two objects would land on the same range
+    let bar = "bar".to_string();
+    db.insert_object(&bar, Range::new(0, 9), Object::new(vec![1])).unwrap();
+    db.insert_object(&bar, Range::new(5, 15), Object::new(vec![2])).unwrap();
+    let before = table_contents(&db, &bar);
+    match db.shift_range(&bar, Range::new(5, 9), 100) {
+        Err(DBError::Overlap{range, ..}) => assert_eq!(range, Range::new(105, 109)),
+        res => panic!("unexpected result {:?}", res),
+    }
+    assert!(table_contents(&db, &bar) == before);
+}
+
+#[cfg(test)]
+fn check_shift_range_respects_policy<B: StorageBackend>(mut db: DB<B>) {
+    let foo = "foo".to_string();
+    db.set_table_options(&foo, TableOptions{overlap: OverlapPolicy::Reject, ..TableOptions::default()}).unwrap();
+    db.insert_object(&foo, Range::new(0, 9), Object::new(vec![1])).unwrap();
+    db.insert_object(&foo, Range::new(20, 29), Object::new(vec![2])).unwrap();
+    let before = table_contents(&db, &foo);
+    assert!(db.shift_range(&foo, Range::new(0, 9), 15).is_err());
+    assert!(table_contents(&db, &foo) == before);
+    db.shift_range(&foo, Range::new(0, 9), 10).unwrap();
+    assert_eq!(table_contents(&db, &foo).0, vec![(Range::new(10, 19), Object::new(vec![1])),
+                                                 (Range::new(20, 29), Object::new(vec![2]))]);
+}
+
+#[test]
+fn test_shift_range_respects_policy() {
+    check_shift_range_respects_policy(DB::new());
+    check_shift_range_respects_policy(DB::with_backend(VecBackend::new()));
+}
@@ -0,0 +1,82 @@
+use std::io;
+use std::io::prelude::*;
+
+/// CRC-32 (IEEE 802.3, as used by zlib and png) over the serialized tables.
+pub struct Crc32 {
+    table: [u32; 256],
+    state: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        let mut table = [0u32; 256];
+        for i in 0..256 {
+            let mut c = i as u32;
+            for _ in 0..8 {
+                c = if c & 1 != 0 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+            }
+            table[i] = c;
+        }
+        return Crc32{table: table, state: 0xffffffff};
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for b in data {
+            self.state = self.table[((self.state ^ *b as u32) & 0xff) as usize] ^ (self.state >> 8);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        return self.state ^ 0xffffffff;
+    }
+}
+
+#[cfg(test)]
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    return crc.finish();
+}
+
+/// Sink that only checksums what is written to it, used to verify tables
+/// without keeping their bytes around.
+impl Write for Crc32 {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+/// Passes writes on to `inner` while checksumming them.
+pub struct ChecksumWriter<'a> {
+    inner: &'a mut Write,
+    pub crc: Crc32,
+}
+
+impl<'a> ChecksumWriter<'a> {
+    pub fn new(inner: &'a mut Write) -> ChecksumWriter<'a> {
+        return ChecksumWriter{inner: inner, crc: Crc32::new()};
+    }
+}
+
+impl<'a> Write for ChecksumWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        self.crc.update(&buf[..n]);
+        return Ok(n);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.inner.flush();
+    }
+}
+
+#[test]
+fn test_crc32() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xcbf43926);
+}
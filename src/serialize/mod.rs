@@ -1,55 +1,140 @@
 extern crate rmp;
-extern crate theban_interval_tree;
 extern crate memrange;
 
+pub mod checksum;
+mod reader;
+mod stream;
+
 use std::collections::BTreeMap;
 use std::u64;
 use std::u32;
 
 use std::io::prelude::*;
-use std::io::Cursor;
+use std::io::{Cursor, BufReader, SeekFrom};
 use std::fs::File;
+use std::path::Path;
 
 
 use db::DB;
 use content::Bitmap;
 use content::Object;
 use dberror::DBError;
+use table_options::{TableOptions, OverlapPolicy};
+use atomic_file;
 use memrange::Range;
-use self::theban_interval_tree::IntervalTree;
+use persistent_tree::PersistentTree;
 use std::fmt::Debug;
+use self::checksum::{Crc32, ChecksumWriter};
+#[cfg(test)] use self::checksum::crc32;
+use bulk_load::{check_sorted, balanced_tree};
+pub use self::reader::{DBReader, ReadLimits};
+pub use self::stream::{DBStream, Record};
+
+/// Every file written by `DB::write` starts with these bytes, followed by
+/// the format version (u16, big endian) and the feature flags (u32, big endian).
+const MAGIC: &'static [u8; 8] = b"THEBANDB";
+const FORMAT_VERSION: u16 = 3;
+const FEATURE_FLAGS: u32 = FEATURE_TABLE_CHECKSUMS | FEATURE_TABLE_DIRECTORY;
+const SUPPORTED_FEATURE_FLAGS: u32 = FEATURE_TABLE_CHECKSUMS | FEATURE_TABLE_DIRECTORY | FEATURE_TABLE_OPTIONS | FEATURE_JOURNAL_GENERATION;
+
+/// Version 1 stores the two table maps as plain msgpack. Starting with
+/// version 2 every table is a frame: its name, the u64 length of the
+/// serialized tree, the tree itself and, if `FEATURE_TABLE_CHECKSUMS` is set,
+/// the CRC-32 of the tree.
+const FIRST_FRAMED_VERSION: u16 = 2;
+
+/// Starting with version 3 a table is its name followed by the tree in
+/// chunks, so it can be written in a single pass. Each chunk is a binary blob
+/// holding a (min, max, data) array of up to `CHUNK_ENTRIES` entries, an
+/// empty blob ends the tree. The trailer is the u64 number of entries and, if
+/// `FEATURE_TABLE_CHECKSUMS` is set, the CRC-32 of all chunks.
+const FIRST_CHUNKED_VERSION: u16 = 3;
+const CHUNK_ENTRIES: u32 = 4096;
+const CHUNK_BYTES: usize = 1 << 20;
+/// Chunk lengths are stored as u32.
+const MAX_CHUNK_LEN: u64 = u32::MAX as u64;
+
+/// Chunked files with this flag end with a directory of the byte offsets of
+/// all trees: a msgpack array of two maps from table name to offset, one for
+/// the objects and one for the bitmaps. It is followed by the offset of the
+/// directory itself (u64, big endian) and `DIRECTORY_MAGIC`.
+const FEATURE_TABLE_DIRECTORY: u32 = 0x2;
+const DIRECTORY_MAGIC: &'static [u8; 8] = b"THEBADIR";
+const FEATURE_TABLE_CHECKSUMS: u32 = 0x1;
+/// The directory of files with this flag has a third element, a map from
+/// table name to the `TableOptions` of every table that has options set.
+const FEATURE_TABLE_OPTIONS: u32 = 0x4;
+/// The last element of the directory of files with this flag is the
+/// generation of the journal whose records the file already contains, see
+/// `DB::checkpoint`.
+const FEATURE_JOURNAL_GENERATION: u32 = 0x8;
+
+/// Files written before the header was introduced start directly with the
+/// msgpack marker of a fixarray of length 2.
+const LEGACY_MARKER: u8 = 0x92;
+
+pub const SECTION_NAMES: [&'static str; 2] = ["objects", "bitmaps"];
+
+struct FileHeader {
+    version: u16,
+    flags: u32,
+}
 
-trait Serialized where Self:Sized{
+pub trait Serialized where Self:Sized{
     fn write<'a>(&self, mut w: &mut Write) -> Result<(), DBError>;
-    fn read<'a>(mut r: &mut Read) -> Result<Self, DBError>;
+    fn read<'a>(r: &mut DBReader) -> Result<Self, DBError>;
+
+    /// Checks that a value read from a file is consistent with its range.
+    fn validate(&self, _range: Range) -> Result<(), DBError> {
+        return Ok(());
+    }
 }
 
-fn write_vec<'a>(vec: &Vec<u8>, mut w: &mut Write) -> Result<(), DBError> {
+pub fn write_vec<'a>(vec: &Vec<u8>, mut w: &mut Write) -> Result<(), DBError> {
     try!(rmp::encode::write_str_len(&mut w, vec.len() as u32));
     try!(w.write_all(vec));
     return Ok(());
 }
 
-fn parse_string<'a>(stream: &mut Read) -> Result<String, DBError> {
+pub fn write_string<'a>(s: &String, mut w: &mut Write) -> Result<(), DBError> {
+    try!(rmp::encode::write_str_len(&mut w, s.len() as u32));
+    try!(w.write_all(s.as_ref()));
+    return Ok(());
+}
+
+pub fn write_range<'a>(range: Range, mut w: &mut Write) -> Result<(), DBError> {
+    try!(rmp::encode::write_uint(&mut w, range.min));
+    try!(rmp::encode::write_uint(&mut w, range.max));
+    return Ok(());
+}
+
+pub fn parse_string<'a>(stream: &mut DBReader) -> Result<String, DBError> {
     let strref = try!(parse_bindata(stream));
     let strval = try!(String::from_utf8(strref));
     return Ok(strval);
 }
 
-fn parse_bindata<'a>(mut stream: &mut Read) -> Result<Vec<u8>, DBError> {
-    let len = try!(rmp::decode::read_str_len(&mut stream));
-    let ulen = len as usize;
-    let mut buf: Vec<u8> = vec![0;ulen];
-    let n = try!(rmp::decode::read_full(&mut stream, &mut buf[0..ulen]));
-    if n != ulen {
-        return Err(DBError::FileFormat("unexpected EOF".into()));
+pub fn parse_bindata<'a>(stream: &mut DBReader) -> Result<Vec<u8>, DBError> {
+    let len = try!(rmp::decode::read_str_len(stream)) as u64;
+    if len > stream.limits.max_blob_size {
+        return Err(stream.error(format!("blob of {} bytes exceeds the limit of {}", len, stream.limits.max_blob_size)));
+    }
+    // the buffer only grows with the data that is actually there, the length
+    // alone does not make us allocate anything
+    let mut buf: Vec<u8> = vec![];
+    try!(Read::take(&mut *stream, len).read_to_end(&mut buf));
+    if buf.len() as u64 != len {
+        return Err(stream.error("unexpected EOF"));
     }
     return Ok(buf);
 }
 
-fn parse_range<'a>(mut r: &mut Read) -> Result<Range, DBError> {
-    let min = try!(rmp::decode::read_u64_loosely(&mut r));
-    let max = try!(rmp::decode::read_u64_loosely(&mut r));
+pub fn parse_range<'a>(r: &mut DBReader) -> Result<Range, DBError> {
+    let min = try!(rmp::decode::read_u64_loosely(r));
+    let max = try!(rmp::decode::read_u64_loosely(r));
+    if min > max {
+        return Err(r.error(format!("invalid range [{}, {}]", min, max)));
+    }
     return Ok(Range::new(min, max));
 }
 
@@ -61,16 +146,26 @@ impl Serialized for Bitmap {
         return Ok(())
     }
 
-    fn read<'a>(mut r: &mut Read) -> Result<Self, DBError> {
-        let len = try!(rmp::decode::read_array_size(&mut r));
+    fn read<'a>(r: &mut DBReader) -> Result<Self, DBError> {
+        r.push_field("bitmap");
+        let len = try!(rmp::decode::read_array_size(r));
         if len != 2 {
-            return Err(DBError::FileFormat("BitMap should have length 2".into()));
+            return Err(r.error("BitMap should have length 2"));
         }
-        let ds = try!(rmp::decode::read_u64_loosely(&mut r));
-        let vec = try!(parse_bindata(&mut r));
+        r.push_field("entry_size");
+        let ds = try!(rmp::decode::read_u64_loosely(r));
+        r.pop_path();
+        r.push_field("data");
+        let vec = try!(parse_bindata(r));
+        r.pop_path();
+        r.pop_path();
         let data = Bitmap{entry_size: ds, data:vec};
         return Ok( data );
     }
+
+    fn validate(&self, range: Range) -> Result<(), DBError> {
+        return self.check_length(range);
+    }
 }
 
 impl Serialized for Object {
@@ -79,81 +174,711 @@ impl Serialized for Object {
         return Ok(())
     }
 
-    fn read<'a>(mut r: &mut Read) -> Result<Self, DBError> {
-        let vec = try!(parse_bindata(&mut r));
+    fn read<'a>(r: &mut DBReader) -> Result<Self, DBError> {
+        r.push_field("object.data");
+        let vec = try!(parse_bindata(r));
+        r.pop_path();
         let data = Object{data: vec};
         return Ok( data );
     }
 
 }
 
-impl<T: Serialized> Serialized for IntervalTree<T> {
+impl Serialized for TableOptions {
+    /// A map from option name to value, options with their default value
+    /// are left out.
+    fn write<'a>(&self, mut w: &mut Write) -> Result<(), DBError> {
+        let mut values = vec![];
+        if self.multimap {
+            values.push(("multimap", 1));
+        }
+        if self.coalesce {
+            values.push(("coalesce", 1));
+        }
+        match self.overlap {
+            OverlapPolicy::Allow => {},
+            OverlapPolicy::Reject => values.push(("overlap", 1)),
+            OverlapPolicy::Evict => values.push(("overlap", 2)),
+        }
+        try!(rmp::encode::write_map_len(&mut w, values.len() as u32));
+        for (name, value) in values {
+            try!(write_string(&name.to_string(), &mut w));
+            try!(rmp::encode::write_uint(&mut w, value));
+        }
+        return Ok(());
+    }
+
+    fn read<'a>(r: &mut DBReader) -> Result<Self, DBError> {
+        r.push_field("options");
+        let len = try!(rmp::decode::read_map_size(r));
+        let mut options = TableOptions::default();
+        for _ in 0..len {
+            let name = try!(parse_string(r));
+            r.push_field(&name);
+            let value = try!(rmp::decode::read_u64_loosely(r));
+            match &name[..] {
+                "multimap" => options.multimap = value != 0,
+                "coalesce" => options.coalesce = value != 0,
+                "overlap" => options.overlap = match value {
+                    0 => OverlapPolicy::Allow,
+                    1 => OverlapPolicy::Reject,
+                    2 => OverlapPolicy::Evict,
+                    _ => return Err(r.error(format!("unknown overlap policy {}", value))),
+                },
+                _ => return Err(r.error(format!("unknown table option {}", name))),
+            }
+            r.pop_path();
+        }
+        r.pop_path();
+        return Ok(options);
+    }
+}
+
+impl<T: Serialized> Serialized for PersistentTree<T> {
     fn write<'a>(&self, mut w: &mut Write) -> Result<(), DBError> {
-        let len = self.range(0, u64::MAX).count() as u64;
-        assert!(3*len < u32::MAX as u64);
+        let len = self.len() as u64;
+        if 3*len > u32::MAX as u64 {
+            return Err(DBError::FileFormat(format!("{} entries do not fit into a single tree array", len)));
+        }
         try!(rmp::encode::write_array_len(&mut w, 3*len as u32));
         for (range, data) in self.range(0, u64::MAX) {
-            try!(rmp::encode::write_uint(&mut w, range.min));
-            try!(rmp::encode::write_uint(&mut w, range.max));
+            try!(write_range(range, &mut w));
             try!(data.write(&mut w));
         }
         return Ok(())
     }
 
-    fn read<'a>(mut r: &mut Read) -> Result<Self, DBError> {
-        let len = try!(rmp::decode::read_array_size(&mut r));
-        let mut tree = IntervalTree::new();
-        for _ in 0..(len/3) {
-            let rng = try!(parse_range(&mut r));
-            let data = try!(T::read(&mut r));
-            tree.insert(rng, data);
+    fn read<'a>(r: &mut DBReader) -> Result<Self, DBError> {
+        let mut entries = vec![];
+        try!(read_tree_entries(r, &mut entries));
+        return Ok( balanced_tree(entries) );
+    }
+}
+
+/// Reads one (min, max, data) array and appends its entries to `entries`.
+fn read_tree_entries<'a, T: Serialized>(r: &mut DBReader, entries: &mut Vec<(Range, T)>) -> Result<(), DBError> {
+    let len = try!(rmp::decode::read_array_size(r));
+    if len % 3 != 0 {
+        return Err(r.error(format!("tree length {} is not a multiple of 3", len)));
+    }
+    let mut previous = entries.last().map(|&(rng, _)| rng);
+    for _ in 0..(len/3) {
+        r.push_index(entries.len() as u64);
+        r.push_field("range");
+        let rng = try!(parse_range(r));
+        try!(check_sorted(previous, rng));
+        r.pop_path();
+        let data = try!(T::read(r));
+        try!(data.validate(rng));
+        r.pop_path();
+        previous = Some(rng);
+        entries.push((rng, data));
+    }
+    return Ok(());
+}
+
+fn write_chunk<'a>(entries: u32, body: &[u8], mut w: &mut Write) -> Result<(), DBError> {
+    let mut head = vec![];
+    try!(rmp::encode::write_array_len(&mut head, 3*entries));
+    let len = head.len() as u64 + body.len() as u64;
+    if len > MAX_CHUNK_LEN {
+        return Err(DBError::Protocol(format!("chunk of {} bytes exceeds the maximal chunk length", len)));
+    }
+    try!(rmp::encode::write_str_len(&mut w, len as u32));
+    try!(w.write_all(&head));
+    try!(w.write_all(body));
+    return Ok(());
+}
+
+/// Writes `tree` in the chunked layout of `FIRST_CHUNKED_VERSION`.
+fn write_chunked_tree<'a, T: Serialized>(tree: &PersistentTree<T>, mut w: &mut Write) -> Result<(), DBError> {
+    let mut total = 0u64;
+    let checksum = {
+        let mut cw = ChecksumWriter::new(w);
+        let mut entries = 0;
+        let mut body = vec![];
+        for (range, data) in tree.range(0, u64::MAX) {
+            let mark = body.len();
+            try!(write_range(range, &mut body));
+            try!(data.write(&mut body));
+            // an entry that would push the chunk past its length limit
+            // starts a chunk of its own
+            if entries > 0 && body.len() as u64 > MAX_CHUNK_LEN - 5 {
+                try!(write_chunk(entries, &body[..mark], &mut cw));
+                body.drain(..mark);
+                entries = 0;
+            }
+            entries += 1;
+            total += 1;
+            if entries == CHUNK_ENTRIES || body.len() >= CHUNK_BYTES {
+                try!(write_chunk(entries, &body, &mut cw));
+                entries = 0;
+                body.clear();
+            }
+        }
+        if entries > 0 {
+            try!(write_chunk(entries, &body, &mut cw));
+        }
+        try!(rmp::encode::write_str_len(&mut cw, 0));
+        cw.crc.finish()
+    };
+    try!(rmp::encode::write_u64(&mut w, total));
+    try!(rmp::encode::write_u32(&mut w, checksum));
+    return Ok(());
+}
+
+fn read_chunked_tree<'a, T: Serialized>(r: &mut DBReader, header: &FileHeader, tbl_name: &String) -> Result<PersistentTree<T>, DBError> {
+    let mut entries = vec![];
+    r.start_checksum();
+    loop {
+        let chunk_len = try!(rmp::decode::read_str_len(r)) as u64;
+        if chunk_len == 0 {
+            break;
         }
-        return Ok( tree );
+        let start = r.position();
+        try!(read_tree_entries(r, &mut entries));
+        if r.position() - start != chunk_len {
+            return Err(r.error(format!("chunk does not match its length {}", chunk_len)));
+        }
+    }
+    let checksum = r.finish_checksum();
+    r.push_field("count");
+    let count = try!(rmp::decode::read_u64(r));
+    if count != entries.len() as u64 {
+        return Err(r.error(format!("table has {} entries instead of {}", entries.len(), count)));
     }
+    r.pop_path();
+    if header.flags & FEATURE_TABLE_CHECKSUMS != 0 {
+        r.push_field("checksum");
+        let expected = try!(rmp::decode::read_u32(r));
+        if checksum != expected {
+            return Err(r.error(format!("checksum mismatch in table {}", tbl_name)));
+        }
+        r.pop_path();
+    }
+    return Ok(balanced_tree(entries));
 }
 
-impl<T: Serialized+Debug> Serialized for BTreeMap<String, IntervalTree<T>> {
+impl<T: Serialized+Debug> Serialized for BTreeMap<String, PersistentTree<T>> {
     fn write<'a>(&self, mut w: &mut Write) -> Result<(), DBError> {
         try!(rmp::encode::write_map_len(&mut w, self.len() as u32));
         for (name, tree) in self {
-            try!(rmp::encode::write_str_len(&mut w, name.len() as u32));
-            try!(w.write_all(&name.as_ref()));
+            try!(write_string(name, &mut w));
             try!(tree.write(&mut w))
         }
     return Ok(())
     }
 
-    fn read<'a>(mut r: &mut Read) -> Result<Self, DBError> {
-        let len = try!(rmp::decode::read_map_size(&mut r));
+    fn read<'a>(r: &mut DBReader) -> Result<Self, DBError> {
+        let len = try!(rmp::decode::read_map_size(r));
+        try!(r.count_tables(len as u64));
         let mut res = BTreeMap::new();
-        for _ in 0..len {
-            let tbl_name = try!(parse_string(&mut r));
-            let tree = try!(IntervalTree::<T>::read(&mut r));
+        for i in 0..len {
+            let tbl_name = try!(parse_table_name(r, i));
+            r.push_table(&tbl_name);
+            let tree = try!(PersistentTree::<T>::read(r));
+            r.pop_path();
             res.insert(tbl_name, tree);
         }
         return Ok( res );
     }
 }
 
+pub fn parse_table_name<'a>(r: &mut DBReader, index: u32) -> Result<String, DBError> {
+    r.push_index(index as u64);
+    r.push_field("name");
+    let name = try!(parse_string(r));
+    r.pop_path();
+    r.pop_path();
+    return Ok(name);
+}
+
+fn read_header_bytes(r: &mut DBReader, buf: &mut [u8]) -> Result<(), DBError> {
+    let n = try!(rmp::decode::read_full(r, buf));
+    if n != buf.len() {
+        return Err(r.error("not a theban db"));
+    }
+    return Ok(());
+}
+
+impl FileHeader {
+    fn current() -> FileHeader {
+        return FileHeader{version: FORMAT_VERSION, flags: FEATURE_FLAGS};
+    }
+
+    fn write<'a>(&self, w: &mut Write) -> Result<(), DBError> {
+        try!(w.write_all(MAGIC));
+        try!(w.write_all(&[(self.version >> 8) as u8, self.version as u8]));
+        try!(w.write_all(&[(self.flags >> 24) as u8, (self.flags >> 16) as u8, (self.flags >> 8) as u8, self.flags as u8]));
+        return Ok(());
+    }
+
+    /// Reads the remainder of the header after `first` has already been
+    /// consumed from the stream.
+    fn read_after<'a>(first: u8, r: &mut DBReader) -> Result<FileHeader, DBError> {
+        let mut magic = [0u8; 8];
+        magic[0] = first;
+        r.push_field("magic");
+        try!(read_header_bytes(r, &mut magic[1..]));
+        if &magic != MAGIC {
+            return Err(r.error("not a theban db"));
+        }
+        r.pop_path();
+        let mut fields = [0u8; 6];
+        r.push_field("version");
+        try!(read_header_bytes(r, &mut fields[0..2]));
+        let version = (fields[0] as u16) << 8 | fields[1] as u16;
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(r.error(format!("unsupported version {}", version)));
+        }
+        r.pop_path();
+        r.push_field("flags");
+        try!(read_header_bytes(r, &mut fields[2..6]));
+        let flags = (fields[2] as u32) << 24 | (fields[3] as u32) << 16 | (fields[4] as u32) << 8 | fields[5] as u32;
+        if flags & !SUPPORTED_FEATURE_FLAGS != 0 {
+            return Err(r.error(format!("unsupported feature flags {:#x}", flags & !SUPPORTED_FEATURE_FLAGS)));
+        }
+        if flags & FEATURE_TABLE_OPTIONS != 0 && (version < FIRST_CHUNKED_VERSION || flags & FEATURE_TABLE_DIRECTORY == 0) {
+            return Err(r.error("table options require a table directory"));
+        }
+        if flags & FEATURE_JOURNAL_GENERATION != 0 && (version < FIRST_CHUNKED_VERSION || flags & FEATURE_TABLE_DIRECTORY == 0) {
+            return Err(r.error("a journal generation requires a table directory"));
+        }
+        r.pop_path();
+        return Ok(FileHeader{version: version, flags: flags});
+    }
+}
+
+#[cfg(test)]
+fn write_tables<'a>(db: &DB, mut w: &mut Write) -> Result<(), DBError> {
+    try!(rmp::encode::write_array_len(&mut w, 2 as u32));
+    try!(db.backend.obj_map.write(&mut w));
+    try!(db.backend.bit_map.write(&mut w));
+    return Ok(());
+}
+
+/// Reads both table maps, the array marker in front of them has already been consumed.
+fn read_tables<'a>(r: &mut DBReader) -> Result<DB, DBError> {
+    r.push_field("objects");
+    let objects = try!(BTreeMap::<String, PersistentTree<Object>>::read(r));
+    r.pop_path();
+    r.push_field("bitmaps");
+    let bitmaps = try!(BTreeMap::<String, PersistentTree<Bitmap>>::read(r));
+    r.pop_path();
+    return Ok( DB::new_from_shared_data(objects,bitmaps) );
+}
+
+#[cfg(test)]
+fn write_framed_tables<'a, T: Serialized>(map: &BTreeMap<String, PersistentTree<T>>, mut w: &mut Write) -> Result<(), DBError> {
+    try!(rmp::encode::write_map_len(&mut w, map.len() as u32));
+    for (name, tree) in map {
+        let mut frame = vec![];
+        try!(tree.write(&mut frame));
+        try!(write_string(name, &mut w));
+        try!(rmp::encode::write_u64(&mut w, frame.len() as u64));
+        try!(w.write_all(&frame));
+        try!(rmp::encode::write_u32(&mut w, crc32(&frame)));
+    }
+    return Ok(());
+}
+
+fn read_framed_tables<'a, T: Serialized>(r: &mut DBReader, header: &FileHeader) -> Result<BTreeMap<String, PersistentTree<T>>, DBError> {
+    let len = try!(rmp::decode::read_map_size(r));
+    try!(r.count_tables(len as u64));
+    let mut res = BTreeMap::new();
+    for i in 0..len {
+        let tbl_name = try!(parse_table_name(r, i));
+        r.push_table(&tbl_name);
+        r.push_field("frame_len");
+        let frame_len = try!(rmp::decode::read_u64(r));
+        r.pop_path();
+        let start = r.position();
+        r.start_checksum();
+        let tree = try!(PersistentTree::<T>::read(r));
+        let checksum = r.finish_checksum();
+        if r.position() - start != frame_len {
+            return Err(r.error(format!("table {} does not match its frame length {}", tbl_name, frame_len)));
+        }
+        if header.flags & FEATURE_TABLE_CHECKSUMS != 0 {
+            r.push_field("checksum");
+            let expected = try!(rmp::decode::read_u32(r));
+            if checksum != expected {
+                return Err(r.error(format!("checksum mismatch in table {}", tbl_name)));
+            }
+            r.pop_path();
+        }
+        r.pop_path();
+        res.insert(tbl_name, tree);
+    }
+    return Ok( res );
+}
+
+/// Keeps track of the number of bytes written, so the table directory can
+/// record where each tree starts.
+struct CountingWriter<'a> {
+    inner: &'a mut Write,
+    count: u64,
+}
+
+impl<'a> Write for CountingWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        self.count += n as u64;
+        return Ok(n);
+    }
+
+    fn flush(&mut self) -> ::std::io::Result<()> {
+        return self.inner.flush();
+    }
+}
+
+/// Returns the offset of every tree written.
+fn write_chunked_tables<'a, T: Serialized>(map: &BTreeMap<String, PersistentTree<T>>, w: &mut CountingWriter) -> Result<BTreeMap<String, u64>, DBError> {
+    let mut offsets = BTreeMap::new();
+    try!(rmp::encode::write_map_len(w, map.len() as u32));
+    for (name, tree) in map {
+        try!(write_string(name, w));
+        offsets.insert(name.clone(), w.count);
+        try!(write_chunked_tree(tree, w));
+    }
+    return Ok(offsets);
+}
+
+fn write_directory<'a>(flags: u32, sections: &[BTreeMap<String, u64>; 2], options: &BTreeMap<String, TableOptions>, generation: u64, mut w: &mut Write) -> Result<(), DBError> {
+    try!(rmp::encode::write_array_len(&mut w, directory_len(flags)));
+    for offsets in sections {
+        try!(rmp::encode::write_map_len(&mut w, offsets.len() as u32));
+        for (name, &offset) in offsets {
+            try!(write_string(name, &mut w));
+            try!(rmp::encode::write_u64(&mut w, offset));
+        }
+    }
+    if flags & FEATURE_TABLE_OPTIONS != 0 {
+        try!(rmp::encode::write_map_len(&mut w, options.len() as u32));
+        for (name, table_options) in options {
+            try!(write_string(name, &mut w));
+            try!(table_options.write(&mut w));
+        }
+    }
+    if flags & FEATURE_JOURNAL_GENERATION != 0 {
+        try!(rmp::encode::write_u64(&mut w, generation));
+    }
+    return Ok(());
+}
+
+fn directory_len(flags: u32) -> u32 {
+    let mut len = 2;
+    if flags & FEATURE_TABLE_OPTIONS != 0 {
+        len += 1;
+    }
+    if flags & FEATURE_JOURNAL_GENERATION != 0 {
+        len += 1;
+    }
+    return len;
+}
+
+fn read_directory_generation<'a>(r: &mut DBReader, flags: u32) -> Result<u64, DBError> {
+    if flags & FEATURE_JOURNAL_GENERATION == 0 {
+        return Ok(0);
+    }
+    r.push_field("generation");
+    let generation = try!(rmp::decode::read_u64(r));
+    r.pop_path();
+    return Ok(generation);
+}
+
+/// Reads the options section of the directory, all of its tables must be
+/// listed in `offsets`.
+fn read_directory_options<'a>(r: &mut DBReader, offsets: &[&BTreeMap<String, u64>; 2]) -> Result<BTreeMap<String, TableOptions>, DBError> {
+    r.push_field("options");
+    let len = try!(rmp::decode::read_map_size(r));
+    try!(r.count_tables(len as u64));
+    let mut res = BTreeMap::new();
+    for i in 0..len {
+        let name = try!(parse_table_name(r, i));
+        if !offsets.iter().any(|section| section.contains_key(&name)) {
+            return Err(r.error(format!("options for unknown table {}", name)));
+        }
+        r.push_table(&name);
+        let options = try!(TableOptions::read(r));
+        r.pop_path();
+        res.insert(name, options);
+    }
+    r.pop_path();
+    return Ok(res);
+}
+
+/// Byte offsets of the trees of a file, see `FEATURE_TABLE_DIRECTORY`.
+pub struct TableDirectory {
+    flags: u32,
+    pub objects: BTreeMap<String, u64>,
+    pub bitmaps: BTreeMap<String, u64>,
+    pub options: BTreeMap<String, TableOptions>,
+}
+
+impl TableDirectory {
+    /// Reads the directory of `file`. Returns None if the file has none.
+    pub fn read(file: &mut File) -> Result<Option<TableDirectory>, DBError> {
+        try!(file.seek(SeekFrom::Start(0)));
+        let header = {
+            let mut input = BufReader::new(&mut *file);
+            let mut r = DBReader::new(&mut input, ReadLimits::default());
+            match read_file_header(&mut r) {
+                Ok(Some(header)) => header,
+                Ok(None) => return Ok(None),
+                Err(e) => return Err(r.context(e)),
+            }
+        };
+        if header.version < FIRST_CHUNKED_VERSION || header.flags & FEATURE_TABLE_DIRECTORY == 0 {
+            return Ok(None);
+        }
+        let end = try!(file.seek(SeekFrom::End(-16)));
+        let mut footer = [0u8; 16];
+        try!(file.read_exact(&mut footer));
+        if &footer[8..16] != DIRECTORY_MAGIC {
+            return Err(DBError::FileFormat("table directory is missing".into()));
+        }
+        let offset = footer[0..8].iter().fold(0u64, |acc, &b| acc << 8 | b as u64);
+        if offset > end {
+            return Err(DBError::FileFormat(format!("table directory offset {} is out of range", offset)));
+        }
+        try!(file.seek(SeekFrom::Start(offset)));
+        let mut input = Read::take(BufReader::new(&mut *file), end - offset);
+        let mut r = DBReader::new_at(&mut input, ReadLimits::default(), offset);
+        r.push_field("directory");
+        let res = rmp::decode::read_array_size(&mut r).map_err(DBError::from).and_then(|len| {
+            if len != directory_len(header.flags) {
+                return Err(r.error(format!("table directory should have length {}", directory_len(header.flags))));
+            }
+            let objects = try!(read_directory_section(&mut r, SECTION_NAMES[0], offset));
+            let bitmaps = try!(read_directory_section(&mut r, SECTION_NAMES[1], offset));
+            let options = if header.flags & FEATURE_TABLE_OPTIONS != 0 { try!(read_directory_options(&mut r, &[&objects, &bitmaps])) } else { BTreeMap::new() };
+            try!(read_directory_generation(&mut r, header.flags));
+            return Ok(TableDirectory{flags: header.flags, objects: objects, bitmaps: bitmaps, options: options});
+        });
+        return res.map(Some).map_err(|e| r.context(e));
+    }
+
+    /// Loads the tree of table `name` in `section` ("objects" or "bitmaps").
+    pub fn read_tree<T: Serialized>(&self, file: &mut File, section: &str, name: &String, offset: u64) -> Result<PersistentTree<T>, DBError> {
+        try!(file.seek(SeekFrom::Start(offset)));
+        let mut input = BufReader::new(file);
+        let mut r = DBReader::new_at(&mut input, ReadLimits::default(), offset);
+        r.push_field(section);
+        r.push_table(name);
+        let header = FileHeader{version: FORMAT_VERSION, flags: self.flags};
+        return read_chunked_tree(&mut r, &header, name).map_err(|e| r.context(e));
+    }
+}
+
+/// Reads the table directory that follows the tables and the footer behind
+/// it, so a file that lost its end is not mistaken for a complete one.
+/// Returns the table options and the journal generation stored in the
+/// directory.
+fn read_directory_and_footer<'a>(r: &mut DBReader, header: &FileHeader) -> Result<(BTreeMap<String, TableOptions>, u64), DBError> {
+    let offset = r.position();
+    r.push_field("directory");
+    let len = try!(rmp::decode::read_array_size(r));
+    if len != directory_len(header.flags) {
+        return Err(r.error(format!("table directory should have length {}", directory_len(header.flags))));
+    }
+    let objects = try!(read_directory_section(r, SECTION_NAMES[0], offset));
+    let bitmaps = try!(read_directory_section(r, SECTION_NAMES[1], offset));
+    let options = if header.flags & FEATURE_TABLE_OPTIONS != 0 { try!(read_directory_options(r, &[&objects, &bitmaps])) } else { BTreeMap::new() };
+    let generation = try!(read_directory_generation(r, header.flags));
+    r.pop_path();
+    r.push_field("footer");
+    let mut footer = [0u8; 16];
+    if try!(rmp::decode::read_full(r, &mut footer)) != footer.len() {
+        return Err(r.error("unexpected EOF"));
+    }
+    let dir_offset = footer[0..8].iter().fold(0u64, |acc, &b| acc << 8 | b as u64);
+    if &footer[8..16] != DIRECTORY_MAGIC || dir_offset != offset {
+        return Err(r.error("invalid footer"));
+    }
+    r.pop_path();
+    return Ok((options, generation));
+}
+
+fn read_directory_section<'a>(r: &mut DBReader, section: &str, end: u64) -> Result<BTreeMap<String, u64>, DBError> {
+    r.push_field(section);
+    let len = try!(rmp::decode::read_map_size(r));
+    try!(r.count_tables(len as u64));
+    let mut offsets = BTreeMap::new();
+    for i in 0..len {
+        let name = try!(parse_table_name(r, i));
+        r.push_table(&name);
+        let offset = try!(rmp::decode::read_u64(r));
+        if offset >= end {
+            return Err(r.error(format!("table offset {} is out of range", offset)));
+        }
+        r.pop_path();
+        offsets.insert(name, offset);
+    }
+    r.pop_path();
+    return Ok(offsets);
+}
+
+fn read_chunked_tables<'a, T: Serialized>(r: &mut DBReader, header: &FileHeader) -> Result<BTreeMap<String, PersistentTree<T>>, DBError> {
+    let len = try!(rmp::decode::read_map_size(r));
+    try!(r.count_tables(len as u64));
+    let mut res = BTreeMap::new();
+    for i in 0..len {
+        let tbl_name = try!(parse_table_name(r, i));
+        r.push_table(&tbl_name);
+        let tree = try!(read_chunked_tree::<T>(r, header, &tbl_name));
+        r.pop_path();
+        res.insert(tbl_name, tree);
+    }
+    return Ok( res );
+}
+
+/// Checksums the chunks of every table of one section without decoding them.
+/// Returns false if the file ended before the section was complete.
+fn verify_chunked_tables<'a>(r: &mut DBReader, corrupt: &mut Vec<String>) -> Result<bool, DBError> {
+    let len = try!(rmp::decode::read_map_size(r));
+    try!(r.count_tables(len as u64));
+    for _ in 0..len {
+        let tbl_name = try!(parse_string(r));
+        r.start_checksum();
+        let mut complete = true;
+        loop {
+            let chunk_len = match rmp::decode::read_str_len(r) {
+                Ok(0) => break,
+                Ok(chunk_len) => chunk_len as u64,
+                Err(_) => { complete = false; break; }
+            };
+            if try!(::std::io::copy(&mut Read::take(&mut *r, chunk_len), &mut ::std::io::sink())) != chunk_len {
+                complete = false;
+                break;
+            }
+        }
+        let checksum = r.finish_checksum();
+        let expected = if complete { rmp::decode::read_u64(r).ok().and_then(|_| rmp::decode::read_u32(r).ok()) } else { None };
+        match expected {
+            Some(expected) if expected == checksum => {},
+            Some(_) => corrupt.push(tbl_name),
+            None => {
+                corrupt.push(tbl_name);
+                return Ok(false);
+            }
+        }
+    }
+    return Ok(true);
+}
+
+/// Checksums every table frame of one section without decoding the trees.
+/// Returns false if the file ended before the section was complete.
+fn verify_framed_tables<'a>(r: &mut DBReader, corrupt: &mut Vec<String>) -> Result<bool, DBError> {
+    let len = try!(rmp::decode::read_map_size(r));
+    try!(r.count_tables(len as u64));
+    for _ in 0..len {
+        let tbl_name = try!(parse_string(r));
+        let frame_len = try!(rmp::decode::read_u64(r));
+        let mut crc = Crc32::new();
+        let copied = try!(::std::io::copy(&mut Read::take(&mut *r, frame_len), &mut crc));
+        let expected = if copied == frame_len { rmp::decode::read_u32(r).ok() } else { None };
+        match expected {
+            Some(expected) if expected == crc.finish() => {},
+            Some(_) => corrupt.push(tbl_name),
+            None => {
+                corrupt.push(tbl_name);
+                return Ok(false);
+            }
+        }
+    }
+    return Ok(true);
+}
+
+/// Outcome of `DB::verify_file`.
+#[derive(Debug)]
+pub struct VerifyReport {
+    pub corrupt_object_tables: Vec<String>,
+    pub corrupt_bitmap_tables: Vec<String>,
+    /// The file ended in the middle of a table.
+    pub truncated: bool,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        return !self.truncated && self.corrupt_object_tables.is_empty() && self.corrupt_bitmap_tables.is_empty();
+    }
+}
+
+/// Reads the header, returns None for legacy files that have none.
+fn read_file_header<'a>(r: &mut DBReader) -> Result<Option<FileHeader>, DBError> {
+    let mut first = [0u8; 1];
+    r.push_field("header");
+    try!(read_header_bytes(r, &mut first));
+    if first[0] == LEGACY_MARKER {
+        r.pop_path();
+        return Ok(None);
+    }
+    let header = try!(FileHeader::read_after(first[0], r));
+    r.pop_path();
+    let len = try!(rmp::decode::read_array_size(r));
+    if len != 2 {
+        return Err(r.error("DB should have length 2"));
+    }
+    return Ok(Some(header));
+}
+
 impl Serialized for DB {
-    fn write<'a>(&self, mut w: &mut Write) -> Result<(), DBError> {
+    fn write<'a>(&self, w: &mut Write) -> Result<(), DBError> {
+        let objects = try!(self.backend.object_tables());
+        let bitmaps = try!(self.backend.bitmap_tables());
+        let mut w = CountingWriter{inner: w, count: 0};
+        let mut header = FileHeader::current();
+        if !self.options.is_empty() {
+            header.flags |= FEATURE_TABLE_OPTIONS;
+        }
+        if self.generation != 0 {
+            header.flags |= FEATURE_JOURNAL_GENERATION;
+        }
+        try!(header.write(&mut w));
         try!(rmp::encode::write_array_len(&mut w, 2 as u32));
-        try!(self.obj_map.write(&mut w));
-        try!(self.bit_map.write(&mut w));
+        let directory = [try!(write_chunked_tables(&objects, &mut w)), try!(write_chunked_tables(&bitmaps, &mut w))];
+        let offset = w.count;
+        try!(write_directory(header.flags, &directory, &self.options, self.generation, &mut w));
+        try!(w.write_all(&[(offset >> 56) as u8, (offset >> 48) as u8, (offset >> 40) as u8, (offset >> 32) as u8,
+                           (offset >> 24) as u8, (offset >> 16) as u8, (offset >> 8) as u8, offset as u8]));
+        try!(w.write_all(DIRECTORY_MAGIC));
         return Ok(());
     }
 
-    fn read<'a>(mut r: &mut Read) -> Result<Self, DBError> {
-        let len = try!(rmp::decode::read_array_size(&mut r));
-        if len != 2 {
-            return Err(DBError::FileFormat("DB should have length 2".into()));
+    fn read<'a>(r: &mut DBReader) -> Result<Self, DBError> {
+        let header = match try!(read_file_header(r)) {
+            Some(header) => header,
+            None => return read_tables(r),
+        };
+        if header.version < FIRST_FRAMED_VERSION {
+            return read_tables(r);
         }
-        let objects = try!(BTreeMap::<String, IntervalTree<Object>>::read(r));
-        let bitmaps = try!(BTreeMap::<String, IntervalTree<Bitmap>>::read(r));
-        return Ok( DB::new_from_data(objects,bitmaps) );
+        let chunked = header.version >= FIRST_CHUNKED_VERSION;
+        r.push_field("objects");
+        let objects = try!(if chunked { read_chunked_tables::<Object>(r, &header) } else { read_framed_tables::<Object>(r, &header) });
+        r.pop_path();
+        r.push_field("bitmaps");
+        let bitmaps = try!(if chunked { read_chunked_tables::<Bitmap>(r, &header) } else { read_framed_tables::<Bitmap>(r, &header) });
+        r.pop_path();
+        let (options, generation) = if header.flags & FEATURE_TABLE_DIRECTORY != 0 { try!(read_directory_and_footer(r, &header)) } else { (BTreeMap::new(), 0) };
+        let mut db = DB::new_from_shared_data(objects,bitmaps);
+        db.options = options;
+        db.generation = generation;
+        return Ok(db);
     }
 }
 
+/// Deserializes a DB from `input`, enforcing `limits`.
+fn read_db<'a>(input: &mut Read, limits: ReadLimits) -> Result<DB, DBError> {
+    let mut r = DBReader::new(input, limits);
+    return match DB::read(&mut r) {
+        Err(_) if r.exhausted() => Err(r.context(r.error(format!("file exceeds the limit of {} bytes", limits.max_total_bytes)))),
+        Err(e) => Err(r.context(e)),
+        res => res,
+    };
+}
+
 impl DB {
     #[must_use]
     pub fn serialize<'a>(&self) -> Result<Vec<u8>, DBError> {
@@ -164,23 +889,60 @@ impl DB {
 
     #[must_use]
     pub fn deserialize<'a>(buf: Vec<u8>) -> Result<DB, DBError> {
-        let db = try!(DB::read(&mut Cursor::new(buf)));
+        return DB::deserialize_with_limits(buf, ReadLimits::default());
+    }
+
+    #[must_use]
+    pub fn deserialize_with_limits<'a>(buf: Vec<u8>, limits: ReadLimits) -> Result<DB, DBError> {
+        let db = try!(read_db(&mut Cursor::new(buf), limits));
         return Ok(db);
     }
 
+    /// Atomically replaces `filename` with the serialized database. A crash
+    /// during the save leaves the previous file untouched.
     #[must_use]
     pub fn save_to_file<'a>(&self, filename: &String) -> Result<(), DBError> {
-        let mut f = try!(File::create(filename));
-        try!(self.write(&mut f));
-        return Ok(());
+        return atomic_file::write_atomically(Path::new(filename), |w| self.write(w));
     }
 
     #[must_use]
     pub fn new_from_file<'a>(filename: &String) -> Result<DB, DBError> {
-        let mut f = try!(File::open(filename));
-        let db = try!(DB::read(&mut f));
+        return DB::new_from_file_with_limits(filename, ReadLimits::default());
+    }
+
+    #[must_use]
+    pub fn new_from_file_with_limits<'a>(filename: &String, limits: ReadLimits) -> Result<DB, DBError> {
+        let mut f = BufReader::new(try!(File::open(filename)));
+        let db = try!(read_db(&mut f, limits));
         return Ok(db);
     }
+
+    /// Checks the table checksums of the file `filename` without loading the
+    /// tables, so a damaged file can be detected before it is used.
+    #[must_use]
+    pub fn verify_file<'a>(filename: &String) -> Result<VerifyReport, DBError> {
+        let mut f = BufReader::new(try!(File::open(filename)));
+        let mut r = DBReader::new(&mut f, ReadLimits::default());
+        let header = match read_file_header(&mut r) {
+            Ok(Some(header)) => header,
+            Ok(None) => return Err(DBError::FileFormat("legacy file has no checksums".into())),
+            Err(e) => return Err(r.context(e)),
+        };
+        if header.version < FIRST_FRAMED_VERSION || header.flags & FEATURE_TABLE_CHECKSUMS == 0 {
+            return Err(DBError::FileFormat(format!("version {} file has no checksums", header.version)));
+        }
+        let mut report = VerifyReport{corrupt_object_tables: vec![], corrupt_bitmap_tables: vec![], truncated: false};
+        let verify_tables = if header.version >= FIRST_CHUNKED_VERSION { verify_chunked_tables } else { verify_framed_tables };
+        let complete = match verify_tables(&mut r, &mut report.corrupt_object_tables) {
+            Ok(true) => verify_tables(&mut r, &mut report.corrupt_bitmap_tables),
+            other => other,
+        };
+        report.truncated = !try!(complete.map_err(|e| r.context(e)));
+        if !report.truncated && header.flags & FEATURE_TABLE_DIRECTORY != 0 {
+            report.truncated = read_directory_and_footer(&mut r, &header).is_err();
+        }
+        return Ok(report);
+    }
 }
 
 
@@ -191,23 +953,23 @@ pub fn test_serialize_objects() {
     let tbl = "foo".to_string();
     db.insert_object(&tbl,
               Range::new(3, 4),
-              Object{data: "foo".into()}); 
+              Object{data: "foo".into()}).unwrap(); 
     db.insert_object(&tbl,
               Range::new(4, 5),
-              Object{data: "foo".into()});
+              Object{data: "foo".into()}).unwrap();
     db.insert_object(&tbl,
               Range::new(5, 6),
-              Object{data: "foo".into()});
+              Object{data: "foo".into()}).unwrap();
 
     let bin = db.serialize().unwrap();
     let db2 = DB::deserialize(bin).unwrap();
     let db2_keys = db2.query_object(&tbl, Range::new(0, 100))
                       .unwrap()
-                      .map(|(r, _)| r.clone())
+                      .map(|e| e.unwrap().0)
                       .collect::<Vec<Range>>();
     let db1_keys = db.query_object(&tbl, Range::new(0, 100))
                      .unwrap()
-                     .map(|(r, _)| r.clone())
+                     .map(|e| e.unwrap().0)
                      .collect::<Vec<Range>>();
     assert_eq!(db1_keys, db2_keys);
 }
@@ -222,22 +984,256 @@ pub fn test_serialize_bitmaps() {
 
     db.insert_bitmap(&tbl,
               Range::new(5, 7),
-              Bitmap::new(1, "goo".into()));
+              Bitmap::new(1, "goo".into())).unwrap();
     db.insert_bitmap(&tbl,
               Range::new(6, 8),
-              Bitmap::new(1, "bar".into()));
+              Bitmap::new(1, "bar".into())).unwrap();
 
     let bin = db.serialize().unwrap();
 
     let db2 = DB::deserialize(bin).unwrap();
     let db2_values = db2.query_bitmap(&tbl, Range::new(6, 7))
                       .unwrap()
-                      .map(|(_, b)| b.data.to_vec())
+                      .map(|e| e.unwrap().1.data.to_vec())
                       .collect::<Vec<Vec<u8>>>();
 
     let db1_values = db.query_bitmap(&tbl, Range::new(6, 7))
                       .unwrap()
-                      .map(|(_, b)| b.data.to_vec())
+                      .map(|e| e.unwrap().1.data.to_vec())
                       .collect::<Vec<Vec<u8>>>();
     assert_eq!(db1_values, db2_values);
 }
+
+#[cfg(test)]
+fn serialize_headerless(db: &DB) -> Vec<u8> {
+    let mut buf = Vec::new();
+    write_tables(db, &mut buf).unwrap();
+    return buf;
+}
+
+#[cfg(test)]
+fn parse_error(res: Result<DB, DBError>) -> (u64, String, DBError) {
+    match res {
+        Err(DBError::Parse{offset, path, err}) => return (offset, path, *err),
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("expected a parse error"),
+    }
+}
+
+#[cfg(test)]
+fn assert_file_format_error(res: Result<DB, DBError>, expected: &str) {
+    match parse_error(res) {
+        (_, _, DBError::FileFormat(msg)) => assert_eq!(msg, expected),
+        (_, _, e) => panic!("unexpected error {}", e),
+    }
+}
+
+#[cfg(test)]
+fn assert_header_error(res: Result<DB, DBError>, expected: &str) {
+    match res {
+        Err(DBError::FileFormat(msg)) => assert_eq!(msg, expected),
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("expected a file format error"),
+    }
+}
+
+#[test]
+pub fn test_serialize_header() {
+    let mut db = DB::new();
+    let tbl = "foo".to_string();
+    db.insert_object(&tbl, Range::new(3, 4), Object{data: "foo".into()}).unwrap();
+
+    let bin = db.serialize().unwrap();
+    assert_eq!(&bin[0..8], MAGIC);
+    assert_eq!(&bin[8..10], &[0, FORMAT_VERSION as u8]);
+
+    let legacy = DB::deserialize(serialize_headerless(&db)).unwrap();
+    assert!(legacy.query_object(&tbl, Range::new(3, 4)).unwrap().count() == 1);
+
+    assert_header_error(DB::deserialize("definitely not a db".into()), "not a theban db");
+    assert_header_error(DB::deserialize(vec![]), "not a theban db");
+
+    let mut v1 = bin[0..14].to_vec();
+    v1[9] = 1;
+    v1.extend(serialize_headerless(&db).into_iter());
+    assert!(DB::deserialize(v1).unwrap().query_object(&tbl, Range::new(3, 4)).unwrap().count() == 1);
+
+    let mut v2 = vec![];
+    FileHeader{version: 2, flags: FEATURE_TABLE_CHECKSUMS}.write(&mut v2).unwrap();
+    rmp::encode::write_array_len(&mut v2, 2).unwrap();
+    write_framed_tables(&db.backend.obj_map, &mut v2).unwrap();
+    write_framed_tables(&db.backend.bit_map, &mut v2).unwrap();
+    assert!(DB::deserialize(v2).unwrap().query_object(&tbl, Range::new(3, 4)).unwrap().count() == 1);
+
+    let mut future = bin.clone();
+    future[8] = 0xff;
+    assert_header_error(DB::deserialize(future), &format!("unsupported version {}", 0xff00 | FORMAT_VERSION));
+}
+
+#[cfg(test)]
+fn directory_offset(bin: &Vec<u8>) -> usize {
+    return bin[bin.len()-16..bin.len()-8].iter().fold(0, |acc, &b| acc << 8 | b as usize);
+}
+
+#[test]
+pub fn test_serialize_chunks() {
+    let tbl = "foo".to_string();
+    let entries = 2 * CHUNK_ENTRIES as u64 + 10;
+    let objects = (0..entries).map(|i| (tbl.clone(), Range::new(i, i), Object{data: vec![i as u8]}));
+    let db = DB::from_sorted_iter(objects, vec![]).unwrap();
+    let bin = db.serialize().unwrap();
+    let db2 = DB::deserialize(bin.clone()).unwrap();
+    let found = db2.query_object(&tbl, Range::new(0, u64::MAX)).unwrap().map(|e| e.unwrap().0.min).collect::<Vec<u64>>();
+    assert_eq!(found, (0..entries).collect::<Vec<u64>>());
+
+    // the entry count in the trailer of the object table is checked, it is
+    // followed by its checksum and the 20 byte bitmap section
+    let mut wrong_count = bin.clone();
+    let pos = directory_offset(&bin) - 20 - 5 - 1;
+    assert_eq!(wrong_count[pos], entries as u8);
+    wrong_count[pos] += 1;
+    assert_file_format_error(DB::deserialize(wrong_count), &format!("table has {} entries instead of {}", entries, entries + 1));
+}
+
+#[test]
+pub fn test_verify_file() {
+    let mut db = DB::new();
+    let (foo, bar) = ("foo".to_string(), "bar".to_string());
+    db.insert_object(&foo, Range::new(3, 4), Object{data: "foo".into()}).unwrap();
+    db.insert_object(&bar, Range::new(3, 4), Object{data: "bar".into()}).unwrap();
+    db.insert_bitmap(&bar, Range::new(0, 2), Bitmap::new(1, "abc".into())).unwrap();
+
+    let path = ::std::env::temp_dir().join(format!("theban_db_verify_{}", ::std::process::id()));
+    let filename = path.to_string_lossy().into_owned();
+    let mut bin = db.serialize().unwrap();
+    let pos = bin.windows(3).position(|w| w == b"foo".as_ref()).unwrap();
+    let pos = pos + 3 + bin[pos+3..].windows(3).position(|w| w == b"foo".as_ref()).unwrap();
+    bin[pos] = b'X';
+    File::create(&path).unwrap().write_all(&bin).unwrap();
+
+    let report = DB::verify_file(&filename).unwrap();
+    assert_eq!(report.corrupt_object_tables, vec![foo.clone()]);
+    assert!(report.corrupt_bitmap_tables.is_empty());
+    assert!(!report.truncated);
+    assert_file_format_error(DB::new_from_file(&filename), "checksum mismatch in table foo");
+
+    File::create(&path).unwrap().write_all(&bin[0..directory_offset(&bin)-4]).unwrap();
+    let report = DB::verify_file(&filename).unwrap();
+    assert_eq!(report.corrupt_bitmap_tables, vec![foo.clone()]);
+    assert!(report.truncated && !report.is_ok());
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(test)]
+fn fuzz_sample_db() -> DB {
+    let mut db = DB::new();
+    let (foo, bar) = ("foo".to_string(), "bar".to_string());
+    db.insert_object(&foo, Range::new(3, 4), Object{data: "foo".into()}).unwrap();
+    db.insert_object(&foo, Range::new(0, u64::MAX), Object{data: vec![]}).unwrap();
+    db.insert_object(&bar, Range::new(10, 400), Object{data: vec![7; 300]}).unwrap();
+    db.insert_bitmap(&bar, Range::new(0, 2), Bitmap::new(1, "abc".into())).unwrap();
+    db.insert_bitmap(&bar, Range::new(100, 101), Bitmap::new(4, vec![1; 8])).unwrap();
+    return db;
+}
+
+#[test]
+pub fn test_deserialize_fuzz_corpus() {
+    let db = fuzz_sample_db();
+    let bin = db.serialize().unwrap();
+    let legacy = serialize_headerless(&db);
+
+    for input in vec![&bin, &legacy] {
+        // every proper prefix of a file is rejected
+        for len in 0..input.len() {
+            assert!(DB::deserialize(input[0..len].to_vec()).is_err());
+        }
+
+        // random byte flips must never panic or allocate unbounded memory
+        let mut state = 0x2545f4914f6cdd1du64;
+        for _ in 0..5000 {
+            let mut mutated = input.clone();
+            for _ in 0..(1 + state % 3) {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let pos = (state % mutated.len() as u64) as usize;
+                mutated[pos] = (state >> 32) as u8;
+            }
+            let _ = DB::deserialize(mutated);
+        }
+    }
+}
+
+#[test]
+pub fn test_deserialize_malicious() {
+    // legacy file claiming a 4 GiB table name
+    let huge_blob = vec![0x92, 0x81, 0xdb, 0xff, 0xff, 0xff, 0xff, b'x'];
+    assert_file_format_error(DB::deserialize(huge_blob.clone()), "unexpected EOF");
+    let limits = ReadLimits{max_blob_size: 1024, ..ReadLimits::default()};
+    assert_file_format_error(DB::deserialize_with_limits(huge_blob, limits), "blob of 4294967295 bytes exceeds the limit of 1024");
+
+    // legacy file with an inverted range
+    let inverted = vec![0x92, 0x81, 0xa1, b'x', 0x93, 0x05, 0x04, 0xa0, 0x80];
+    let (offset, path, err) = parse_error(DB::deserialize(inverted));
+    assert_eq!((offset, path.as_ref()), (7, "objects[\"x\"][0].range"));
+    assert_eq!(format!("{}", err), "File format error: invalid range [5, 4]");
+
+    // legacy file with entries out of order
+    let unsorted = vec![0x92, 0x81, 0xa1, b'x', 0x96, 0x05, 0x06, 0xa0, 0x05, 0x05, 0xa0, 0x80];
+    match parse_error(DB::deserialize(unsorted)) {
+        (10, ref path, DBError::UnsortedInput{..}) => assert_eq!(path, "objects[\"x\"][1].range"),
+        (_, _, e) => panic!("unexpected error {}", e),
+    }
+
+    // tree array that is not made of (min, max, data) triples
+    let ragged = vec![0x92, 0x81, 0xa1, b'x', 0x92, 0x04, 0x05, 0x80];
+    assert_file_format_error(DB::deserialize(ragged), "tree length 2 is not a multiple of 3");
+
+    // bitmap whose data does not cover its range
+    let short_bitmap = vec![0x92, 0x80, 0x81, 0xa1, b'x', 0x93, 0x04, 0x05, 0x92, 0x01, 0xa1, b'x'];
+    match parse_error(DB::deserialize(short_bitmap)) {
+        (12, ref path, DBError::InvalidBitmapLength{entry_size: 1, got: 1, ..}) => assert_eq!(path, "bitmaps[\"x\"][0]"),
+        (_, _, e) => panic!("unexpected error {}", e),
+    }
+
+    // bitmap claiming to cover the whole address space, whose length does not fit in a u64
+    let mut full_bitmap = vec![0x92, 0x80, 0x81, 0xa1, b'x', 0x93, 0x00, 0xcf];
+    full_bitmap.extend_from_slice(&[0xff; 8]);
+    full_bitmap.extend_from_slice(&[0x92, 0x01, 0xa1, b'x']);
+    match parse_error(DB::deserialize(full_bitmap)) {
+        (_, ref path, DBError::InvalidBitmapLength{entry_size: 1, got: 1, ..}) => assert_eq!(path, "bitmaps[\"x\"][0]"),
+        (_, _, e) => panic!("unexpected error {}", e),
+    }
+    let mut db = DB::new();
+    let x = "x".to_string();
+    db.insert_bitmap(&x, Range::new(7, 7), Bitmap::new(1, vec![9])).unwrap();
+    db.backend.bit_map.get_mut(&x).unwrap().insert(Range::new(0, u64::MAX), Bitmap::new(1, vec![9]));
+    db.backend.bit_map.get_mut(&x).unwrap().delete(Range::new(7, 7));
+    match parse_error(DB::deserialize(db.serialize().unwrap())) {
+        (_, ref path, DBError::InvalidBitmapLength{entry_size: 1, got: 1, ..}) => assert_eq!(path, "bitmaps[\"x\"][0]"),
+        (_, _, e) => panic!("unexpected error {}", e),
+    }
+
+    let bin = fuzz_sample_db().serialize().unwrap();
+    let limits = ReadLimits{max_tables: 3, ..ReadLimits::default()};
+    assert_file_format_error(DB::deserialize_with_limits(bin.clone(), limits), "4 tables exceed the limit of 3");
+    let limits = ReadLimits{max_total_bytes: 100, ..ReadLimits::default()};
+    assert_file_format_error(DB::deserialize_with_limits(bin.clone(), limits), "file exceeds the limit of 100 bytes");
+    let limits = ReadLimits{max_total_bytes: bin.len() as u64, ..ReadLimits::default()};
+    assert!(DB::deserialize_with_limits(bin, limits).is_ok());
+}
+
+#[test]
+pub fn test_deserialize_error_context() {
+    let bin = fuzz_sample_db().serialize().unwrap();
+
+    // header errors carry no context
+    assert_header_error(DB::deserialize(bin[0..5].to_vec()), "not a theban db");
+
+    // entry size of the second bitmap in table "bar"
+    let pos = bin.windows(3).position(|w| w == b"abc".as_ref()).unwrap() + 6;
+    let mut broken = bin.clone();
+    broken[pos] = 0xc1;
+    let (offset, path, _) = parse_error(DB::deserialize(broken));
+    assert_eq!((offset, path.as_ref()), (pos as u64 + 1, "bitmaps[\"bar\"][1].bitmap.entry_size"));
+}
@@ -0,0 +1,127 @@
+use std::cmp;
+use std::io;
+use std::io::prelude::*;
+use std::u32;
+use std::u64;
+
+use dberror::DBError;
+use super::checksum::Crc32;
+
+/// Upper bounds enforced while deserializing, so that a corrupt or malicious
+/// file is rejected instead of exhausting memory.
+#[derive(Clone, Copy, Debug)]
+pub struct ReadLimits {
+    /// Number of object tables plus number of bitmap tables.
+    pub max_tables: u64,
+    /// Size of a single object, bitmap or table name.
+    pub max_blob_size: u64,
+    /// Number of bytes consumed from the input.
+    pub max_total_bytes: u64,
+}
+
+impl Default for ReadLimits {
+    fn default() -> ReadLimits {
+        return ReadLimits{max_tables: 1 << 20, max_blob_size: u32::MAX as u64, max_total_bytes: u64::MAX};
+    }
+}
+
+/// Wraps the input of the deserializer, keeps track of the byte offset and
+/// enforces the `ReadLimits`. Reads past `max_total_bytes` behave like EOF.
+///
+/// The parser also maintains the logical path of the value it is currently
+/// reading. Segments are only popped after a value was read successfully, so
+/// after an error the path still points at the broken value.
+pub struct DBReader<'a> {
+    inner: &'a mut Read,
+    pos: u64,
+    path: Vec<String>,
+    tables: u64,
+    crc: Option<Crc32>,
+    pub limits: ReadLimits,
+}
+
+impl<'a> DBReader<'a> {
+    pub fn new(inner: &'a mut Read, limits: ReadLimits) -> DBReader<'a> {
+        return DBReader{inner: inner, pos: 0, path: vec![], tables: 0, crc: None, limits: limits};
+    }
+
+    /// Creates a reader for input that starts at byte `pos` of the file.
+    pub fn new_at(inner: &'a mut Read, limits: ReadLimits, pos: u64) -> DBReader<'a> {
+        return DBReader{inner: inner, pos: pos, path: vec![], tables: 0, crc: None,
+                        limits: ReadLimits{max_total_bytes: limits.max_total_bytes.saturating_add(pos), ..limits}};
+    }
+
+    pub fn push_field(&mut self, name: &str) {
+        let segment = if self.path.is_empty() { name.to_string() } else { format!(".{}", name) };
+        self.path.push(segment);
+    }
+
+    pub fn push_table(&mut self, name: &String) {
+        self.path.push(format!("[{:?}]", name));
+    }
+
+    pub fn push_index(&mut self, index: u64) {
+        self.path.push(format!("[{}]", index));
+    }
+
+    pub fn pop_path(&mut self) {
+        self.path.pop();
+    }
+
+    pub fn path(&self) -> String {
+        return self.path.concat();
+    }
+
+    /// Attaches the current offset and path to an error raised while parsing.
+    /// Errors in the file header are returned as they are, so a file that is
+    /// no theban db or has an unsupported version still is a `FileFormat`
+    /// error.
+    pub fn context(&self, err: DBError) -> DBError {
+        if self.path.first().map(|s| s == "header").unwrap_or(false) {
+            return err;
+        }
+        return DBError::Parse{offset: self.pos, path: self.path(), err: Box::new(err)};
+    }
+
+    pub fn position(&self) -> u64 {
+        return self.pos;
+    }
+
+    pub fn exhausted(&self) -> bool {
+        return self.pos >= self.limits.max_total_bytes;
+    }
+
+    pub fn error<S: Into<String>>(&self, msg: S) -> DBError {
+        return DBError::FileFormat(msg.into());
+    }
+
+    /// Accounts for `n` more tables against `max_tables`.
+    pub fn count_tables(&mut self, n: u64) -> Result<(), DBError> {
+        self.tables = self.tables.saturating_add(n);
+        if self.tables > self.limits.max_tables {
+            return Err(self.error(format!("{} tables exceed the limit of {}", self.tables, self.limits.max_tables)));
+        }
+        return Ok(());
+    }
+
+    /// Starts checksumming every byte read from now on.
+    pub fn start_checksum(&mut self) {
+        self.crc = Some(Crc32::new());
+    }
+
+    pub fn finish_checksum(&mut self) -> u32 {
+        return self.crc.take().map(|crc| crc.finish()).unwrap_or(0);
+    }
+}
+
+impl<'a> Read for DBReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = cmp::min(buf.len() as u64, self.limits.max_total_bytes - self.pos) as usize;
+        let n = try!(self.inner.read(&mut buf[..len]));
+        if let Some(ref mut crc) = self.crc {
+            crc.update(&buf[..n]);
+        }
+        self.pos += n as u64;
+        return Ok(n);
+    }
+}
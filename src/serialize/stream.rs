@@ -0,0 +1,292 @@
+extern crate rmp;
+
+use std::io::prelude::*;
+
+use memrange::Range;
+
+use content::Bitmap;
+use content::Object;
+use dberror::DBError;
+use bulk_load::check_sorted;
+use super::{Serialized, DBReader, ReadLimits, FileHeader, FEATURE_TABLE_CHECKSUMS, FEATURE_TABLE_DIRECTORY, FIRST_FRAMED_VERSION, FIRST_CHUNKED_VERSION};
+use super::{read_file_header, read_directory_and_footer, parse_table_name, parse_range, SECTION_NAMES};
+
+/// A single entry of a serialized DB.
+#[derive(Debug, PartialEq)]
+pub enum Record {
+    Object(String, Range, Object),
+    Bitmap(String, Range, Bitmap),
+}
+
+struct TableState {
+    name: String,
+    /// Entries left in the current tree array.
+    left: u64,
+    index: u64,
+    previous: Option<Range>,
+    /// Start and length of the current frame or chunk.
+    start: u64,
+    len: u64,
+}
+
+/// Reads the records of a serialized DB one at a time, so only the current
+/// record is kept in memory. Files of every format version are supported.
+///
+/// The same checks as in `DB::deserialize` are applied while streaming. The
+/// first error is returned with its offset and path, after that the stream
+/// ends.
+pub struct DBStream<'a> {
+    r: DBReader<'a>,
+    header: Option<FileHeader>,
+    section: usize,
+    tables_left: u32,
+    table_index: u32,
+    table: Option<TableState>,
+    done: bool,
+}
+
+impl<'a> DBStream<'a> {
+    #[must_use]
+    pub fn new(input: &'a mut Read) -> Result<DBStream<'a>, DBError> {
+        return DBStream::new_with_limits(input, ReadLimits::default());
+    }
+
+    #[must_use]
+    pub fn new_with_limits(input: &'a mut Read, limits: ReadLimits) -> Result<DBStream<'a>, DBError> {
+        let mut stream = DBStream{r: DBReader::new(input, limits), header: None, section: 0, tables_left: 0,
+                                  table_index: 0, table: None, done: false};
+        let res = read_file_header(&mut stream.r).and_then(|header| {
+            stream.header = header;
+            return stream.start_section();
+        });
+        if let Err(e) = res {
+            return Err(stream.r.context(e));
+        }
+        return Ok(stream);
+    }
+
+    fn version(&self) -> u16 {
+        return self.header.as_ref().map(|h| h.version).unwrap_or(0);
+    }
+
+    fn checksums(&self) -> bool {
+        return self.header.as_ref().map(|h| h.flags & FEATURE_TABLE_CHECKSUMS != 0).unwrap_or(false);
+    }
+
+    fn start_section(&mut self) -> Result<(), DBError> {
+        self.r.push_field(SECTION_NAMES[self.section]);
+        self.tables_left = try!(rmp::decode::read_map_size(&mut self.r));
+        self.table_index = 0;
+        return self.r.count_tables(self.tables_left as u64);
+    }
+
+    fn start_table(&mut self) -> Result<(), DBError> {
+        let name = try!(parse_table_name(&mut self.r, self.table_index));
+        self.tables_left -= 1;
+        self.table_index += 1;
+        self.r.push_table(&name);
+        let mut table = TableState{name: name, left: 0, index: 0, previous: None, start: 0, len: 0};
+        if self.version() >= FIRST_CHUNKED_VERSION {
+            table.start = self.r.position();
+            self.r.start_checksum();
+        } else if self.version() >= FIRST_FRAMED_VERSION {
+            self.r.push_field("frame_len");
+            table.len = try!(rmp::decode::read_u64(&mut self.r));
+            self.r.pop_path();
+            table.start = self.r.position();
+            self.r.start_checksum();
+            table.left = try!(read_tree_len(&mut self.r));
+        } else {
+            table.left = try!(read_tree_len(&mut self.r));
+        }
+        self.table = Some(table);
+        return Ok(());
+    }
+
+    /// Called whenever the current tree array is exhausted. Starts the next
+    /// chunk if there is one, otherwise checks the end of the table.
+    fn end_of_array(&mut self) -> Result<bool, DBError> {
+        let version = self.version();
+        let checksums = self.checksums();
+        let table = self.table.as_mut().unwrap();
+        if version >= FIRST_CHUNKED_VERSION {
+            if self.r.position() - table.start != table.len {
+                return Err(self.r.error(format!("chunk does not match its length {}", table.len)));
+            }
+            let chunk_len = try!(rmp::decode::read_str_len(&mut self.r)) as u64;
+            if chunk_len != 0 {
+                table.start = self.r.position();
+                table.len = chunk_len;
+                table.left = try!(read_tree_len(&mut self.r));
+                return Ok(false);
+            }
+            let checksum = self.r.finish_checksum();
+            self.r.push_field("count");
+            let count = try!(rmp::decode::read_u64(&mut self.r));
+            if count != table.index {
+                return Err(self.r.error(format!("table has {} entries instead of {}", table.index, count)));
+            }
+            self.r.pop_path();
+            try!(check_checksum(&mut self.r, checksums, checksum, &table.name));
+        } else if version >= FIRST_FRAMED_VERSION {
+            let checksum = self.r.finish_checksum();
+            if self.r.position() - table.start != table.len {
+                return Err(self.r.error(format!("table {} does not match its frame length {}", table.name, table.len)));
+            }
+            try!(check_checksum(&mut self.r, checksums, checksum, &table.name));
+        }
+        return Ok(true);
+    }
+
+    fn read_entry(&mut self) -> Result<Record, DBError> {
+        let section = self.section;
+        let table = self.table.as_mut().unwrap();
+        let r = &mut self.r;
+        r.push_index(table.index);
+        r.push_field("range");
+        let rng = try!(parse_range(r));
+        try!(check_sorted(table.previous, rng));
+        r.pop_path();
+        let record = if section == 0 {
+            let data = try!(Object::read(r));
+            try!(data.validate(rng));
+            Record::Object(table.name.clone(), rng, data)
+        } else {
+            let data = try!(Bitmap::read(r));
+            try!(data.validate(rng));
+            Record::Bitmap(table.name.clone(), rng, data)
+        };
+        r.pop_path();
+        table.previous = Some(rng);
+        table.left -= 1;
+        table.index += 1;
+        return Ok(record);
+    }
+
+    fn advance(&mut self) -> Result<Option<Record>, DBError> {
+        loop {
+            let left = match self.table {
+                Some(ref table) => table.left,
+                None => {
+                    if self.tables_left > 0 {
+                        try!(self.start_table());
+                        continue;
+                    }
+                    self.r.pop_path();
+                    self.section += 1;
+                    if self.section == SECTION_NAMES.len() {
+                        if let Some(ref header) = self.header {
+                            if header.flags & FEATURE_TABLE_DIRECTORY != 0 {
+                                try!(read_directory_and_footer(&mut self.r, header));
+                            }
+                        }
+                        return Ok(None);
+                    }
+                    try!(self.start_section());
+                    continue;
+                }
+            };
+            if left > 0 {
+                return self.read_entry().map(Some);
+            }
+            if try!(self.end_of_array()) {
+                self.r.pop_path();
+                self.table = None;
+            }
+        }
+    }
+}
+
+fn read_tree_len(r: &mut DBReader) -> Result<u64, DBError> {
+    let len = try!(rmp::decode::read_array_size(r));
+    if len % 3 != 0 {
+        return Err(r.error(format!("tree length {} is not a multiple of 3", len)));
+    }
+    return Ok((len / 3) as u64);
+}
+
+fn check_checksum(r: &mut DBReader, enabled: bool, checksum: u32, tbl_name: &String) -> Result<(), DBError> {
+    if !enabled {
+        return Ok(());
+    }
+    r.push_field("checksum");
+    let expected = try!(rmp::decode::read_u32(r));
+    if checksum != expected {
+        return Err(r.error(format!("checksum mismatch in table {}", tbl_name)));
+    }
+    r.pop_path();
+    return Ok(());
+}
+
+impl<'a> Iterator for DBStream<'a> {
+    type Item = Result<Record, DBError>;
+
+    fn next(&mut self) -> Option<Result<Record, DBError>> {
+        if self.done {
+            return None;
+        }
+        return match self.advance() {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(self.r.context(e)))
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+fn stream_all(bin: &Vec<u8>) -> Vec<Result<Record, DBError>> {
+    let mut input = &bin[..];
+    return DBStream::new(&mut input).unwrap().collect();
+}
+
+#[test]
+fn test_stream_records() {
+    use std::u64;
+    use super::{fuzz_sample_db, serialize_headerless};
+
+    let db = fuzz_sample_db();
+    let (foo, bar) = ("foo".to_string(), "bar".to_string());
+    let expected = vec![Record::Object(bar.clone(), Range::new(10, 400), Object{data: vec![7; 300]}),
+                        Record::Object(foo.clone(), Range::new(0, u64::MAX), Object{data: vec![]}),
+                        Record::Object(foo.clone(), Range::new(3, 4), Object{data: "foo".into()}),
+                        Record::Bitmap(bar.clone(), Range::new(0, 2), Bitmap::new(1, "abc".into())),
+                        Record::Bitmap(bar.clone(), Range::new(100, 101), Bitmap::new(4, vec![1; 8]))];
+    for bin in vec![db.serialize().unwrap(), serialize_headerless(&db)] {
+        let records = stream_all(&bin).into_iter().map(|r| r.unwrap()).collect::<Vec<Record>>();
+        assert_eq!(records, expected);
+    }
+}
+
+#[test]
+fn test_stream_errors() {
+    use super::fuzz_sample_db;
+
+    let bin = fuzz_sample_db().serialize().unwrap();
+    let pos = bin.windows(3).position(|w| w == b"foo".as_ref()).unwrap();
+    let pos = pos + 3 + bin[pos+3..].windows(3).position(|w| w == b"foo".as_ref()).unwrap();
+    let mut corrupt = bin.clone();
+    corrupt[pos] = b'X';
+    let records = stream_all(&corrupt);
+    assert_eq!(records.len(), 4);
+    assert!(records[0..3].iter().all(|r| r.is_ok()));
+    match records[3] {
+        Err(DBError::Parse{ref path, ref err, ..}) => {
+            assert_eq!(path, "objects[\"foo\"].checksum");
+            assert_eq!(format!("{}", err), "File format error: checksum mismatch in table foo");
+        }
+        _ => panic!("expected a checksum error"),
+    }
+
+    for len in 0..bin.len() {
+        let mut input = &bin[0..len];
+        if let Ok(stream) = DBStream::new(&mut input) {
+            assert!(stream.last().unwrap().is_err());
+        }
+    }
+}
@@ -0,0 +1,268 @@
+extern crate memrange;
+
+use self::memrange::Range;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+use db::DB;
+use content::Object;
+use content::Bitmap;
+use dberror::DBError;
+use persistent_tree::PersistentTree;
+
+type TableLock = Arc<RwLock<DB>>;
+
+/// Handle to a DB that can be shared between threads. Clones of the handle
+/// refer to the same data.
+///
+/// Every table is kept in a DB of its own behind its own lock, so any number
+/// of readers and a single writer can work on a table at the same time while
+/// other tables stay fully available. The directory of tables has a lock of
+/// its own that is only held while a table is looked up, created or removed.
+/// Mutations made through a `SharedDB` are not journaled.
+#[derive(Clone)]
+pub struct SharedDB {
+    tables: Arc<RwLock<BTreeMap<String, TableLock>>>,
+}
+
+fn single_table_db(table: &String, objects: PersistentTree<Object>, bitmaps: PersistentTree<Bitmap>) -> TableLock {
+    let mut obj_map = BTreeMap::new();
+    let mut bit_map = BTreeMap::new();
+    obj_map.insert(table.clone(), objects);
+    bit_map.insert(table.clone(), bitmaps);
+    return Arc::new(RwLock::new(DB::new_from_shared_data(obj_map, bit_map)));
+}
+
+impl SharedDB {
+    pub fn new() -> SharedDB {
+        return SharedDB{tables: Arc::new(RwLock::new(BTreeMap::new()))};
+    }
+
+    /// Takes over all tables of `db`. Tables of a lazily opened DB stay lazy.
+    /// Mutations of a `SharedDB` are not journaled, so this fails and returns
+    /// `db` if it has a journal, see `DB::close_journal`.
+    pub fn from_db(mut db: DB) -> Result<SharedDB, DB> {
+        if db.journal.is_some() {
+            return Err(db);
+        }
+        let mut tables = BTreeMap::new();
+        let names = db.tables().into_iter().cloned().collect::<Vec<String>>();
+        for name in names {
+            let mut part = DB::new();
+            if let Some(objects) = db.backend.obj_map.remove(&name) {
+                part.backend.obj_map.insert(name.clone(), objects);
+            }
+            if let Some(bitmaps) = db.backend.bit_map.remove(&name) {
+                part.backend.bit_map.insert(name.clone(), bitmaps);
+            }
+            if let Some(options) = db.options.remove(&name) {
+                part.options.insert(name.clone(), options);
+            }
+            part.backend.lazy = db.backend.lazy.as_ref().map(|lazy| lazy.restrict(&name));
+            tables.insert(name.clone(), Arc::new(RwLock::new(part)));
+        }
+        return Ok(SharedDB{tables: Arc::new(RwLock::new(tables))});
+    }
+
+    /// Reassembles a plain DB, fails and returns the handle if it is still
+    /// shared with other handles or table locks are held.
+    pub fn into_db(self) -> Result<DB, SharedDB> {
+        let lock = match Arc::try_unwrap(self.tables) {
+            Ok(lock) => lock,
+            Err(tables) => return Err(SharedDB{tables: tables}),
+        };
+        let tables = lock.into_inner().expect("table directory lock poisoned");
+        let mut db = DB::new();
+        for (_, table) in tables {
+            let part = match Arc::try_unwrap(table) {
+                Ok(part) => part.into_inner().expect("table lock poisoned"),
+                Err(_) => unreachable!("table locks are only shared while the directory is"),
+            };
+            db.merge_tables(&part);
+        }
+        return Ok(db);
+    }
+
+    fn get_table(&self, table: &String) -> Option<TableLock> {
+        let tables = self.tables.read().expect("table directory lock poisoned");
+        return tables.get(table).cloned();
+    }
+
+    fn get_or_create_table(&self, table: &String) -> TableLock {
+        if let Some(lock) = self.get_table(table) {
+            return lock;
+        }
+        let mut tables = self.tables.write().expect("table directory lock poisoned");
+        return tables.entry(table.clone())
+                     .or_insert_with(|| single_table_db(table, PersistentTree::new(), PersistentTree::new()))
+                     .clone();
+    }
+
+    /// Runs `f` with shared access to the DB holding `table`. Returns None if
+    /// the table does not exist.
+    pub fn read_table<F, R>(&self, table: &String, f: F) -> Option<R>
+        where F: FnOnce(&DB) -> R {
+        return self.get_table(table).map(|lock| {
+            let db = lock.read().expect("table lock poisoned");
+            f(&db)
+        });
+    }
+
+    /// Runs `f` with exclusive access to the DB holding `table`, creating the
+    /// table if needed. `f` must only touch `table`.
+    pub fn write_table<F, R>(&self, table: &String, f: F) -> R
+        where F: FnOnce(&mut DB) -> R {
+        let lock = self.get_or_create_table(table);
+        let mut db = lock.write().expect("table lock poisoned");
+        return f(&mut db);
+    }
+
+    /// Runs `f` on every table while holding the read locks of all of them.
+    pub(crate) fn with_all_tables<F>(&self, mut f: F)
+        where F: FnMut(&DB) {
+        let tables = self.tables.read().expect("table directory lock poisoned");
+        let guards = tables.values().map(|lock| lock.read().expect("table lock poisoned")).collect::<Vec<_>>();
+        for db in guards.iter() {
+            f(db);
+        }
+    }
+
+    pub fn has_table(&self, table: &String) -> bool {
+        return self.get_table(table).is_some();
+    }
+
+    pub fn tables(&self) -> Vec<String> {
+        let tables = self.tables.read().expect("table directory lock poisoned");
+        return tables.keys().cloned().collect();
+    }
+
+    #[must_use]
+    pub fn insert_object(&self, table: &String, r: Range, d: Object) -> Result<(), DBError> {
+        return self.write_table(table, |db| db.insert_object(table, r, d));
+    }
+
+    #[must_use]
+    pub fn delete_object(&self, table: &String, r: Range) -> Result<(), DBError> {
+        if let Some(lock) = self.get_table(table) {
+            return lock.write().expect("table lock poisoned").delete_object(table, r);
+        }
+        return Ok(());
+    }
+
+    #[must_use]
+    pub fn delete_object_value(&self, table: &String, r: Range, d: &Object) -> Result<(), DBError> {
+        if let Some(lock) = self.get_table(table) {
+            return lock.write().expect("table lock poisoned").delete_object_value(table, r, d);
+        }
+        return Ok(());
+    }
+
+    #[must_use]
+    pub fn delete_intersecting_objects(&self, table: &String, r: Range) -> Result<(), DBError> {
+        if let Some(lock) = self.get_table(table) {
+            return lock.write().expect("table lock poisoned").delete_intersecting_objects(table, r);
+        }
+        return Ok(());
+    }
+
+    #[must_use]
+    pub fn punch_hole(&self, table: &String, r: Range) -> Result<(), DBError> {
+        if let Some(lock) = self.get_table(table) {
+            return lock.write().expect("table lock poisoned").punch_hole(table, r);
+        }
+        return Ok(());
+    }
+
+    #[must_use]
+    pub fn insert_bitmap(&self, table: &String, r: Range, d: Bitmap) -> Result<(), DBError> {
+        try!(d.check_length(r));
+        return self.write_table(table, |db| db.insert_bitmap(table, r, d));
+    }
+
+    #[must_use]
+    pub fn delete_bitmap(&self, table: &String, entry_size: u64, r: Range) -> Result<(), DBError> {
+        return match self.get_table(table) {
+            Some(lock) => lock.write().expect("table lock poisoned").delete_bitmap(table, entry_size, r),
+            None => Ok(()),
+        };
+    }
+
+    #[must_use]
+    pub fn create_table(&self, table: &String) -> Result<(), DBError> {
+        let mut tables = self.tables.write().expect("table directory lock poisoned");
+        if tables.contains_key(table) {
+            return Err(DBError::TableExists(table.clone()));
+        }
+        tables.insert(table.clone(), single_table_db(table, PersistentTree::new(), PersistentTree::new()));
+        return Ok(());
+    }
+
+    /// Removes the table from the directory. Readers that are still working
+    /// on it finish on the old data.
+    #[must_use]
+    pub fn drop_table(&self, table: &String) -> Result<(), DBError> {
+        let mut tables = self.tables.write().expect("table directory lock poisoned");
+        return match tables.remove(table) {
+            Some(_) => Ok(()),
+            None => Err(DBError::NoSuchTable(table.clone())),
+        };
+    }
+
+    #[must_use]
+    pub fn rename_table(&self, from: &String, to: &String) -> Result<(), DBError> {
+        let mut tables = self.tables.write().expect("table directory lock poisoned");
+        if !tables.contains_key(from) {
+            return Err(DBError::NoSuchTable(from.clone()));
+        }
+        if tables.contains_key(to) {
+            return Err(DBError::TableExists(to.clone()));
+        }
+        let lock = tables.remove(from).unwrap();
+        try!(lock.write().expect("table lock poisoned").rename_table(from, to));
+        tables.insert(to.clone(), lock);
+        return Ok(());
+    }
+
+    #[must_use]
+    pub fn clear_table(&self, table: &String) -> Result<(), DBError> {
+        return match self.get_table(table) {
+            Some(lock) => lock.write().expect("table lock poisoned").clear_table(table),
+            None => Err(DBError::NoSuchTable(table.clone())),
+        };
+    }
+}
+
+#[test]
+fn test_shared_db_threads() {
+    use std::thread;
+
+    let shared = SharedDB::new();
+    let workers = (0..4).map(|t| {
+        let shared = shared.clone();
+        thread::spawn(move || {
+            let tbl = format!("tbl{}", t);
+            for i in 0..100 {
+                shared.insert_object(&tbl, Range::new(i, i), Object::new(vec![t as u8])).unwrap();
+                shared.insert_bitmap(&tbl, Range::new(i, i), Bitmap::new(1, vec![t as u8])).unwrap();
+                let seen = shared.read_table(&tbl, |db| db.query_object(&tbl, Range::new(0, 1000)).unwrap().count());
+                assert_eq!(seen, Some(i as usize + 1));
+            }
+        })
+    }).collect::<Vec<_>>();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    assert_eq!(shared.tables(), vec!["tbl0", "tbl1", "tbl2", "tbl3"]);
+    let (tbl0, renamed) = ("tbl0".to_string(), "renamed".to_string());
+    shared.rename_table(&tbl0, &renamed).unwrap();
+    shared.drop_table(&"tbl1".to_string()).unwrap();
+
+    let db = shared.into_db().ok().unwrap();
+    assert_eq!(db.tables(), vec!["renamed", "tbl2", "tbl3"]);
+    let bitmaps = db.query_bitmap(&renamed, Range::new(0, 1000)).unwrap()
+                    .map(|e| e.map(|(r, b)| (r, b.to_bitmap())).unwrap())
+                    .collect::<Vec<(Range, Bitmap)>>();
+    assert_eq!(bitmaps, vec![(Range::new(0, 99), Bitmap::new(1, vec![0; 100]))]);
+}
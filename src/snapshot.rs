@@ -0,0 +1,131 @@
+extern crate memrange;
+
+use self::memrange::Range;
+
+use db::DB;
+use shared_db::SharedDB;
+use content::Object;
+#[cfg(test)] use content::Bitmap;
+#[cfg(test)] use std::borrow::Cow;
+use db_iterator::BitmapSliceIter;
+use multimap::ObjectValuesIter;
+use backend::MemoryIter;
+
+/// Immutable point-in-time view of a DB. Taking a snapshot only clones the
+/// handles to the trees. A later write to the live DB copies the nodes on the
+/// path to the changed entry, everything else stays shared.
+pub struct Snapshot {
+    db: DB,
+}
+
+impl Snapshot {
+    pub fn query_object(&self, table: &String, r: Range) -> Option<MemoryIter<Object>> {
+        return self.db.query_object(table, r);
+    }
+
+    pub fn query_bitmap<'a>(&'a self, table: &String, r: Range) -> Option<BitmapSliceIter<'a>> {
+        return self.db.query_bitmap(table, r);
+    }
+
+    pub fn query_object_values<'a>(&'a self, table: &String, r: Range) -> Option<ObjectValuesIter<'a, MemoryIter<'a, Object>>> {
+        return self.db.query_object_values(table, r);
+    }
+
+    pub fn has_table(&self, table: &String) -> bool {
+        return self.db.has_table(table);
+    }
+
+    /// Names of all tables in ascending order.
+    pub fn tables(&self) -> Vec<&String> {
+        return self.db.tables();
+    }
+
+    /// Turns the snapshot into a new, independent DB.
+    pub fn into_db(self) -> DB {
+        return self.db;
+    }
+}
+
+impl Clone for Snapshot {
+    fn clone(&self) -> Snapshot {
+        return self.db.snapshot();
+    }
+}
+
+impl DB {
+    pub fn snapshot(&self) -> Snapshot {
+        let mut db = DB::with_backend(self.backend.clone());
+        db.options = self.options.clone();
+        return Snapshot{db: db};
+    }
+
+    /// Adds the tables of `other`, a DB holding different tables.
+    pub(crate) fn merge_tables(&mut self, other: &DB) {
+        self.options.extend(other.options.iter().map(|(name, options)| (name.clone(), options.clone())));
+        let (backend, other) = (&mut self.backend, &other.backend);
+        backend.obj_map.extend(other.obj_map.iter().map(|(name, tree)| (name.clone(), tree.clone())));
+        backend.bit_map.extend(other.bit_map.iter().map(|(name, tree)| (name.clone(), tree.clone())));
+        if let Some(ref other_lazy) = other.lazy {
+            match backend.lazy {
+                Some(ref mut lazy) => lazy.merge(other_lazy),
+                None => backend.lazy = Some(other_lazy.clone()),
+            }
+        }
+    }
+}
+
+impl SharedDB {
+    /// Takes a snapshot of all tables. Every table is read locked until the
+    /// snapshot is complete, so it reflects a single point in time.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut snapshot = DB::new();
+        self.with_all_tables(|db| snapshot.merge_tables(db));
+        return Snapshot{db: snapshot};
+    }
+}
+
+#[test]
+fn test_snapshot_isolation() {
+    let (foo, bar) = ("foo".to_string(), "bar".to_string());
+    let mut db = DB::new();
+    db.insert_object(&foo, Range::new(0, 10), Object::new(vec![1])).unwrap();
+    db.insert_object(&bar, Range::new(0, 10), Object::new(vec![2])).unwrap();
+    db.insert_bitmap(&foo, Range::new(0, 3), Bitmap::new(1, vec![1, 2, 3, 4])).unwrap();
+
+    let snap = db.snapshot();
+    db.insert_object(&foo, Range::new(20, 30), Object::new(vec![3])).unwrap();
+    db.delete_object(&foo, Range::new(0, 10)).unwrap();
+    db.delete_bitmap(&foo, 1, Range::new(0, 1)).unwrap();
+    db.drop_table(&bar).unwrap();
+
+    let objects = snap.query_object(&foo, Range::new(0, 100)).unwrap().map(|e| e.unwrap()).collect::<Vec<_>>();
+    assert_eq!(objects, vec![(Range::new(0, 10), Cow::Borrowed(&Object::new(vec![1])))]);
+    let bitmaps = snap.query_bitmap(&foo, Range::new(0, 100)).unwrap()
+                      .map(|e| e.map(|(r, b)| (r, b.to_bitmap())).unwrap())
+                      .collect::<Vec<(Range, Bitmap)>>();
+    assert_eq!(bitmaps, vec![(Range::new(0, 3), Bitmap::new(1, vec![1, 2, 3, 4]))]);
+    assert_eq!(snap.tables(), vec!["bar", "foo"]);
+    assert_eq!(db.tables(), vec!["foo"]);
+
+    let objects = db.query_object(&foo, Range::new(0, 100)).unwrap().map(|e| e.unwrap()).collect::<Vec<_>>();
+    assert_eq!(objects, vec![(Range::new(20, 30), Cow::Borrowed(&Object::new(vec![3])))]);
+}
+
+#[test]
+fn test_snapshot_sharing() {
+    let (foo, bar) = ("foo".to_string(), "bar".to_string());
+    let mut db = DB::new();
+    db.insert_object(&foo, Range::new(0, 10), Object::new(vec![1])).unwrap();
+    db.insert_object(&bar, Range::new(0, 10), Object::new(vec![2])).unwrap();
+
+    let snap = db.snapshot();
+    db.insert_object(&foo, Range::new(20, 30), Object::new(vec![3])).unwrap();
+    assert!(!snap.db.backend.obj_map[&foo].ptr_eq(&db.backend.obj_map[&foo]));
+    assert!(snap.db.backend.obj_map[&bar].ptr_eq(&db.backend.obj_map[&bar]));
+
+    let shared = SharedDB::from_db(db).ok().unwrap();
+    let snap = shared.snapshot();
+    shared.insert_object(&bar, Range::new(20, 30), Object::new(vec![4])).unwrap();
+    assert_eq!(snap.query_object(&bar, Range::new(0, 100)).unwrap().count(), 1);
+    assert_eq!(snap.into_db().query_object(&foo, Range::new(0, 100)).unwrap().count(), 2);
+}
@@ -0,0 +1,177 @@
+use memrange::Range;
+
+use std::u64;
+
+use db::DB;
+use backend::StorageBackend;
+use dberror::DBError;
+use journal::Entry;
+
+#[cfg(test)] use content::Object;
+
+/// What `insert_object` does with objects that intersect the new one. An
+/// object stored at exactly the same range does not count, it is replaced or,
+/// in a multimap table, gets another value.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OverlapPolicy {
+    /// Objects may overlap.
+    Allow,
+    /// The insert fails with `DBError::Overlap`.
+    Reject,
+    /// The intersecting objects are removed.
+    Evict,
+}
+
+impl Default for OverlapPolicy {
+    fn default() -> OverlapPolicy {
+        return OverlapPolicy::Allow;
+    }
+}
+
+/// Settings of a table. They are journaled and saved together with the
+/// contents of the table.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct TableOptions {
+    /// The table holds any number of distinct objects per range, see
+    /// `DB::query_object_values`. The values of a range are stored packed
+    /// into a single msgpack array, which is what `query_object` returns.
+    /// Every insert or delete of a value unpacks and rewrites the whole
+    /// array, so it costs time linear in the number of values of the range.
+    pub multimap: bool,
+    /// Tables with a policy other than `Allow` never contain intersecting
+    /// objects.
+    pub overlap: OverlapPolicy,
+    /// An inserted object is merged with the objects holding the same data
+    /// that it touches or intersects. Not supported for multimap tables.
+    pub coalesce: bool,
+}
+
+impl<B: StorageBackend> DB<B> {
+    /// Options of `table`, the defaults if none were set.
+    pub fn table_options(&self, table: &String) -> TableOptions {
+        return self.options.get(table).cloned().unwrap_or_default();
+    }
+
+    /// Sets the options of `table`, creating the table if it does not exist.
+    /// `multimap` can only be changed while the table holds no objects, an
+    /// `overlap` policy other than `Allow` only be set if no objects of the
+    /// table intersect.
+    #[must_use]
+    pub fn set_table_options(&mut self, table: &String, options: TableOptions) -> Result<(), DBError> {
+        let has_objects = match self.query_object(table, Range::new(0, u64::MAX)).and_then(|mut iter| iter.next()) {
+            Some(Err(e)) => return Err(e),
+            Some(Ok(_)) => true,
+            None => false,
+        };
+        if has_objects && options.multimap != self.table_options(table).multimap {
+            return Err(DBError::TableNotEmpty(table.clone()));
+        }
+        if options.multimap && options.coalesce {
+            return Err(DBError::Protocol("multimap tables can not coalesce objects".into()));
+        }
+        if options.overlap != OverlapPolicy::Allow {
+            if let Some((range, existing)) = try!(self.first_overlap(table)) {
+                return Err(DBError::Overlap{range: range, existing: existing});
+            }
+        }
+        try!(self.backend_add_table(table));
+        self.log(Entry::SetTableOptions(table, &options));
+        self.set_options(table, if options == TableOptions::default() { None } else { Some(options) });
+        return Ok(());
+    }
+
+    /// The first pair of intersecting objects in `table`.
+    fn first_overlap(&self, table: &String) -> Result<Option<(Range, Range)>, DBError> {
+        let mut previous: Option<Range> = None;
+        for entry in self.query_object(table, Range::new(0, u64::MAX)).into_iter().flatten() {
+            let (rng, _) = try!(entry);
+            if let Some(prev) = previous {
+                if prev.max >= rng.min {
+                    return Ok(Some((rng, prev)));
+                }
+            }
+            if previous.map(|prev| prev.max < rng.max).unwrap_or(true) {
+                previous = Some(rng);
+            }
+        }
+        return Ok(None);
+    }
+
+    /// Objects to remove before `r` is inserted into `table`, as demanded by
+    /// the overlap policy of the table. The objects in `merged` are replaced
+    /// by the new one anyway.
+    pub(crate) fn overlapping_objects(&self, table: &String, r: Range, merged: &Vec<Range>) -> Result<Vec<Range>, DBError> {
+        let policy = self.options.get(table).map(|options| options.overlap).unwrap_or_default();
+        if policy == OverlapPolicy::Allow {
+            return Ok(vec![]);
+        }
+        let mut ranges = vec![];
+        for entry in self.query_object(table, r).into_iter().flatten() {
+            let (rng, _) = try!(entry);
+            if rng != r && !merged.contains(&rng) {
+                ranges.push(rng);
+            }
+        }
+        if let (OverlapPolicy::Reject, Some(&existing)) = (policy, ranges.first()) {
+            return Err(DBError::Overlap{range: r, existing: existing});
+        }
+        return Ok(ranges);
+    }
+}
+
+#[test]
+fn test_table_options() {
+    let (foo, bar) = ("foo".to_string(), "bar".to_string());
+    let multimap = TableOptions{multimap: true, ..TableOptions::default()};
+    let mut db = DB::new();
+    db.set_table_options(&foo, multimap.clone()).unwrap();
+    assert!(db.has_table(&foo));
+    db.insert_object(&foo, Range::new(0, 1), Object::new(vec![1])).unwrap();
+    assert!(db.set_table_options(&foo, TableOptions::default()).is_err());
+    db.rename_table(&foo, &bar).unwrap();
+    assert_eq!(db.table_options(&foo), TableOptions::default());
+    assert_eq!(db.table_options(&bar), multimap);
+
+    let loaded = DB::deserialize(db.serialize().unwrap()).unwrap();
+    assert_eq!(loaded.table_options(&bar), multimap);
+    db.clear_table(&bar).unwrap();
+    db.set_table_options(&bar, TableOptions::default()).unwrap();
+    db.drop_table(&bar).unwrap();
+    assert!(db.options.is_empty());
+}
+
+#[cfg(test)]
+fn object_ranges(db: &DB, tbl: &String) -> Vec<Range> {
+    return db.query_object(tbl, Range::new(0, 100)).unwrap().map(|e| e.unwrap().0).collect();
+}
+
+#[test]
+fn test_overlap_policy() {
+    let (foo, bar) = ("foo".to_string(), "bar".to_string());
+    let mut db = DB::new();
+    db.set_table_options(&foo, TableOptions{overlap: OverlapPolicy::Reject, ..TableOptions::default()}).unwrap();
+    db.insert_object(&foo, Range::new(0, 9), Object::new(vec![1])).unwrap();
+    match db.insert_object(&foo, Range::new(5, 15), Object::new(vec![2])) {
+        Err(DBError::Overlap{range, existing}) => assert_eq!((range, existing), (Range::new(5, 15), Range::new(0, 9))),
+        res => panic!("unexpected result {:?}", res),
+    }
+    db.insert_object(&foo, Range::new(0, 9), Object::new(vec![3])).unwrap();
+    db.insert_object(&foo, Range::new(10, 15), Object::new(vec![4])).unwrap();
+    assert_eq!(object_ranges(&db, &foo), vec![Range::new(0, 9), Range::new(10, 15)]);
+
+    db.set_table_options(&foo, TableOptions{overlap: OverlapPolicy::Evict, ..TableOptions::default()}).unwrap();
+    db.insert_object(&foo, Range::new(5, 12), Object::new(vec![5])).unwrap();
+    db.insert_object(&foo, Range::new(20, 30), Object::new(vec![6])).unwrap();
+    assert_eq!(object_ranges(&db, &foo), vec![Range::new(5, 12), Range::new(20, 30)]);
+
+    db.insert_object(&bar, Range::new(0, 9), Object::new(vec![1])).unwrap();
+    db.insert_object(&bar, Range::new(2, 3), Object::new(vec![1])).unwrap();
+    db.insert_object(&bar, Range::new(5, 6), Object::new(vec![1])).unwrap();
+    assert!(db.set_table_options(&bar, TableOptions{overlap: OverlapPolicy::Reject, ..TableOptions::default()}).is_err());
+    db.delete_object(&bar, Range::new(0, 9)).unwrap();
+    db.set_table_options(&bar, TableOptions{overlap: OverlapPolicy::Reject, ..TableOptions::default()}).unwrap();
+
+    let loaded = DB::deserialize(db.serialize().unwrap()).unwrap();
+    assert_eq!(loaded.table_options(&foo).overlap, OverlapPolicy::Evict);
+    assert_eq!(loaded.table_options(&bar).overlap, OverlapPolicy::Reject);
+}
@@ -0,0 +1,397 @@
+use memrange::Range;
+
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::u64;
+
+use db::DB;
+use backend::StorageBackend;
+use content::Object;
+use content::Bitmap;
+use dberror::DBError;
+use table_options::TableOptions;
+
+#[cfg(test)] use backend::VecBackend;
+
+/// All objects and bitmaps of a table.
+type TableEntries = (Vec<(Range, Object)>, Vec<(Range, Bitmap)>);
+
+/// Inverse of a single change made to the backend or the table options.
+enum Undo {
+    /// Stores the object again, or removes the one at the range if None.
+    Object(String, Range, Option<Object>),
+    Bitmap(String, Range, Option<Bitmap>),
+    /// Drops a table that was created.
+    DropTable(String),
+    /// Recreates a dropped or cleared table with its former entries.
+    RestoreTable(String, TableEntries),
+    /// Renames the first table back to the second.
+    RenameTable(String, String),
+    Options(String, Option<TableOptions>),
+}
+
+/// Changes made by the running transactions, recorded so they can be undone
+/// in reverse order.
+#[derive(Default)]
+pub(crate) struct UndoLog {
+    entries: Vec<Undo>,
+    depth: usize,
+}
+
+impl UndoLog {
+    fn begin(&mut self) -> usize {
+        self.depth += 1;
+        return self.entries.len();
+    }
+
+    fn commit(&mut self) {
+        self.depth -= 1;
+        // an enclosing transaction may still have to undo the changes
+        if self.depth == 0 {
+            self.entries.clear();
+        }
+    }
+
+    fn is_recording(&self) -> bool {
+        return self.depth > 0;
+    }
+}
+
+impl<B: StorageBackend> DB<B> {
+    /// Runs `f` as one atomic update. If `f` returns an error or panics, every
+    /// mutation it made is discarded and the error or panic is passed on.
+    /// Otherwise its mutations are journaled as a single record, so replay
+    /// after a crash applies either all or none of them.
+    ///
+    /// While `f` runs, every change to the backend records the entry it
+    /// replaces, which rollback puts back in reverse order. Dropping or
+    /// clearing a table inside a transaction keeps a copy of its entries. If
+    /// the backend fails during rollback, that error is returned and the
+    /// tables are left partially rolled back.
+    pub fn transaction<F, R>(&mut self, f: F) -> Result<R, DBError>
+        where F: FnOnce(&mut DB<B>) -> Result<R, DBError> {
+        let undo_mark = self.undo.begin();
+        let mark = self.journal.as_mut().map(|journal| journal.begin());
+        let res = panic::catch_unwind(AssertUnwindSafe(|| f(self)));
+        let mut undone = Ok(());
+        if let Ok(Ok(_)) = res {
+            if let Some(ref mut journal) = self.journal {
+                journal.commit();
+            }
+            self.undo.commit();
+        } else {
+            undone = self.rollback(undo_mark);
+            if let (Some(journal), Some(mark)) = (self.journal.as_mut(), mark) {
+                journal.rollback(mark);
+            }
+        }
+        return match res {
+            Ok(res) => undone.and(res),
+            Err(payload) => panic::resume_unwind(payload),
+        };
+    }
+
+    /// Undoes the changes recorded since `mark`. Returns the first error of
+    /// the backend but keeps undoing the remaining changes.
+    fn rollback(&mut self, mark: usize) -> Result<(), DBError> {
+        let mut res = Ok(());
+        while self.undo.entries.len() > mark {
+            let entry = self.undo.entries.pop().unwrap();
+            let undone = self.apply_undo(entry);
+            if res.is_ok() {
+                res = undone;
+            }
+        }
+        self.undo.depth -= 1;
+        return res;
+    }
+
+    fn apply_undo(&mut self, entry: Undo) -> Result<(), DBError> {
+        match entry {
+            Undo::Object(table, r, Some(d)) => return self.backend.insert_object(&table, r, d),
+            Undo::Object(table, r, None) => return self.backend.delete_object(&table, r),
+            Undo::Bitmap(table, r, Some(d)) => return self.backend.insert_bitmap(&table, r, d),
+            Undo::Bitmap(table, r, None) => return self.backend.delete_bitmap(&table, r),
+            Undo::DropTable(table) => return self.backend.drop_table(&table),
+            Undo::RestoreTable(table, (objects, bitmaps)) => {
+                try!(self.backend.add_table(&table));
+                try!(self.backend.clear_table(&table));
+                for (rng, d) in objects {
+                    try!(self.backend.insert_object(&table, rng, d));
+                }
+                for (rng, d) in bitmaps {
+                    try!(self.backend.insert_bitmap(&table, rng, d));
+                }
+                return Ok(());
+            }
+            Undo::RenameTable(from, to) => return self.backend.rename_table(&from, &to),
+            Undo::Options(table, Some(options)) => {
+                self.options.insert(table, options);
+                return Ok(());
+            }
+            Undo::Options(table, None) => {
+                self.options.remove(&table);
+                return Ok(());
+            }
+        }
+    }
+
+    fn record(&mut self, entry: Undo) {
+        if self.undo.is_recording() {
+            self.undo.entries.push(entry);
+        }
+    }
+
+    fn object_at(&self, table: &String, r: Range) -> Result<Option<Object>, DBError> {
+        for entry in self.backend.query_objects(table, r).into_iter().flatten() {
+            let (rng, d) = try!(entry);
+            if rng == r {
+                return Ok(Some(d.into_owned()));
+            }
+        }
+        return Ok(None);
+    }
+
+    fn bitmap_at(&self, table: &String, r: Range) -> Result<Option<Bitmap>, DBError> {
+        for entry in self.backend.query_bitmaps(table, r).into_iter().flatten() {
+            let (rng, d) = try!(entry);
+            if rng == r {
+                return Ok(Some(d.into_owned()));
+            }
+        }
+        return Ok(None);
+    }
+
+    /// Copy of all entries of `table`.
+    fn table_entries(&self, table: &String) -> Result<TableEntries, DBError> {
+        let mut objects = vec![];
+        for entry in self.backend.query_objects(table, Range::new(0, u64::MAX)).into_iter().flatten() {
+            let (rng, d) = try!(entry);
+            objects.push((rng, d.into_owned()));
+        }
+        let mut bitmaps = vec![];
+        for entry in self.backend.query_bitmaps(table, Range::new(0, u64::MAX)).into_iter().flatten() {
+            let (rng, d) = try!(entry);
+            bitmaps.push((rng, d.into_owned()));
+        }
+        return Ok((objects, bitmaps));
+    }
+
+    // The backend is changed through the methods below only, so a
+    // transaction can undo every change.
+
+    pub(crate) fn backend_add_table(&mut self, table: &String) -> Result<(), DBError> {
+        if self.undo.is_recording() && !self.backend.has_table(table) {
+            self.record(Undo::DropTable(table.clone()));
+        }
+        return self.backend.add_table(table);
+    }
+
+    pub(crate) fn backend_drop_table(&mut self, table: &String) -> Result<(), DBError> {
+        if self.undo.is_recording() {
+            let entries = try!(self.table_entries(table));
+            self.record(Undo::RestoreTable(table.clone(), entries));
+        }
+        return self.backend.drop_table(table);
+    }
+
+    pub(crate) fn backend_clear_table(&mut self, table: &String) -> Result<(), DBError> {
+        if self.undo.is_recording() {
+            let entries = try!(self.table_entries(table));
+            self.record(Undo::RestoreTable(table.clone(), entries));
+        }
+        return self.backend.clear_table(table);
+    }
+
+    pub(crate) fn backend_rename_table(&mut self, from: &String, to: &String) -> Result<(), DBError> {
+        self.record(Undo::RenameTable(to.clone(), from.clone()));
+        return self.backend.rename_table(from, to);
+    }
+
+    pub(crate) fn backend_insert_object(&mut self, table: &String, r: Range, d: Object) -> Result<(), DBError> {
+        try!(self.backend_add_table(table));
+        if self.undo.is_recording() {
+            let previous = try!(self.object_at(table, r));
+            self.record(Undo::Object(table.clone(), r, previous));
+        }
+        return self.backend.insert_object(table, r, d);
+    }
+
+    pub(crate) fn backend_delete_object(&mut self, table: &String, r: Range) -> Result<(), DBError> {
+        if self.undo.is_recording() {
+            if let Some(previous) = try!(self.object_at(table, r)) {
+                self.record(Undo::Object(table.clone(), r, Some(previous)));
+            }
+        }
+        return self.backend.delete_object(table, r);
+    }
+
+    pub(crate) fn backend_insert_bitmap(&mut self, table: &String, r: Range, d: Bitmap) -> Result<(), DBError> {
+        try!(self.backend_add_table(table));
+        if self.undo.is_recording() {
+            let previous = try!(self.bitmap_at(table, r));
+            self.record(Undo::Bitmap(table.clone(), r, previous));
+        }
+        return self.backend.insert_bitmap(table, r, d);
+    }
+
+    pub(crate) fn backend_delete_bitmap(&mut self, table: &String, r: Range) -> Result<(), DBError> {
+        if self.undo.is_recording() {
+            if let Some(previous) = try!(self.bitmap_at(table, r)) {
+                self.record(Undo::Bitmap(table.clone(), r, Some(previous)));
+            }
+        }
+        return self.backend.delete_bitmap(table, r);
+    }
+
+    /// Records that a bulk load stored objects at `ranges`, which were empty.
+    pub(crate) fn record_loaded_objects<I: Iterator<Item=Range>>(&mut self, table: &String, ranges: I) {
+        if self.undo.is_recording() {
+            self.undo.entries.extend(ranges.map(|rng| Undo::Object(table.clone(), rng, None)));
+        }
+    }
+
+    pub(crate) fn record_loaded_bitmaps<I: Iterator<Item=Range>>(&mut self, table: &String, ranges: I) {
+        if self.undo.is_recording() {
+            self.undo.entries.extend(ranges.map(|rng| Undo::Bitmap(table.clone(), rng, None)));
+        }
+    }
+
+    /// Sets the options of `table`, None for the defaults.
+    pub(crate) fn set_options(&mut self, table: &String, options: Option<TableOptions>) {
+        let previous = match options {
+            Some(options) => self.options.insert(table.clone(), options),
+            None => self.options.remove(table),
+        };
+        self.record(Undo::Options(table.clone(), previous));
+    }
+}
+
+#[cfg(test)]
+fn object_ranges(db: &DB, tbl: &String) -> Vec<Range> {
+    return db.query_object(tbl, Range::new(0, 100))
+             .map(|iter| iter.map(|e| e.unwrap().0).collect())
+             .unwrap_or(vec![]);
+}
+
+#[test]
+fn test_transaction_rollback() {
+    let tbl = "foo".to_string();
+    let mut db = DB::new();
+    db.insert_object(&tbl, Range::new(0, 10), Object::new(vec![1])).unwrap();
+
+    let res = db.transaction(|tx| {
+        try!(tx.delete_intersecting_objects(&tbl, Range::new(0, 100)));
+        tx.insert_object(&tbl, Range::new(20, 30), Object::new(vec![2])).unwrap();
+        try!(tx.insert_bitmap(&tbl, Range::new(0, 1), Bitmap::new(1, vec![1, 2, 3])));
+        return Ok(());
+    });
+    assert!(res.is_err());
+    assert_eq!(object_ranges(&db, &tbl), vec![Range::new(0, 10)]);
+
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        db.transaction(|tx| -> Result<(), DBError> {
+            try!(tx.delete_object(&tbl, Range::new(0, 10)));
+            panic!("aborted");
+        })
+    }));
+    assert!(res.is_err());
+    assert_eq!(object_ranges(&db, &tbl), vec![Range::new(0, 10)]);
+
+    let count = db.transaction(|tx| {
+        try!(tx.delete_intersecting_objects(&tbl, Range::new(0, 100)));
+        tx.insert_object(&tbl, Range::new(20, 30), Object::new(vec![2])).unwrap();
+        try!(tx.insert_bitmap(&tbl, Range::new(0, 1), Bitmap::new(1, vec![1, 2])));
+        return Ok(tx.query_object(&tbl, Range::new(0, 100)).unwrap().count());
+    }).unwrap();
+    assert_eq!(count, 1);
+    assert_eq!(object_ranges(&db, &tbl), vec![Range::new(20, 30)]);
+}
+
+#[test]
+fn test_transaction_journal() {
+    let dir = ::std::env::temp_dir();
+    let snapshot = dir.join(format!("theban_db_tx_{}.db", ::std::process::id())).to_string_lossy().into_owned();
+    let journal = dir.join(format!("theban_db_tx_{}.log", ::std::process::id())).to_string_lossy().into_owned();
+    let _ = ::std::fs::remove_file(&snapshot);
+    let _ = ::std::fs::remove_file(&journal);
+    let (foo, bar) = ("foo".to_string(), "bar".to_string());
+    {
+        let mut db = DB::open_journaled(&snapshot, &journal).unwrap();
+        db.insert_object(&foo, Range::new(0, 10), Object::new(vec![1])).unwrap();
+        db.transaction(|tx| {
+            tx.insert_object(&foo, Range::new(20, 30), Object::new(vec![2])).unwrap();
+            return tx.transaction(|inner| {
+                inner.insert_object(&bar, Range::new(1, 2), Object::new(vec![3])).unwrap();
+                return inner.rename_table(&bar, &foo);
+            }).or_else(|_| tx.rename_table(&foo, &bar));
+        }).unwrap();
+        let res = db.transaction(|tx| {
+            tx.drop_table(&bar).unwrap();
+            return tx.checkpoint();
+        });
+        assert!(res.is_err());
+        db.sync_journal().unwrap();
+    }
+
+    let db = DB::open_journaled(&snapshot, &journal).unwrap();
+    assert_eq!(db.tables(), vec![&bar]);
+    assert_eq!(object_ranges(&db, &bar), vec![Range::new(0, 10), Range::new(20, 30)]);
+    ::std::fs::remove_file(&journal).unwrap();
+}
+
+#[cfg(test)]
+fn full_state<B: StorageBackend>(db: &DB<B>) -> Vec<(String, Option<TableOptions>, TableEntries)> {
+    return db.tables().into_iter().map(|table| {
+        (table.clone(), db.options.get(table).cloned(), db.table_entries(table).unwrap())
+    }).collect();
+}
+
+#[cfg(test)]
+fn check_undo<B: StorageBackend>(mut db: DB<B>) {
+    let (foo, bar, baz, qux) = ("foo".to_string(), "bar".to_string(), "baz".to_string(), "qux".to_string());
+    db.set_table_options(&foo, TableOptions{multimap: true, ..TableOptions::default()}).unwrap();
+    db.insert_object(&foo, Range::new(0, 10), Object::new(vec![1])).unwrap();
+    db.insert_object(&foo, Range::new(0, 10), Object::new(vec![2])).unwrap();
+    db.insert_object(&bar, Range::new(5, 6), Object::new(vec![3])).unwrap();
+    db.insert_bitmap(&bar, Range::new(0, 3), Bitmap::new(1, vec![1, 2, 3, 4])).unwrap();
+    let before = full_state(&db);
+
+    let res = db.transaction(|tx| -> Result<(), DBError> {
+        try!(tx.delete_object_value(&foo, Range::new(0, 10), &Object::new(vec![1])));
+        try!(tx.insert_bitmap(&bar, Range::new(4, 5), Bitmap::new(1, vec![5, 6])));
+        try!(tx.delete_bitmap(&bar, 1, Range::new(1, 1)));
+        try!(tx.punch_hole(&bar, Range::new(6, 6)));
+        try!(tx.transaction(|inner| {
+            try!(inner.rename_table(&foo, &baz));
+            return inner.create_table(&qux);
+        }));
+        try!(tx.set_table_options(&bar, TableOptions{coalesce: true, ..TableOptions::default()}));
+        try!(tx.clear_table(&bar));
+        try!(tx.drop_table(&baz));
+        return Err(DBError::Protocol("aborted".into()));
+    });
+    assert!(res.is_err());
+    assert_eq!(full_state(&db), before);
+
+    // an inner rollback keeps the changes of the enclosing transaction
+    db.transaction(|tx| {
+        try!(tx.delete_object(&bar, Range::new(5, 6)));
+        let res = tx.transaction(|inner| -> Result<(), DBError> {
+            try!(inner.drop_table(&foo));
+            return Err(DBError::Protocol("aborted".into()));
+        });
+        assert!(res.is_err());
+        return Ok(());
+    }).unwrap();
+    let after = full_state(&db);
+    assert_eq!((after[0].2).0, vec![]);
+    assert_eq!(after[1], before[1]);
+    assert!(db.undo.entries.is_empty() && db.undo.depth == 0);
+}
+
+#[test]
+fn test_transaction_undo() {
+    check_undo(DB::new());
+    check_undo(DB::with_backend(VecBackend::new()));
+}
@@ -0,0 +1,293 @@
+extern crate memrange;
+
+use self::memrange::Range;
+
+use std::collections::BTreeMap;
+use std::mem;
+
+use db::DB;
+use backend::StorageBackend;
+use content::Object;
+use content::Bitmap;
+use dberror::DBError;
+
+#[cfg(test)] use table_options::TableOptions;
+
+enum BatchOp {
+    InsertObject(Range, Object),
+    DeleteObject(Range),
+    DeleteObjectValue(Range, Object),
+    DeleteIntersectingObjects(Range),
+    PunchHole(Range),
+    InsertBitmap(Range, Bitmap),
+    DeleteBitmap(u64, Range),
+}
+
+/// Collects mutations to be applied to a DB in one go with `DB::write_batch`.
+///
+/// Operations keep their order per table. Runs of bitmap inserts with the
+/// same entry size are sorted and merged into as few bitmaps as possible
+/// before they touch the tree, so each of them is merged with the stored
+/// bitmaps only once. On tables without options, runs of object inserts and
+/// deletes only apply the last operation for each range, in range order.
+pub struct WriteBatch {
+    tables: BTreeMap<String, Vec<BatchOp>>,
+    len: usize,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        return WriteBatch{tables: BTreeMap::new(), len: 0};
+    }
+
+    /// Number of operations in the batch.
+    pub fn len(&self) -> usize {
+        return self.len;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len == 0;
+    }
+
+    fn push(&mut self, table: &String, op: BatchOp) {
+        if !self.tables.contains_key(table) {
+            self.tables.insert(table.clone(), vec![]);
+        }
+        self.tables.get_mut(table).unwrap().push(op);
+        self.len += 1;
+    }
+
+    pub fn insert_object(&mut self, table: &String, r: Range, d: Object) {
+        self.push(table, BatchOp::InsertObject(r, d));
+    }
+
+    pub fn delete_object(&mut self, table: &String, r: Range) {
+        self.push(table, BatchOp::DeleteObject(r));
+    }
+
+    pub fn delete_object_value(&mut self, table: &String, r: Range, d: Object) {
+        self.push(table, BatchOp::DeleteObjectValue(r, d));
+    }
+
+    pub fn delete_intersecting_objects(&mut self, table: &String, r: Range) {
+        self.push(table, BatchOp::DeleteIntersectingObjects(r));
+    }
+
+    pub fn punch_hole(&mut self, table: &String, r: Range) {
+        self.push(table, BatchOp::PunchHole(r));
+    }
+
+    #[must_use]
+    pub fn insert_bitmap(&mut self, table: &String, r: Range, d: Bitmap) -> Result<(), DBError> {
+        try!(d.check_length(r));
+        self.push(table, BatchOp::InsertBitmap(r, d));
+        return Ok(());
+    }
+
+    pub fn delete_bitmap(&mut self, table: &String, entry_size: u64, r: Range) {
+        self.push(table, BatchOp::DeleteBitmap(entry_size, r));
+    }
+}
+
+/// Merges bitmaps that overlap or touch each other. Where bitmaps overlap the
+/// one inserted last wins, just like with consecutive `insert_bitmap` calls.
+fn coalesce_bitmaps(inserts: Vec<(Range, Bitmap)>) -> Result<Vec<(Range, Bitmap)>, DBError> {
+    let mut sorted = inserts.into_iter().enumerate().collect::<Vec<(usize, (Range, Bitmap))>>();
+    sorted.sort_by_key(|&(_, (rng, _))| rng.min);
+
+    let mut groups: Vec<(Range, Vec<(usize, (Range, Bitmap))>)> = vec![];
+    for (seq, (rng, bitmap)) in sorted {
+        if let Some(&mut (ref mut group_range, ref mut members)) = groups.last_mut() {
+            if rng.intersect(&group_range.get_extended()) {
+                *group_range = group_range.get_union(&rng);
+                members.push((seq, (rng, bitmap)));
+                continue;
+            }
+        }
+        groups.push((rng, vec![(seq, (rng, bitmap))]));
+    }
+
+    let mut res = vec![];
+    for (_, mut members) in groups {
+        members.sort_by_key(|&(seq, _)| seq);
+        let (_, (rng, last)) = members.pop().unwrap();
+        let partners = members.into_iter().map(|(_, member)| member).collect();
+        res.push(try!(last.merge_bitmaps(rng, partners)));
+    }
+    return Ok(res);
+}
+
+/// Pending object inserts and deletes of a table, the last one per range.
+struct ObjectOps {
+    ops: BTreeMap<(u64, u64), Option<Object>>,
+    inserted: bool,
+}
+
+impl ObjectOps {
+    fn new() -> ObjectOps {
+        return ObjectOps{ops: BTreeMap::new(), inserted: false};
+    }
+
+    fn push(&mut self, r: Range, d: Option<Object>) {
+        self.inserted |= d.is_some();
+        self.ops.insert((r.min, r.max), d);
+    }
+}
+
+impl<B: StorageBackend> DB<B> {
+    fn flush_object_ops(&mut self, table: &String, pending: ObjectOps) -> Result<(), DBError> {
+        // an insert creates the table, even if a later delete drops the object
+        if pending.inserted && !self.has_table(table) {
+            try!(self.create_table(table));
+        }
+        for ((min, max), op) in pending.ops {
+            match op {
+                Some(d) => try!(self.insert_object(table, Range::new(min, max), d)),
+                None => try!(self.delete_object(table, Range::new(min, max))),
+            }
+        }
+        return Ok(());
+    }
+
+    fn flush_bitmap_inserts(&mut self, table: &String, inserts: Vec<(Range, Bitmap)>) -> Result<(), DBError> {
+        for (rng, bitmap) in try!(coalesce_bitmaps(inserts)) {
+            try!(self.insert_bitmap(table, rng, bitmap));
+        }
+        return Ok(());
+    }
+
+    /// Applies all operations of `batch` as a single transaction.
+    #[must_use]
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<(), DBError> {
+        return self.transaction(|db| {
+            for (table, ops) in batch.tables {
+                // with options, the result of an insert depends on the
+                // objects already stored
+                let plain = !db.options.contains_key(&table);
+                let mut objects = ObjectOps::new();
+                let mut pending: BTreeMap<u64, Vec<(Range, Bitmap)>> = BTreeMap::new();
+                for op in ops {
+                    match op {
+                        BatchOp::InsertBitmap(r, d) => pending.entry(d.entry_size).or_insert(vec![]).push((r, d)),
+                        BatchOp::DeleteBitmap(entry_size, r) => {
+                            if let Some(inserts) = pending.remove(&entry_size) {
+                                try!(db.flush_bitmap_inserts(&table, inserts));
+                            }
+                            try!(db.delete_bitmap(&table, entry_size, r));
+                        }
+                        BatchOp::InsertObject(r, d) if plain => objects.push(r, Some(d)),
+                        BatchOp::DeleteObject(r) if plain => objects.push(r, None),
+                        op => {
+                            try!(db.flush_object_ops(&table, mem::replace(&mut objects, ObjectOps::new())));
+                            try!(db.apply_object_op(&table, op));
+                        }
+                    }
+                }
+                try!(db.flush_object_ops(&table, objects));
+                for (_, inserts) in pending {
+                    try!(db.flush_bitmap_inserts(&table, inserts));
+                }
+            }
+            return Ok(());
+        });
+    }
+
+    fn apply_object_op(&mut self, table: &String, op: BatchOp) -> Result<(), DBError> {
+        match op {
+            BatchOp::InsertObject(r, d) => return self.insert_object(table, r, d),
+            BatchOp::DeleteObject(r) => return self.delete_object(table, r),
+            BatchOp::DeleteObjectValue(r, d) => return self.delete_object_value(table, r, &d),
+            BatchOp::DeleteIntersectingObjects(r) => return self.delete_intersecting_objects(table, r),
+            BatchOp::PunchHole(r) => return self.punch_hole(table, r),
+            BatchOp::InsertBitmap(..) | BatchOp::DeleteBitmap(..) => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+fn bitmap_contents(db: &DB, tbl: &String) -> Vec<(Range, Bitmap)> {
+    return db.query_bitmap(tbl, Range::new(0, 1000)).unwrap()
+             .map(|e| e.map(|(r, b)| (r, b.to_bitmap())).unwrap())
+             .collect();
+}
+
+#[test]
+fn test_write_batch_matches_single_writes() {
+    let tbl = "foo".to_string();
+    let ops = vec![(10, 12, 1), (0, 3, 2), (2, 5, 3), (20, 21, 4), (13, 14, 5), (30, 31, 6), (4, 4, 7)];
+    let mut single = DB::new();
+    let mut batched = DB::new();
+    let mut batch = WriteBatch::new();
+    single.insert_bitmap(&tbl, Range::new(6, 8), Bitmap::new(1, vec![9; 3])).unwrap();
+    batched.insert_bitmap(&tbl, Range::new(6, 8), Bitmap::new(1, vec![9; 3])).unwrap();
+    for &(min, max, val) in &ops {
+        let rng = Range::new(min, max);
+        let bitmap = Bitmap::new(1, vec![val; rng.len() as usize]);
+        single.insert_bitmap(&tbl, rng, bitmap.clone()).unwrap();
+        batch.insert_bitmap(&tbl, rng, bitmap).unwrap();
+        if val == 4 {
+            single.delete_bitmap(&tbl, 1, Range::new(11, 11)).unwrap();
+            batch.delete_bitmap(&tbl, 1, Range::new(11, 11));
+        }
+    }
+    single.insert_bitmap(&tbl, Range::new(0, 0), Bitmap::new(2, vec![1, 2])).unwrap();
+    batch.insert_bitmap(&tbl, Range::new(0, 0), Bitmap::new(2, vec![1, 2])).unwrap();
+    single.insert_object(&tbl, Range::new(1, 2), Object::new(vec![1])).unwrap();
+    batch.insert_object(&tbl, Range::new(1, 2), Object::new(vec![1]));
+    assert!(batch.insert_bitmap(&tbl, Range::new(0, 1), Bitmap::new(1, vec![1])).is_err());
+    assert_eq!(batch.len(), 10);
+
+    batched.write_batch(batch).unwrap();
+    assert_eq!(bitmap_contents(&batched, &tbl), bitmap_contents(&single, &tbl));
+    assert_eq!(batched.query_object(&tbl, Range::new(0, 100)).unwrap().count(), 1);
+}
+
+#[test]
+fn test_coalesce_bitmaps() {
+    let inserts = vec![(Range::new(4, 5), Bitmap::new(1, vec![1, 1])),
+                       (Range::new(0, 4), Bitmap::new(1, vec![2; 5])),
+                       (Range::new(8, 8), Bitmap::new(1, vec![3]))];
+    let merged = coalesce_bitmaps(inserts).unwrap();
+    assert_eq!(merged, vec![(Range::new(0, 5), Bitmap::new(1, vec![2, 2, 2, 2, 2, 1])),
+                            (Range::new(8, 8), Bitmap::new(1, vec![3]))]);
+}
+
+#[test]
+fn test_write_batch_object_ops() {
+    let (foo, bar, baz) = ("foo".to_string(), "bar".to_string(), "baz".to_string());
+    let objects = |db: &DB, tbl: &String| db.query_object(tbl, Range::new(0, 1000)).unwrap()
+                                            .map(|e| e.map(|(r, d)| (r, d.into_owned())).unwrap())
+                                            .collect::<Vec<(Range, Object)>>();
+    let mut single = DB::new();
+    let mut batched = DB::new();
+    for db in [&mut single, &mut batched].iter_mut() {
+        db.set_table_options(&bar, TableOptions{coalesce: true, ..TableOptions::default()}).unwrap();
+        db.insert_object(&foo, Range::new(40, 50), Object::new(vec![9])).unwrap();
+    }
+    let mut batch = WriteBatch::new();
+    let ops = vec![(&foo, 20, 30, Some(1)), (&foo, 0, 10, Some(2)), (&foo, 20, 30, None), (&foo, 0, 10, Some(3)),
+                   (&foo, 40, 50, None), (&baz, 0, 1, Some(4)), (&baz, 0, 1, None),
+                   (&bar, 0, 10, Some(5)), (&bar, 11, 20, Some(5)), (&bar, 0, 10, None)];
+    for (tbl, min, max, val) in ops {
+        match val {
+            Some(val) => {
+                single.insert_object(tbl, Range::new(min, max), Object::new(vec![val])).unwrap();
+                batch.insert_object(tbl, Range::new(min, max), Object::new(vec![val]));
+            }
+            None => {
+                single.delete_object(tbl, Range::new(min, max)).unwrap();
+                batch.delete_object(tbl, Range::new(min, max));
+            }
+        }
+        if min == 40 {
+            single.punch_hole(tbl, Range::new(5, 5)).unwrap();
+            batch.punch_hole(tbl, Range::new(5, 5));
+        }
+    }
+    batched.write_batch(batch).unwrap();
+    assert_eq!(batched.tables(), single.tables());
+    for tbl in &[&foo, &bar, &baz] {
+        assert_eq!(objects(&batched, tbl), objects(&single, tbl));
+    }
+    assert_eq!(objects(&batched, &bar), vec![(Range::new(0, 20), Object::new(vec![5]))]);
+}